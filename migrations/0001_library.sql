@@ -0,0 +1,10 @@
+CREATE TABLE IF NOT EXISTS library (
+    filename TEXT PRIMARY KEY NOT NULL,
+    title TEXT NOT NULL,
+    artist TEXT NOT NULL,
+    album TEXT,
+    duration INTEGER,
+    cover TEXT,
+    mtime INTEGER NOT NULL,
+    size INTEGER NOT NULL
+);
@@ -0,0 +1,7 @@
+ALTER TABLE library ADD COLUMN bitrate INTEGER;
+ALTER TABLE library ADD COLUMN sample_rate INTEGER;
+ALTER TABLE library ADD COLUMN channels INTEGER;
+ALTER TABLE library ADD COLUMN codec TEXT;
+
+-- Force the next scan to probe every file
+UPDATE library SET mtime = 0;
@@ -0,0 +1,8 @@
+ALTER TABLE library ADD COLUMN album_artist TEXT;
+ALTER TABLE library ADD COLUMN track_number INTEGER;
+ALTER TABLE library ADD COLUMN disc_number INTEGER;
+ALTER TABLE library ADD COLUMN year INTEGER;
+ALTER TABLE library ADD COLUMN genre TEXT;
+
+-- Force the next scan to read the new tags
+UPDATE library SET mtime = 0;
@@ -0,0 +1,23 @@
+CREATE TABLE IF NOT EXISTS playlists (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    name TEXT NOT NULL,
+    created_at INTEGER NOT NULL,
+    updated_at INTEGER NOT NULL
+);
+
+-- `filename` is either a file in MUSIC_DIR or a YouTube id, same as QueueItem
+CREATE TABLE IF NOT EXISTS playlist_entries (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    playlist_id INTEGER NOT NULL REFERENCES playlists (id) ON DELETE CASCADE,
+    position INTEGER NOT NULL,
+    filename TEXT NOT NULL,
+    title TEXT NOT NULL,
+    artist TEXT NOT NULL,
+    artists TEXT,
+    thumbnail TEXT,
+    duration INTEGER,
+    artist_thumbnail TEXT,
+    url TEXT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS playlist_entries_position ON playlist_entries (playlist_id, position);
@@ -0,0 +1,6 @@
+-- Server state that has to survive a restart, stored as JSON
+CREATE TABLE IF NOT EXISTS app_state (
+    key TEXT PRIMARY KEY NOT NULL,
+    value TEXT NOT NULL,
+    updated_at INTEGER NOT NULL
+);
@@ -0,0 +1,11 @@
+-- Playback sessions keyed by device id, `session` is a PlaylistSession as JSON
+CREATE TABLE IF NOT EXISTS sessions (
+    device TEXT PRIMARY KEY NOT NULL,
+    session TEXT NOT NULL,
+    updated_at INTEGER NOT NULL
+);
+
+-- The single shared session from before is kept as `default`, it can be transferred to a device
+INSERT OR IGNORE INTO sessions (device, session, updated_at)
+    SELECT 'default', value, updated_at FROM app_state WHERE key = 'playlist_session';
+DELETE FROM app_state WHERE key = 'playlist_session';
@@ -0,0 +1,15 @@
+-- One row per play, `filename` is a file in MUSIC_DIR for local plays and a YouTube id for temp ones
+CREATE TABLE IF NOT EXISTS plays (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    filename TEXT NOT NULL,
+    title TEXT NOT NULL,
+    artist TEXT NOT NULL,
+    duration INTEGER,
+    played_at INTEGER NOT NULL,
+    -- Seconds, NULL until the client reports it
+    listened INTEGER,
+    source TEXT NOT NULL CHECK (source IN ('local', 'temp'))
+);
+
+CREATE INDEX IF NOT EXISTS plays_filename ON plays (filename);
+CREATE INDEX IF NOT EXISTS plays_played_at ON plays (played_at);
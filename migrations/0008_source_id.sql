@@ -0,0 +1,7 @@
+-- YouTube id of the video a file was downloaded from, read from its comment tag
+ALTER TABLE library ADD COLUMN source_id TEXT;
+
+CREATE INDEX IF NOT EXISTS library_source_id ON library (source_id);
+
+-- Rescan so existing downloads get their id
+UPDATE library SET mtime = 0;
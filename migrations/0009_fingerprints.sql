@@ -0,0 +1,5 @@
+-- Acoustic fingerprint of the start of each file, little endian 32 bit words
+ALTER TABLE library ADD COLUMN fingerprint BLOB;
+
+-- Rescan so existing files get fingerprinted
+UPDATE library SET mtime = 0;
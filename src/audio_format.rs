@@ -0,0 +1,224 @@
+//! Which format downloads are saved in, set globally and overridable per request
+
+use axum::{extract::State, http::StatusCode, Json};
+use serde::{Deserialize, Serialize};
+
+use crate::{persist, AppState};
+
+#[derive(Clone, Copy, Serialize, Deserialize)]
+#[serde(tag = "format", rename_all = "lowercase")]
+pub enum AudioFormat {
+    Opus,
+    /// AAC as YouTube serves it, remuxed without re-encoding
+    M4a,
+    Mp3 {
+        /// kbps, yt-dlp picks a VBR quality when not set
+        bitrate: Option<u32>,
+    },
+    Flac,
+}
+
+/// What downloads always were before the format could be chosen
+impl Default for AudioFormat {
+    fn default() -> Self {
+        Self::Mp3 { bitrate: None }
+    }
+}
+
+impl AudioFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Opus => "opus",
+            Self::M4a => "m4a",
+            Self::Mp3 { .. } => "mp3",
+            Self::Flac => "flac",
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Self::Opus => "audio/ogg",
+            Self::M4a => "audio/mp4",
+            Self::Mp3 { .. } => "audio/mpeg",
+            Self::Flac => "audio/flac",
+        }
+    }
+
+    /// yt-dlp format selection, streams already in the target codec are picked first
+    /// so they are only remuxed
+    pub fn selector(self) -> &'static str {
+        match self {
+            Self::Opus => "bestaudio[acodec=opus]/bestaudio/best",
+            Self::M4a => "bestaudio[ext=m4a]/bestaudio/best",
+            Self::Mp3 { .. } | Self::Flac => "bestaudio/best",
+        }
+    }
+
+    /// Format selection and extraction arguments for yt-dlp
+    pub fn yt_dlp_args(self) -> Vec<String> {
+        let mut args = vec![
+            "-f".to_string(),
+            self.selector().to_string(),
+            "-x".to_string(),
+            "--audio-format".to_string(),
+            self.extension().to_string(),
+        ];
+        if let Self::Mp3 {
+            bitrate: Some(bitrate),
+        } = self
+        {
+            args.push("--audio-quality".to_string());
+            args.push(format!("{bitrate}K"));
+        }
+
+        args
+    }
+
+    /// Encoder and container arguments for ffmpeg writing to a pipe, the output can't be seeked
+    /// so MP4 is fragmented. `bitrate` in kbps overrides the default, which is what yt-dlp picks.
+    pub fn ffmpeg_args(self, bitrate: Option<u32>) -> Vec<String> {
+        let (codec, default_bitrate) = match self {
+            Self::Opus => ("libopus", Some(160)),
+            Self::M4a => ("aac", Some(192)),
+            Self::Mp3 { bitrate } => ("libmp3lame", bitrate),
+            Self::Flac => ("flac", None),
+        };
+
+        let mut args = vec!["-c:a".to_string(), codec.to_string()];
+        match (self, bitrate.or(default_bitrate)) {
+            (Self::Flac, _) => {}
+            (_, Some(bitrate)) => args.extend(["-b:a".to_string(), format!("{bitrate}k")]),
+            // yt-dlp's default VBR quality
+            (_, None) => args.extend(["-q:a".to_string(), "5".to_string()]),
+        }
+        args.extend(self.container_args());
+
+        args
+    }
+
+    /// Like [`Self::ffmpeg_args`] but remuxing a stream that is already in this format,
+    /// see [`Self::is_codec`]
+    pub fn ffmpeg_copy_args(self) -> Vec<String> {
+        let mut args = vec!["-c:a".to_string(), "copy".to_string()];
+        args.extend(self.container_args());
+
+        args
+    }
+
+    fn container_args(self) -> Vec<String> {
+        let container = match self {
+            Self::Opus => "ogg",
+            Self::M4a => "mp4",
+            Self::Mp3 { .. } => "mp3",
+            Self::Flac => "flac",
+        };
+
+        let mut args = vec![];
+        if let Self::M4a = self {
+            args.extend([
+                "-movflags".to_string(),
+                "frag_keyframe+empty_moov".to_string(),
+            ]);
+        }
+        args.extend(["-f".to_string(), container.to_string()]);
+
+        args
+    }
+
+    /// Whether a stream in `acodec`, as yt-dlp names codecs, can be copied as is.
+    /// An mp3 with a set bitrate is always re-encoded.
+    pub fn is_codec(self, acodec: &str) -> bool {
+        match self {
+            Self::Opus => acodec == "opus",
+            Self::M4a => acodec.starts_with("mp4a"),
+            Self::Mp3 { bitrate: None } => acodec == "mp3",
+            Self::Mp3 { bitrate: Some(_) } => false,
+            Self::Flac => acodec == "flac",
+        }
+    }
+
+    /// Parse `opus`, `m4a`, `mp3` or `flac`, `bitrate` only applies to mp3
+    pub fn from_name(name: &str, bitrate: Option<u32>) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "opus" => Some(Self::Opus),
+            "m4a" => Some(Self::M4a),
+            "mp3" => Some(Self::Mp3 { bitrate }),
+            "flac" => Some(Self::Flac),
+            _ => None,
+        }
+    }
+
+    fn validate(self) -> Result<Self, String> {
+        match self {
+            Self::Mp3 { bitrate: Some(b) } => check_bitrate(b).map(|_| self),
+            _ => Ok(self),
+        }
+    }
+}
+
+pub fn check_bitrate(bitrate: u32) -> Result<(), String> {
+    if (32..=320).contains(&bitrate) {
+        Ok(())
+    } else {
+        Err("Bitrate must be between 32 and 320 kbps".to_string())
+    }
+}
+
+/// `?format=mp3&bitrate=192` on download endpoints
+#[derive(Deserialize)]
+pub struct FormatQuery {
+    format: Option<String>,
+    bitrate: Option<u32>,
+}
+
+impl FormatQuery {
+    /// The format named in the query, `None` if there is none
+    fn parse(&self) -> Result<Option<AudioFormat>, String> {
+        let Some(name) = self.format.as_deref() else {
+            return Ok(None);
+        };
+        let format =
+            AudioFormat::from_name(name, self.bitrate).ok_or("Unknown format".to_string())?;
+        // Dropped by from_name otherwise
+        if self.bitrate.is_some() && !matches!(format, AudioFormat::Mp3 { .. }) {
+            return Err("Bitrate only applies to mp3".to_string());
+        }
+
+        format.validate().map(Some)
+    }
+}
+
+/// The format asked for in `query`, the global setting otherwise
+pub async fn requested(
+    state: &AppState,
+    query: &FormatQuery,
+) -> Result<AudioFormat, (StatusCode, String)> {
+    match query.parse() {
+        Ok(Some(format)) => Ok(format),
+        Ok(None) => Ok(*state.audio_format.lock().await),
+        Err(e) => Err((StatusCode::BAD_REQUEST, e)),
+    }
+}
+
+pub async fn get_default(State(state): State<AppState>) -> Json<AudioFormat> {
+    Json(*state.audio_format.lock().await)
+}
+
+pub async fn set_default(
+    State(state): State<AppState>,
+    Json(body): Json<FormatQuery>,
+) -> Result<Json<AudioFormat>, (StatusCode, String)> {
+    // Same shape as AudioFormat, read as a query so a bitrate for another format is noticed
+    let format = body
+        .parse()
+        .and_then(|f| f.ok_or("Missing format".to_string()))
+        .map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+
+    let mut current = state.audio_format.lock().await;
+    persist::save(&state.db, persist::AUDIO_FORMAT, &format)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))?;
+    *current = format;
+
+    Ok(Json(format))
+}
@@ -0,0 +1,277 @@
+//! Finding the same song saved more than once and merging the copies
+
+use std::collections::HashMap;
+
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    routing::{get, post},
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{fingerprint, history, library, playlists, AppState, QueueItem, Track, MUSIC_DIR};
+
+type ApiResult<T> = Result<T, (StatusCode, String)>;
+
+/// Seconds two copies may differ by
+const DURATION_TOLERANCE: u64 = 3;
+
+/// Bracketed parts of a title containing one of these are dropped when comparing,
+/// so `Title (Official Audio)` and `Title [Lyrics]` match `Title`
+const NOISE: &[&str] = &[
+    "official",
+    "audio",
+    "video",
+    "lyric",
+    "lyrics",
+    "visualizer",
+    "visualiser",
+    "hd",
+    "hq",
+    "4k",
+    "mv",
+    "explicit",
+];
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/", get(list_duplicates))
+        .route("/merge", post(merge))
+}
+
+fn normalize_artist(artist: &str) -> String {
+    let artist = artist.to_lowercase();
+    let first = artist.split([',', '&']).next().unwrap_or_default();
+    let first = first.split(" feat").next().unwrap_or_default();
+
+    first
+        .trim()
+        .trim_end_matches(" - topic")
+        .trim_end_matches("vevo")
+        .trim()
+        .to_string()
+}
+
+fn normalize_title(title: &str, artist: &str) -> String {
+    let mut title = title.to_lowercase();
+
+    // Uploads often put the artist in front: `Artist - Title`
+    if let Some(rest) = title.strip_prefix(&format!("{artist} - ")) {
+        title = rest.to_string();
+    }
+
+    let mut kept = String::with_capacity(title.len());
+    let mut rest = title.as_str();
+    while let Some(start) = rest.find(['(', '[']) {
+        let close = if rest[start..].starts_with('(') {
+            ')'
+        } else {
+            ']'
+        };
+        let Some(len) = rest[start..].find(close) else {
+            break;
+        };
+
+        let inner = &rest[start + 1..start + len];
+        let noise = inner
+            .split(|c: char| !c.is_alphanumeric())
+            .any(|w| NOISE.contains(&w));
+
+        kept.push_str(&rest[..start]);
+        if !noise {
+            kept.push_str(&rest[start..start + len + 1]);
+        }
+        rest = &rest[start + len + 1..];
+    }
+    kept.push_str(rest);
+
+    kept.split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .collect::<Vec<&str>>()
+        .join(" ")
+}
+
+fn find(parents: &mut [usize], i: usize) -> usize {
+    if parents[i] != i {
+        parents[i] = find(parents, parents[i]);
+    }
+    parents[i]
+}
+
+fn union(parents: &mut [usize], a: usize, b: usize) {
+    let (a, b) = (find(parents, a), find(parents, b));
+    if a != b {
+        parents[b] = a;
+    }
+}
+
+#[derive(Deserialize)]
+struct DuplicatesQuery {
+    /// Seconds, defaults to [`DURATION_TOLERANCE`]
+    tolerance: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct DuplicateGroup {
+    /// Same source video
+    same_source: bool,
+    /// Same title and artist once normalized, with close durations
+    same_title: bool,
+    /// Same audio according to the fingerprints, whatever the names
+    same_recording: bool,
+    /// Most played first, that's usually the one to keep
+    tracks: Vec<Track>,
+}
+
+async fn list_duplicates(
+    State(state): State<AppState>,
+    Query(query): Query<DuplicatesQuery>,
+) -> ApiResult<Json<Vec<DuplicateGroup>>> {
+    let tolerance = query.tolerance.unwrap_or(DURATION_TOLERANCE);
+    let tracks = library::tracks(&state)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))?;
+
+    let mut parents = (0..tracks.len()).collect::<Vec<usize>>();
+    let mut by_source: HashMap<&str, usize> = HashMap::new();
+    let mut by_title: HashMap<(String, String), Vec<usize>> = HashMap::new();
+    let mut same_source = vec![false; tracks.len()];
+    let mut same_title = vec![false; tracks.len()];
+
+    for (i, track) in tracks.iter().enumerate() {
+        if let Some(source) = track.source_id.as_deref() {
+            if let Some(&first) = by_source.get(source) {
+                union(&mut parents, first, i);
+                same_source[first] = true;
+                same_source[i] = true;
+            } else {
+                by_source.insert(source, i);
+            }
+        }
+
+        let artist = normalize_artist(&track.artist);
+        let title = normalize_title(&track.title, &artist);
+        let candidates = by_title.entry((title, artist)).or_default();
+
+        for &other in candidates.iter() {
+            // Unknown durations don't rule a match out
+            let close = match (track.duration, tracks[other].duration) {
+                (Some(a), Some(b)) => a.abs_diff(b) <= tolerance,
+                _ => true,
+            };
+            if close {
+                union(&mut parents, other, i);
+                same_title[other] = true;
+                same_title[i] = true;
+            }
+        }
+        candidates.push(i);
+    }
+
+    let mut fingerprints = library::fingerprints(&state)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))?;
+    let prints = tracks
+        .iter()
+        .map(|t| fingerprints.remove(&t.filename).unwrap_or_default())
+        .collect::<Vec<Vec<u32>>>();
+    let recordings = tokio::task::spawn_blocking(move || fingerprint::matching_pairs(&prints))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    let mut same_recording = vec![false; tracks.len()];
+    for (a, b, _) in recordings {
+        union(&mut parents, a, b);
+        same_recording[a] = true;
+        same_recording[b] = true;
+    }
+
+    let mut groups: HashMap<usize, Vec<usize>> = HashMap::new();
+    for i in 0..tracks.len() {
+        let root = find(&mut parents, i);
+        groups.entry(root).or_default().push(i);
+    }
+
+    let mut duplicates = groups
+        .into_values()
+        .filter(|g| g.len() > 1)
+        .map(|g| {
+            let mut group = DuplicateGroup {
+                same_source: g.iter().any(|&i| same_source[i]),
+                same_title: g.iter().any(|&i| same_title[i]),
+                same_recording: g.iter().any(|&i| same_recording[i]),
+                tracks: g.iter().map(|&i| tracks[i].clone()).collect(),
+            };
+            group
+                .tracks
+                .sort_by_key(|t| std::cmp::Reverse(t.play_count.unwrap_or_default()));
+            group
+        })
+        .collect::<Vec<DuplicateGroup>>();
+    duplicates.sort_by_cached_key(|g| g.tracks[0].title.to_lowercase());
+
+    Ok(Json(duplicates))
+}
+
+#[derive(Deserialize)]
+struct MergeRequest {
+    keep: String,
+    remove: Vec<String>,
+}
+
+/// Delete the `remove` files, moving their plays and playlist entries over to `keep`
+async fn merge(
+    State(state): State<AppState>,
+    Json(body): Json<MergeRequest>,
+) -> ApiResult<Json<Track>> {
+    let internal = |e: String| (StatusCode::INTERNAL_SERVER_ERROR, e);
+
+    let keep = library::track(&state, &body.keep)
+        .await
+        .map_err(internal)?
+        .ok_or((StatusCode::NOT_FOUND, format!("{} not found", body.keep)))?;
+
+    for filename in &body.remove {
+        if *filename == keep.filename {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                "Cannot remove the track to keep".to_string(),
+            ));
+        }
+        if library::track(&state, filename)
+            .await
+            .map_err(internal)?
+            .is_none()
+        {
+            return Err((StatusCode::NOT_FOUND, format!("{filename} not found")));
+        }
+    }
+
+    let item = QueueItem::from(keep.clone());
+    for filename in &body.remove {
+        tracing::info!("Merging {} into {}", filename, keep.filename);
+
+        history::rename(&state, filename, &keep.filename)
+            .await
+            .map_err(internal)?;
+        playlists::replace_file(&state, filename, &item).await?;
+
+        if let Err(e) = std::fs::remove_file(format!("{MUSIC_DIR}/{filename}")) {
+            tracing::error!("Cannot delete {}: {}", filename, e);
+        }
+        library::remove(&state, filename).await.map_err(internal)?;
+    }
+
+    // Copies with the same name share a cover, which was deleted along with them
+    library::index_file(&state, &keep.filename)
+        .await
+        .map_err(internal)?;
+
+    let kept = library::track(&state, &keep.filename)
+        .await
+        .map_err(internal)?
+        .unwrap_or(keep);
+
+    Ok(Json(kept))
+}
@@ -0,0 +1,453 @@
+//! Chromaprint-style acoustic fingerprints.
+//!
+//! Audio is decoded by ffmpeg to low rate mono, every frame is split into bands and each
+//! 32 bit word records whether the energy difference between neighbouring bands rose or fell
+//! since the previous frame. Re-encoding flips few bits, a different recording flips about half.
+
+use std::{
+    collections::{HashMap, HashSet},
+    f32::consts::PI,
+    process::Stdio,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use axum::{
+    extract::{Multipart, State},
+    http::StatusCode,
+    Json,
+};
+use serde::Serialize;
+use tokio::process::Command;
+
+use crate::{library, AppState, Track, TEMP_DIR};
+
+type ApiResult<T> = Result<T, (StatusCode, String)>;
+
+const SAMPLE_RATE: usize = 5512;
+const FRAME: usize = 2048;
+const HOP: usize = 512;
+const BANDS: usize = 33;
+const MIN_FREQ: f32 = 300.0;
+const MAX_FREQ: f32 = 2000.0;
+/// Only the start of a file is fingerprinted
+const SECONDS: u32 = 120;
+
+/// Frames two prints may be shifted by, about 10 seconds of leading silence
+pub const MAX_OFFSET: usize = 10 * SAMPLE_RATE / HOP;
+/// Share of equal bits from which two prints are the same recording
+pub const THRESHOLD: f32 = 0.75;
+
+/// Words owned by more prints than this are silence or noise, not worth a lookup
+const COMMON_WORD: usize = 8;
+/// Identical words two prints must share before they are compared
+const MIN_HITS: u32 = 3;
+/// Most matches returned by [`identify`]
+const MAX_MATCHES: usize = 5;
+
+/// Fingerprint the start of `path`, empty if it is too short
+pub async fn compute(path: &str) -> Result<Vec<u32>, String> {
+    let proc = Command::new("ffmpeg")
+        .args(["-v", "error", "-i", path, "-t"])
+        .arg(SECONDS.to_string())
+        .args(["-vn", "-ac", "1", "-ar"])
+        .arg(SAMPLE_RATE.to_string())
+        .args(["-f", "s16le", "-"])
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .output()
+        .await
+        .map_err(|e| format!("Failed to spawn ffmpeg: {e}"))?;
+
+    if !proc.status.success() {
+        return Err(format!(
+            "ffmpeg error: {}",
+            String::from_utf8_lossy(&proc.stderr)
+        ));
+    }
+
+    let samples = proc
+        .stdout
+        .chunks_exact(2)
+        .map(|s| i16::from_le_bytes([s[0], s[1]]) as f32 / i16::MAX as f32)
+        .collect::<Vec<f32>>();
+
+    tokio::task::spawn_blocking(move || fingerprint(&samples))
+        .await
+        .map_err(|e| e.to_string())
+}
+
+fn fingerprint(samples: &[f32]) -> Vec<u32> {
+    if samples.len() < FRAME {
+        return Vec::new();
+    }
+
+    let window = (0..FRAME)
+        .map(|i| 0.54 - 0.46 * (2.0 * PI * i as f32 / (FRAME - 1) as f32).cos())
+        .collect::<Vec<f32>>();
+    let twiddles = twiddles(FRAME);
+    // Logarithmic, like pitch
+    let edges = (0..=BANDS)
+        .map(|b| {
+            let freq = MIN_FREQ * (MAX_FREQ / MIN_FREQ).powf(b as f32 / BANDS as f32);
+            (freq * FRAME as f32 / SAMPLE_RATE as f32).round() as usize
+        })
+        .collect::<Vec<usize>>();
+
+    let mut re = vec![0.0; FRAME];
+    let mut im = vec![0.0; FRAME];
+    let mut previous: Option<[f32; BANDS]> = None;
+    let mut print = Vec::with_capacity((samples.len() - FRAME) / HOP);
+
+    for start in (0..=samples.len() - FRAME).step_by(HOP) {
+        for ((r, s), w) in re.iter_mut().zip(&samples[start..]).zip(&window) {
+            *r = s * w;
+        }
+        im.fill(0.0);
+        fft(&mut re, &mut im, &twiddles);
+
+        let mut energy = [0.0; BANDS];
+        for (e, band) in energy.iter_mut().zip(edges.windows(2)) {
+            *e = (band[0]..band[1])
+                .map(|k| re[k] * re[k] + im[k] * im[k])
+                .sum();
+        }
+
+        if let Some(previous) = previous {
+            let word = (0..BANDS - 1)
+                .filter(|&m| energy[m] - energy[m + 1] - (previous[m] - previous[m + 1]) > 0.0)
+                .fold(0, |word, m| word | 1 << m);
+            print.push(word);
+        }
+        previous = Some(energy);
+    }
+
+    print
+}
+
+/// First half of the unit circle for an FFT of `n` points
+fn twiddles(n: usize) -> Vec<(f32, f32)> {
+    (0..n / 2)
+        .map(|k| {
+            let angle = -2.0 * PI * k as f32 / n as f32;
+            (angle.cos(), angle.sin())
+        })
+        .collect()
+}
+
+/// In place radix-2 FFT, `twiddles` holds the first half of the unit circle for `re.len()`
+fn fft(re: &mut [f32], im: &mut [f32], twiddles: &[(f32, f32)]) {
+    let n = re.len();
+
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            re.swap(i, j);
+            im.swap(i, j);
+        }
+    }
+
+    let mut len = 2;
+    while len <= n {
+        let half = len / 2;
+        let stride = n / len;
+        for start in (0..n).step_by(len) {
+            for k in 0..half {
+                let (wr, wi) = twiddles[k * stride];
+                let (a, b) = (start + k, start + k + half);
+                let tr = re[b] * wr - im[b] * wi;
+                let ti = re[b] * wi + im[b] * wr;
+                re[b] = re[a] - tr;
+                im[b] = im[a] - ti;
+                re[a] += tr;
+                im[a] += ti;
+            }
+        }
+        len <<= 1;
+    }
+}
+
+pub fn to_bytes(print: &[u32]) -> Vec<u8> {
+    print.iter().flat_map(|w| w.to_le_bytes()).collect()
+}
+
+pub fn from_bytes(bytes: &[u8]) -> Vec<u32> {
+    bytes
+        .chunks_exact(4)
+        .map(|w| u32::from_le_bytes([w[0], w[1], w[2], w[3]]))
+        .collect()
+}
+
+/// Share of equal bits at the best alignment of `a` and `b` within `max_offset` frames.
+/// Alignments overlapping less than half of the shorter print are not considered.
+pub fn similarity(a: &[u32], b: &[u32], max_offset: usize) -> f32 {
+    let min_overlap = (a.len().min(b.len()) / 2).max(1);
+    let max_offset = max_offset as isize;
+
+    let mut best = 0.0;
+    for offset in -max_offset..=max_offset {
+        let (a, b) = if offset >= 0 {
+            (a.get(offset as usize..).unwrap_or_default(), b)
+        } else {
+            (a, b.get(offset.unsigned_abs()..).unwrap_or_default())
+        };
+
+        let overlap = a.len().min(b.len());
+        if overlap < min_overlap {
+            continue;
+        }
+
+        let errors: u32 = a.iter().zip(b).map(|(x, y)| (x ^ y).count_ones()).sum();
+        let score = 1.0 - errors as f32 / (overlap * 32) as f32;
+        if score > best {
+            best = score;
+        }
+    }
+
+    best
+}
+
+/// Pairs of `prints` that are the same recording, with their similarity.
+/// Only prints sharing a few identical words get compared, so this doesn't go through every pair.
+pub fn matching_pairs(prints: &[Vec<u32>]) -> Vec<(usize, usize, f32)> {
+    let mut owners: HashMap<u32, Vec<usize>> = HashMap::new();
+    for (i, print) in prints.iter().enumerate() {
+        let words = print
+            .iter()
+            .copied()
+            .filter(|&w| w != 0)
+            .collect::<HashSet<u32>>();
+        for word in words {
+            owners.entry(word).or_default().push(i);
+        }
+    }
+
+    let mut hits: HashMap<(usize, usize), u32> = HashMap::new();
+    for owners in owners
+        .values()
+        .filter(|o| (2..=COMMON_WORD).contains(&o.len()))
+    {
+        for (n, &a) in owners.iter().enumerate() {
+            for &b in &owners[n + 1..] {
+                *hits.entry((a, b)).or_default() += 1;
+            }
+        }
+    }
+
+    hits.into_iter()
+        .filter(|&(_, hits)| hits >= MIN_HITS)
+        .filter_map(|((a, b), _)| {
+            let score = similarity(&prints[a], &prints[b], MAX_OFFSET);
+            (score >= THRESHOLD).then_some((a, b, score))
+        })
+        .collect()
+}
+
+#[derive(Serialize)]
+pub struct Match {
+    score: f32,
+    track: Track,
+}
+
+/// Find which library tracks the uploaded `file` is a recording of, best match first
+pub async fn identify(
+    State(state): State<AppState>,
+    mut multipart: Multipart,
+) -> ApiResult<Json<Vec<Match>>> {
+    static UPLOADS: AtomicU64 = AtomicU64::new(0);
+
+    let mut file = None;
+    while let Some(field) = multipart
+        .next_field()
+        .await
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?
+    {
+        if field.name() == Some("file") {
+            let bytes = field
+                .bytes()
+                .await
+                .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+            file = Some(bytes);
+        }
+    }
+    let Some(file) = file.filter(|f| !f.is_empty()) else {
+        return Err((StatusCode::BAD_REQUEST, "No file uploaded".to_string()));
+    };
+
+    let path = format!(
+        "{TEMP_DIR}/identify-{}",
+        UPLOADS.fetch_add(1, Ordering::Relaxed)
+    );
+    tokio::fs::write(&path, &file)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    let print = compute(&path).await;
+    _ = tokio::fs::remove_file(&path).await;
+
+    let print = print.map_err(|e| (StatusCode::UNPROCESSABLE_ENTITY, e))?;
+    if print.is_empty() {
+        return Err((
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "Too short to identify".to_string(),
+        ));
+    }
+
+    let library = library::fingerprints(&state)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))?;
+
+    let mut scores = tokio::task::spawn_blocking(move || {
+        library
+            .into_iter()
+            .map(|(filename, known)| (similarity(&print, &known, MAX_OFFSET), filename))
+            .filter(|(score, _)| *score >= THRESHOLD)
+            .collect::<Vec<(f32, String)>>()
+    })
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    scores.sort_by(|a, b| b.0.total_cmp(&a.0));
+
+    let mut matches = Vec::new();
+    for (score, filename) in scores.into_iter().take(MAX_MATCHES) {
+        if let Some(track) = library::track(&state, &filename)
+            .await
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))?
+        {
+            matches.push(Match { score, track });
+        }
+    }
+
+    Ok(Json(matches))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// xorshift, deterministic noise without a dependency
+    fn noise(seed: u32, len: usize) -> Vec<u32> {
+        let mut x = seed.max(1);
+        (0..len)
+            .map(|_| {
+                x ^= x << 13;
+                x ^= x >> 17;
+                x ^= x << 5;
+                x
+            })
+            .collect()
+    }
+
+    /// A few seconds of tones that change every quarter second
+    fn melody(seed: u32, seconds: usize) -> Vec<f32> {
+        let notes = noise(seed, seconds * 4);
+        (0..seconds * SAMPLE_RATE)
+            .map(|i| {
+                let note = notes[i * 4 / SAMPLE_RATE];
+                let t = i as f32 / SAMPLE_RATE as f32;
+                [note % 1500, note / 7 % 1500]
+                    .iter()
+                    .map(|f| (2.0 * PI * (350 + f) as f32 * t).sin() * 0.4)
+                    .sum()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn fft_finds_a_sine() {
+        let n = 64;
+        let mut re = (0..n)
+            .map(|i| (2.0 * PI * 5.0 * i as f32 / n as f32).sin())
+            .collect::<Vec<f32>>();
+        let mut im = vec![0.0; n];
+        fft(&mut re, &mut im, &twiddles(n));
+
+        for k in 0..n {
+            let magnitude = (re[k] * re[k] + im[k] * im[k]).sqrt();
+            if k == 5 || k == n - 5 {
+                assert!(
+                    (magnitude - n as f32 / 2.0).abs() < 1e-3,
+                    "bin {k}: {magnitude}"
+                );
+                // A sine is all imaginary, negative at its own frequency
+                assert!(re[k].abs() < 1e-3);
+                assert!((im[k] + if k == 5 { 32.0 } else { -32.0 }).abs() < 1e-3);
+            } else {
+                assert!(magnitude < 1e-3, "bin {k}: {magnitude}");
+            }
+        }
+    }
+
+    #[test]
+    fn bytes_round_trip() {
+        let print = noise(1, 100);
+        assert_eq!(to_bytes(&print).len(), 400);
+        assert_eq!(from_bytes(&to_bytes(&print)), print);
+    }
+
+    #[test]
+    fn similarity_of_identical_and_offset_prints() {
+        let print = noise(2, 500);
+        assert_eq!(similarity(&print, &print, 0), 1.0);
+
+        // Like leading silence cut off one of them
+        assert_eq!(similarity(&print, &print[7..], 10), 1.0);
+        assert_eq!(similarity(&print[7..], &print, 10), 1.0);
+        assert!(similarity(&print, &print[7..], 5) < THRESHOLD);
+
+        let other = noise(3, 500);
+        let unrelated = similarity(&print, &other, MAX_OFFSET);
+        assert!(unrelated < THRESHOLD, "{unrelated}");
+        assert!(similarity(&[], &print, MAX_OFFSET) == 0.0);
+    }
+
+    #[test]
+    fn threshold_tells_copies_apart() {
+        // Flip `bits` of every word
+        let flipped = |print: &[u32], bits: u32| {
+            print
+                .iter()
+                .map(|w| w ^ ((1u64 << bits) - 1) as u32)
+                .collect::<Vec<u32>>()
+        };
+        let print = noise(4, 500);
+
+        // A quarter of the bits off is still the same recording
+        assert!(similarity(&print, &flipped(&print, 8), 0) >= THRESHOLD);
+        assert!(similarity(&print, &flipped(&print, 9), 0) < THRESHOLD);
+    }
+
+    #[test]
+    fn matching_pairs_finds_copies_only() {
+        let print = noise(5, 500);
+        let prints = vec![
+            print.clone(),
+            noise(6, 500),
+            print[12..].to_vec(),
+            noise(7, 500),
+        ];
+
+        let pairs = matching_pairs(&prints);
+        assert_eq!(pairs.len(), 1);
+        assert_eq!((pairs[0].0, pairs[0].1), (0, 2));
+        assert_eq!(pairs[0].2, 1.0);
+    }
+
+    #[test]
+    fn fingerprint_survives_volume_and_tells_melodies_apart() {
+        let song = melody(8, 10);
+        let quieter = song.iter().map(|s| s * 0.5).collect::<Vec<f32>>();
+        let other = melody(9, 10);
+
+        let print = fingerprint(&song);
+        assert_eq!(print.len(), (song.len() - FRAME) / HOP);
+        assert!(similarity(&print, &fingerprint(&quieter), MAX_OFFSET) > 0.95);
+        assert!(similarity(&print, &fingerprint(&other), MAX_OFFSET) < THRESHOLD);
+        assert!(fingerprint(&song[..FRAME - 1]).is_empty());
+    }
+}
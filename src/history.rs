@@ -0,0 +1,218 @@
+//! Every play is recorded in `plays`, `/api/stats` summarizes them
+
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    routing::get,
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+
+use crate::{library, utils, AppState, Track};
+
+type ApiResult<T> = Result<T, (StatusCode, String)>;
+
+fn internal(e: sqlx::Error) -> (StatusCode, String) {
+    tracing::error!("Stats query failed: {}", e);
+    (StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+}
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/", get(summary))
+        .route("/top-tracks", get(top_tracks))
+        .route("/top-artists", get(top_artists))
+}
+
+/// Returns the id of the new play, `listened` can be filled in later with [`update_play`]
+pub async fn record(state: &AppState, track: &Track, listened: Option<u64>) -> Result<i64, String> {
+    let source = match library::track(state, &track.filename).await? {
+        Some(_) => "local",
+        None => "temp",
+    };
+
+    sqlx::query_scalar(
+        "INSERT INTO plays (filename, title, artist, duration, played_at, listened, source)
+        VALUES (?, ?, ?, ?, ?, ?, ?)
+        RETURNING id",
+    )
+    .bind(&track.filename)
+    .bind(&track.title)
+    .bind(&track.artist)
+    .bind(track.duration.map(|d| d as i64))
+    .bind(utils::unix_now())
+    .bind(listened.map(|l| l as i64))
+    .bind(source)
+    .fetch_one(&state.db)
+    .await
+    .map_err(|e| e.to_string())
+}
+
+/// Keep the history of a local file when it is renamed
+pub async fn rename(state: &AppState, from: &str, to: &str) -> Result<(), String> {
+    sqlx::query("UPDATE plays SET filename = ? WHERE filename = ? AND source = 'local'")
+        .bind(to)
+        .bind(from)
+        .execute(&state.db)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    Ok(())
+}
+
+#[derive(Deserialize)]
+pub struct UpdatePlayRequest {
+    /// Seconds actually listened
+    listened: u64,
+}
+
+pub async fn update_play(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+    Json(body): Json<UpdatePlayRequest>,
+) -> ApiResult<(StatusCode, &'static str)> {
+    let result = sqlx::query("UPDATE plays SET listened = ? WHERE id = ?")
+        .bind(body.listened as i64)
+        .bind(id)
+        .execute(&state.db)
+        .await
+        .map_err(internal)?;
+    if result.rows_affected() == 0 {
+        return Err((StatusCode::NOT_FOUND, "Play not found".to_string()));
+    }
+
+    Ok((StatusCode::OK, "OK"))
+}
+
+/// Unix timestamps, both ends included
+#[derive(Deserialize)]
+struct StatsQuery {
+    from: Option<i64>,
+    to: Option<i64>,
+    limit: Option<u32>,
+}
+
+impl StatsQuery {
+    fn from(&self) -> i64 {
+        self.from.unwrap_or(0)
+    }
+
+    fn to(&self) -> i64 {
+        self.to.unwrap_or(i64::MAX)
+    }
+
+    fn limit(&self) -> u32 {
+        self.limit.unwrap_or(10).min(100)
+    }
+}
+
+// Plays whose listened time was never reported count as listened to the end
+const LISTENED: &str = "COALESCE(SUM(COALESCE(listened, duration)), 0)";
+
+#[derive(Serialize, FromRow)]
+struct Summary {
+    plays: i64,
+    tracks: i64,
+    artists: i64,
+    /// Seconds
+    listening_time: i64,
+    local_plays: i64,
+    temp_plays: i64,
+}
+
+async fn summary(
+    State(state): State<AppState>,
+    Query(query): Query<StatsQuery>,
+) -> ApiResult<Json<Summary>> {
+    let summary = sqlx::query_as(&format!(
+        "SELECT
+            COUNT(*) AS plays,
+            COUNT(DISTINCT filename) AS tracks,
+            COUNT(DISTINCT artist) AS artists,
+            {LISTENED} AS listening_time,
+            COALESCE(SUM(source = 'local'), 0) AS local_plays,
+            COALESCE(SUM(source = 'temp'), 0) AS temp_plays
+        FROM plays
+        WHERE played_at BETWEEN ? AND ?"
+    ))
+    .bind(query.from())
+    .bind(query.to())
+    .fetch_one(&state.db)
+    .await
+    .map_err(internal)?;
+
+    Ok(Json(summary))
+}
+
+#[derive(Serialize, FromRow)]
+struct TopTrack {
+    filename: String,
+    title: String,
+    artist: String,
+    source: String,
+    plays: i64,
+    listening_time: i64,
+    last_played: i64,
+}
+
+async fn top_tracks(
+    State(state): State<AppState>,
+    Query(query): Query<StatsQuery>,
+) -> ApiResult<Json<Vec<TopTrack>>> {
+    // Title and artist come from the latest play, as MAX() picks that row
+    let tracks = sqlx::query_as(&format!(
+        "SELECT filename, title, artist, source,
+            COUNT(*) AS plays,
+            {LISTENED} AS listening_time,
+            MAX(played_at) AS last_played
+        FROM plays
+        WHERE played_at BETWEEN ? AND ?
+        GROUP BY filename
+        ORDER BY plays DESC, last_played DESC
+        LIMIT ?"
+    ))
+    .bind(query.from())
+    .bind(query.to())
+    .bind(query.limit())
+    .fetch_all(&state.db)
+    .await
+    .map_err(internal)?;
+
+    Ok(Json(tracks))
+}
+
+#[derive(Serialize, FromRow)]
+struct TopArtist {
+    artist: String,
+    plays: i64,
+    tracks: i64,
+    listening_time: i64,
+    last_played: i64,
+}
+
+async fn top_artists(
+    State(state): State<AppState>,
+    Query(query): Query<StatsQuery>,
+) -> ApiResult<Json<Vec<TopArtist>>> {
+    let artists = sqlx::query_as(&format!(
+        "SELECT artist,
+            COUNT(*) AS plays,
+            COUNT(DISTINCT filename) AS tracks,
+            {LISTENED} AS listening_time,
+            MAX(played_at) AS last_played
+        FROM plays
+        WHERE played_at BETWEEN ? AND ?
+        GROUP BY artist COLLATE NOCASE
+        ORDER BY plays DESC, last_played DESC
+        LIMIT ?"
+    ))
+    .bind(query.from())
+    .bind(query.to())
+    .bind(query.limit())
+    .fetch_all(&state.db)
+    .await
+    .map_err(internal)?;
+
+    Ok(Json(artists))
+}
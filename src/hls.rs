@@ -0,0 +1,256 @@
+//! HLS for library tracks, so long mixes can be seeked on mobile browsers.
+//! Segments are AAC in MPEG-TS, cut by a single ffmpeg encoding the whole track the first time
+//! one of them is asked for, so they play back gapless. They are kept in the temp cache.
+//!
+//! MPEG-TS rather than fMP4: it is what every HLS client plays, down to protocol version 3,
+//! and each segment stands on its own so it can be served as soon as it is cut, fMP4 would
+//! also need an init segment written before the first one.
+//!
+//! The playlist is served before the encode is done, so its durations come from the library.
+//! ffmpeg can only cut between AAC frames, so a segment is up to a frame off
+//! [`SEGMENT_DURATION`]: `-segment_time_delta` lets it take the frame nearest each boundary,
+//! and the target duration leaves room for a segment running over.
+
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    hash::Hasher,
+    process::Stdio,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use axum::{
+    extract::{Path, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, BufReader},
+    process::Command,
+    sync::{watch, Mutex},
+};
+
+use crate::{library, stream, AppState, MUSIC_DIR, TEMP_DIR};
+
+type ApiResult<T> = Result<T, (StatusCode, String)>;
+
+/// Seconds
+const SEGMENT_DURATION: u64 = 10;
+/// Longest a segment can be, rounded up
+const TARGET_DURATION: u64 = SEGMENT_DURATION + 1;
+/// Seconds a cut may come before a boundary
+const SEGMENT_TIME_DELTA: &str = "0.05";
+const BITRATE: &str = "160k";
+
+/// How far an encode got
+#[derive(Clone, Default)]
+struct Encoded {
+    /// Segments already in the temp cache
+    segments: u64,
+    /// Set once ffmpeg is done
+    result: Option<Result<(), String>>,
+}
+
+/// Encodes in progress, keyed by the name of the track's segments without their number
+#[derive(Default)]
+pub struct Encodes(Mutex<HashMap<String, watch::Receiver<Encoded>>>);
+
+impl Encodes {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/:filename/index.m3u8", get(playlist))
+        .route("/:filename/:segment", get(segment))
+}
+
+/// Path and duration of `filename`, if it is in the library
+async fn source(state: &AppState, filename: &str) -> ApiResult<(String, u64)> {
+    let track = library::track(state, filename)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))?
+        .ok_or_else(|| (StatusCode::NOT_FOUND, format!("{filename} not found")))?;
+
+    let duration = track.duration.filter(|d| *d > 0).ok_or((
+        StatusCode::UNPROCESSABLE_ENTITY,
+        "Unknown duration".to_string(),
+    ))?;
+
+    Ok((format!("{MUSIC_DIR}/{filename}"), duration))
+}
+
+async fn playlist(
+    State(state): State<AppState>,
+    Path(filename): Path<String>,
+) -> ApiResult<Response> {
+    let (_, duration) = source(&state, &filename).await?;
+
+    let mut m3u8 = format!(
+        "#EXTM3U\n\
+        #EXT-X-VERSION:3\n\
+        #EXT-X-TARGETDURATION:{TARGET_DURATION}\n\
+        #EXT-X-MEDIA-SEQUENCE:0\n\
+        #EXT-X-PLAYLIST-TYPE:VOD\n"
+    );
+    for n in 0..duration.div_ceil(SEGMENT_DURATION) {
+        let length = (duration - n * SEGMENT_DURATION).min(SEGMENT_DURATION);
+        _ = write!(m3u8, "#EXTINF:{length},\n{n}.ts\n");
+    }
+    m3u8.push_str("#EXT-X-ENDLIST\n");
+
+    Ok((
+        [(header::CONTENT_TYPE, "application/vnd.apple.mpegurl")],
+        m3u8,
+    )
+        .into_response())
+}
+
+async fn segment(
+    State(state): State<AppState>,
+    Path((filename, segment)): Path<(String, String)>,
+) -> ApiResult<Response> {
+    let unknown = || (StatusCode::NOT_FOUND, "Unknown segment".to_string());
+
+    let n: u64 = segment
+        .strip_suffix(".ts")
+        .and_then(|n| n.parse().ok())
+        .ok_or_else(unknown)?;
+    let (path, duration) = source(&state, &filename).await?;
+    if n >= duration.div_ceil(SEGMENT_DURATION) {
+        return Err(unknown());
+    }
+
+    let meta = std::fs::metadata(&path).map_err(|e| (StatusCode::NOT_FOUND, e.to_string()))?;
+    let key = format!(
+        "hls-{:016x}",
+        stream::file_hasher(&filename, &meta).finish()
+    );
+    let name = segment_name(&key, n);
+
+    let ts = |data: Vec<u8>| ([(header::CONTENT_TYPE, "video/mp2t")], data).into_response();
+
+    if state.temp_cache.lookup(&name) {
+        // Might have been evicted since
+        if let Ok(data) = tokio::fs::read(format!("{TEMP_DIR}/{name}")).await {
+            return Ok(ts(data));
+        }
+    }
+
+    let mut encoded = encoding(&state, &key, &path).await;
+    let encoded = encoded
+        .wait_for(|e| e.segments > n || e.result.is_some())
+        .await
+        .map(|e| e.clone())
+        .unwrap_or_default();
+
+    if encoded.segments > n {
+        state.temp_cache.touch(&name);
+        return tokio::fs::read(format!("{TEMP_DIR}/{name}"))
+            .await
+            .map(ts)
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()));
+    }
+
+    match encoded.result {
+        Some(Err(e)) => Err((StatusCode::INTERNAL_SERVER_ERROR, e)),
+        // Shorter than the library says
+        _ => Err(unknown()),
+    }
+}
+
+fn segment_name(key: &str, n: u64) -> String {
+    format!("{key}-{n}.ts")
+}
+
+/// The encode of the track at `path` into segments named after `key`, started if not running
+async fn encoding(state: &AppState, key: &str, path: &str) -> watch::Receiver<Encoded> {
+    let mut encodes = state.hls.0.lock().await;
+    if let Some(encoded) = encodes.get(key) {
+        return encoded.clone();
+    }
+
+    let (tx, rx) = watch::channel(Encoded::default());
+    encodes.insert(key.to_string(), rx.clone());
+    tokio::spawn(encode(state.clone(), key.to_string(), path.to_string(), tx));
+
+    rx
+}
+
+/// Encode the whole track, moving each segment into the temp cache as soon as it is complete
+async fn encode(state: AppState, key: String, path: String, tx: watch::Sender<Encoded>) {
+    // Unique so segments of an encode that is still winding down aren't written over
+    static ENCODES: AtomicU64 = AtomicU64::new(0);
+    let part = format!(
+        "{TEMP_DIR}/{key}-%d.ts.{}.part",
+        ENCODES.fetch_add(1, Ordering::Relaxed)
+    );
+    let part_of = |n: u64| part.replace("%d", &n.to_string());
+
+    tracing::info!("Encoding HLS segments of {}", path);
+    let result = async {
+        // The segment list gets a line once a segment is closed
+        let mut child = Command::new("ffmpeg")
+            .args(["-v", "error", "-i", &path])
+            .args(["-vn", "-c:a", "aac", "-b:a", BITRATE])
+            .args(["-f", "segment", "-segment_format", "mpegts"])
+            .arg("-segment_time")
+            .arg(SEGMENT_DURATION.to_string())
+            .args(["-segment_time_delta", SEGMENT_TIME_DELTA])
+            .args(["-segment_list", "pipe:1", "-segment_list_type", "flat"])
+            .arg(&part)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|e| format!("Failed to spawn ffmpeg: {e}"))?;
+
+        let mut lines = BufReader::new(child.stdout.take().expect("stdout is piped")).lines();
+        let mut stderr = child.stderr.take().expect("stderr is piped");
+        let mut errors = String::new();
+
+        let read_segments = async {
+            let mut n = 0;
+            while let Ok(Some(_)) = lines.next_line().await {
+                let name = segment_name(&key, n);
+                match tokio::fs::rename(part_of(n), format!("{TEMP_DIR}/{name}")).await {
+                    Ok(()) => state.temp_cache.touch(&name),
+                    Err(e) => return Err(format!("Cannot cache {name}: {e}")),
+                }
+
+                n += 1;
+                tx.send_modify(|e| e.segments = n);
+            }
+
+            Ok(())
+        };
+        let (segments, _) = tokio::join!(read_segments, stderr.read_to_string(&mut errors));
+        segments?;
+
+        match child.wait().await {
+            Ok(status) if status.success() => Ok(()),
+            Ok(_) => Err(format!("ffmpeg error: {errors}")),
+            Err(e) => Err(e.to_string()),
+        }
+    }
+    .await;
+
+    if let Err(e) = &result {
+        tracing::error!("HLS encode of {} failed: {}", path, e);
+
+        // The segment ffmpeg was writing, and the rest if caching failed
+        let done = tx.borrow().segments;
+        let mut n = done;
+        while tokio::fs::remove_file(part_of(n)).await.is_ok() {
+            n += 1;
+        }
+    }
+
+    state.hls.0.lock().await.remove(&key);
+    tx.send_modify(|e| e.result = Some(result));
+}
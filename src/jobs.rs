@@ -0,0 +1,919 @@
+//! Downloads run as background jobs, a few at a time.
+//! Progress of each job is streamed over `/api/jobs/:id/events`.
+
+use std::{
+    collections::BTreeMap,
+    convert::Infallible,
+    process::Stdio,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use audiotags::{MimeType, Picture};
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::sse::{self, KeepAlive, Sse},
+    routing::{get, post},
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, BufReader},
+    process::Command,
+    sync::{broadcast, mpsc, watch, Mutex, Semaphore},
+};
+use tokio_stream::{wrappers::ReceiverStream, Stream, StreamExt};
+
+use crate::{
+    audio_format::{self, AudioFormat, FormatQuery},
+    library, playlists, tags, utils, AppState, QueueItem, Track, IMG_DIR, MAX_RETRIES, MUSIC_DIR,
+};
+
+/// How many yt-dlp processes may run at once
+pub const WORKERS: usize = 2;
+
+/// Finished jobs kept around for clients that connect late
+const KEEP_FINISHED: usize = 50;
+
+/// Last lines of yt-dlp stderr kept per job
+const KEEP_STDERR: usize = 100;
+
+/// yt-dlp works in `PARTIAL_DIR/{job id}` and only moves finished files into `MUSIC_DIR`
+const PARTIAL_DIR: &str = "partial";
+
+const PROGRESS_PREFIX: &str = "[progress]";
+const POSTPROCESS_PREFIX: &str = "[postprocess]";
+const FILEPATH_PREFIX: &str = "[filepath]";
+
+#[derive(Clone, Serialize)]
+#[serde(tag = "status", rename_all = "lowercase")]
+pub enum JobStatus {
+    Queued,
+    Downloading { percent: Option<f32> },
+    Postprocessing,
+    Done { track: Downloaded },
+    Failed { error: String },
+    Cancelled,
+}
+
+impl JobStatus {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Queued => "queued",
+            Self::Downloading { .. } => "downloading",
+            Self::Postprocessing => "postprocessing",
+            Self::Done { .. } => "done",
+            Self::Failed { .. } => "failed",
+            Self::Cancelled => "cancelled",
+        }
+    }
+
+    fn is_finished(&self) -> bool {
+        matches!(
+            self,
+            Self::Done { .. } | Self::Failed { .. } | Self::Cancelled
+        )
+    }
+}
+
+impl From<JobStatus> for sse::Event {
+    fn from(status: JobStatus) -> Self {
+        sse::Event::default()
+            .event(status.name())
+            .json_data(&status)
+            .expect("serialize job status to json")
+    }
+}
+
+/// What `/download` has always answered with
+#[derive(Clone, Serialize)]
+pub struct Downloaded {
+    filename: String,
+    title: String,
+    artist: String,
+    thumbnail: String,
+    duration: f32,
+}
+
+impl From<Track> for Downloaded {
+    fn from(track: Track) -> Self {
+        Self {
+            filename: track.filename,
+            title: track.title,
+            artist: track.artist,
+            thumbnail: track.thumbnail.unwrap_or_default(),
+            duration: track.duration.unwrap_or_default() as f32,
+        }
+    }
+}
+
+#[derive(Clone, Serialize)]
+pub struct Job {
+    id: u64,
+    url: String,
+    audio_format: AudioFormat,
+
+    #[serde(flatten)]
+    status: JobStatus,
+
+    created_at: i64,
+    updated_at: i64,
+
+    /// What yt-dlp complained about, across retries
+    stderr: Vec<String>,
+
+    #[serde(skip)]
+    events: broadcast::Sender<JobStatus>,
+    #[serde(skip)]
+    cancel: watch::Sender<bool>,
+    /// Kept even when finished, until whoever waits on it unpins it
+    #[serde(skip)]
+    pinned: bool,
+}
+
+pub struct Jobs {
+    jobs: Mutex<BTreeMap<u64, Job>>,
+    next_id: AtomicU64,
+    workers: Arc<Semaphore>,
+}
+
+impl Jobs {
+    pub fn new(workers: usize) -> Self {
+        Self {
+            jobs: Mutex::new(BTreeMap::new()),
+            next_id: AtomicU64::new(1),
+            workers: Arc::new(Semaphore::new(workers)),
+        }
+    }
+
+    async fn set_status(&self, id: u64, status: JobStatus) {
+        let mut jobs = self.jobs.lock().await;
+        let Some(job) = jobs.get_mut(&id) else {
+            return;
+        };
+
+        job.status = status.clone();
+        job.updated_at = utils::unix_now();
+        _ = job.events.send(status);
+    }
+
+    /// Current status along with a receiver for what comes after it
+    async fn subscribe(&self, id: u64) -> Option<(JobStatus, broadcast::Receiver<JobStatus>)> {
+        let jobs = self.jobs.lock().await;
+        let job = jobs.get(&id)?;
+
+        Some((job.status.clone(), job.events.subscribe()))
+    }
+
+    /// Let job `id` be evicted like any other finished job
+    async fn unpin(&self, id: u64) {
+        if let Some(job) = self.jobs.lock().await.get_mut(&id) {
+            job.pinned = false;
+        }
+    }
+
+    async fn log(&self, id: u64, line: String) {
+        let mut jobs = self.jobs.lock().await;
+        let Some(job) = jobs.get_mut(&id) else {
+            return;
+        };
+
+        if job.stderr.len() >= KEEP_STDERR {
+            job.stderr.remove(0);
+        }
+        job.stderr.push(line);
+    }
+}
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/", get(list_jobs).post(create_job))
+        .route("/playlist", post(download_playlist))
+        .route("/:id", get(get_job))
+        .route("/:id/events", get(job_events))
+        .route("/:id/cancel", post(cancel_job))
+        .route("/:id/retry", post(retry_job))
+}
+
+/// Queue `url` for download and return the job id right away
+pub async fn enqueue(state: &AppState, url: String, format: AudioFormat) -> u64 {
+    push(state, url, format, false).await
+}
+
+/// [`enqueue`], with a `pinned` job surviving eviction until [`Jobs::unpin`]
+async fn push(state: &AppState, url: String, format: AudioFormat, pinned: bool) -> u64 {
+    let id = state.jobs.next_id.fetch_add(1, Ordering::Relaxed);
+    let now = utils::unix_now();
+
+    let (cancel, cancelled) = watch::channel(false);
+
+    let mut jobs = state.jobs.jobs.lock().await;
+    jobs.insert(
+        id,
+        Job {
+            id,
+            url: url.clone(),
+            audio_format: format,
+            status: JobStatus::Queued,
+            created_at: now,
+            updated_at: now,
+            stderr: vec![],
+            events: broadcast::channel(16).0,
+            cancel,
+            pinned,
+        },
+    );
+
+    let finished = jobs
+        .values()
+        .filter(|j| j.status.is_finished() && !j.pinned)
+        .map(|j| j.id)
+        .collect::<Vec<u64>>();
+    for old in finished
+        .iter()
+        .take(finished.len().saturating_sub(KEEP_FINISHED))
+    {
+        jobs.remove(old);
+    }
+    drop(jobs);
+
+    tokio::spawn(run(state.clone(), id, url, format, cancelled));
+
+    id
+}
+
+/// Wait for job `id` to finish
+pub async fn wait(state: &AppState, id: u64) -> Result<Downloaded, String> {
+    let (mut status, mut events) = state
+        .jobs
+        .subscribe(id)
+        .await
+        .ok_or_else(|| "Job not found".to_string())?;
+
+    loop {
+        match status {
+            JobStatus::Done { track } => return Ok(track),
+            JobStatus::Failed { error } => return Err(error),
+            JobStatus::Cancelled => return Err("Cancelled".to_string()),
+            _ => {}
+        }
+
+        status = match events.recv().await {
+            Ok(s) => s,
+            Err(broadcast::error::RecvError::Lagged(_)) => continue,
+            Err(broadcast::error::RecvError::Closed) => return Err("Job vanished".to_string()),
+        };
+    }
+}
+
+/// Resolves once the job is cancelled
+async fn cancelled(cancel: &mut watch::Receiver<bool>) {
+    if cancel.wait_for(|c| *c).await.is_err() {
+        // The job is gone, nobody can cancel it anymore
+        std::future::pending::<()>().await;
+    }
+}
+
+async fn run(
+    state: AppState,
+    id: u64,
+    url: String,
+    format: AudioFormat,
+    mut cancel: watch::Receiver<bool>,
+) {
+    let _permit = tokio::select! {
+        permit = state.jobs.workers.clone().acquire_owned() => {
+            permit.expect("worker semaphore is never closed")
+        }
+        _ = cancelled(&mut cancel) => {
+            tracing::info!("Cancelled before start: {}", url);
+            state.jobs.set_status(id, JobStatus::Cancelled).await;
+            return;
+        }
+    };
+
+    tracing::info!("Downloading: {}", url);
+
+    // In a task of its own so a panic fails the job instead of leaving it downloading forever
+    let downloading = {
+        let (state, url, mut cancel) = (state.clone(), url.clone(), cancel.clone());
+        tokio::spawn(async move { download(&state, id, &url, format, &mut cancel).await })
+    };
+    let downloaded = downloading
+        .await
+        .unwrap_or_else(|e| Err(format!("Download panicked: {e}")));
+
+    let status = match downloaded {
+        Ok(track) => JobStatus::Done { track },
+        Err(_) if *cancel.borrow() => {
+            tracing::info!("Cancelled: {}", url);
+            JobStatus::Cancelled
+        }
+        Err(e) => {
+            tracing::error!("Download of {} failed: {}", url, e);
+            JobStatus::Failed { error: e }
+        }
+    };
+
+    // Leftover .part files, intermediate formats and thumbnails
+    _ = std::fs::remove_dir_all(format!("{PARTIAL_DIR}/{id}"));
+
+    state.jobs.set_status(id, status).await;
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Artist {
+    artist: Option<String>,
+    channel: Option<String>,
+    uploader: Option<String>,
+}
+
+impl Artist {
+    pub fn get(self) -> String {
+        self.artist
+            .or(self.uploader)
+            .or(self.channel)
+            .unwrap_or_else(|| "Unknown".to_string())
+    }
+}
+
+/// What yt-dlp prints about a video
+#[derive(Deserialize)]
+pub struct DownloadResponse {
+    pub id: String,
+    pub title: String,
+    pub description: Option<String>,
+
+    #[serde(flatten)]
+    pub artist: Artist,
+
+    pub thumbnail: String,
+    pub duration: f32,
+}
+
+impl DownloadResponse {
+    /// Auto-generated YouTube Music uploads, their thumbnail is the album art centered in 16:9
+    pub fn is_music(&self) -> bool {
+        self.description
+            .as_deref()
+            .is_some_and(|d| d.starts_with("Provided to YouTube by"))
+    }
+}
+
+/// Re-encode an image as JPEG, cropped to its centered square if `square`
+pub fn cover_jpeg(data: &[u8], square: bool) -> Result<Vec<u8>, String> {
+    let mut img = image::load_from_memory(data)
+        .map_err(|e| format!("Load image error: {e}"))?
+        .into_rgb8();
+
+    let (width, height) = img.dimensions();
+    if square && width != height {
+        let side = width.min(height);
+        let (x, y) = ((width - side) / 2, (height - side) / 2);
+        img = image::imageops::crop(&mut img, x, y, side, side).to_image();
+    }
+
+    let mut buffer = Vec::with_capacity(img.len());
+    img.write_to(
+        &mut std::io::Cursor::new(&mut buffer),
+        image::ImageFormat::Jpeg,
+    )
+    .map_err(|e| format!("Crop image error: {e}"))?;
+
+    Ok(buffer)
+}
+
+async fn download(
+    state: &AppState,
+    id: u64,
+    url: &str,
+    format: AudioFormat,
+    cancel: &mut watch::Receiver<bool>,
+) -> Result<Downloaded, String> {
+    if let Some(video_id) = utils::youtube_id(url) {
+        if let Some(track) = library::find_by_source(state, &video_id).await? {
+            tracing::info!("{} is already downloaded as {}", video_id, track.filename);
+            return Ok(Downloaded::from(track));
+        }
+    }
+
+    let mut i = 0;
+    let (stdout, filename) = loop {
+        i += 1;
+
+        match yt_dlp(state, id, url, format, cancel).await {
+            Ok(printed) => break printed,
+            Err(e) => {
+                tracing::error!("Yt-DLP stderr: {}", e);
+                if i == MAX_RETRIES || *cancel.borrow() {
+                    return Err(e);
+                }
+            }
+        }
+    };
+
+    #[cfg(debug_assertions)]
+    tracing::debug!("Parsing JSON from yt-dlp...");
+
+    let parsed: DownloadResponse = serde_json::from_str(&stdout).map_err(|e| {
+        tracing::error!("Failed to parse JSON: {}\n{}", e, stdout);
+        "Failed to parse JSON".to_string()
+    })?;
+
+    let is_music = parsed.is_music();
+    let mut image_path = parsed.thumbnail;
+
+    if is_music {
+        tracing::info!("Cropping image for {}...", parsed.title);
+        let music_path = format!("{MUSIC_DIR}/{filename}");
+
+        let mut tag = tags::read(state, &music_path).map_err(|e| {
+            let message = format!("Open music file error: {e}");
+            tracing::error!("{message} | path: {music_path}");
+            message
+        })?;
+
+        if let Some(cover) = tag.album_cover() {
+            let buffer = cover_jpeg(cover.data, true).map_err(|e| {
+                tracing::error!("{}", e);
+                e
+            })?;
+
+            image_path = format!("{IMG_DIR}/{}.jpeg", utils::without_extension(&filename));
+            _ = std::fs::write(&image_path, &buffer);
+
+            tag.set_album_cover(Picture::new(&buffer, MimeType::Jpeg));
+            tag.write_to_path(&music_path)?;
+        }
+    }
+
+    // Normally already there from --embed-metadata, the index relies on it
+    if let Err(e) = tag_source(state, &filename, &parsed.id) {
+        tracing::warn!("Cannot store source of {}: {}", filename, e);
+    }
+
+    if let Err(e) = library::index_file(state, &filename).await {
+        tracing::error!("Failed to index {}: {}", parsed.title, e);
+    }
+
+    Ok(Downloaded {
+        filename,
+        title: parsed.title,
+        artist: parsed.artist.get(),
+        thumbnail: image_path,
+        duration: parsed.duration,
+    })
+}
+
+fn tag_source(state: &AppState, filename: &str, id: &str) -> Result<(), String> {
+    let path = format!("{MUSIC_DIR}/{filename}");
+    let mut tag = tags::read(state, &path)?;
+    if tag.source_id().as_deref() == Some(id) {
+        return Ok(());
+    }
+
+    tag.set_source_id(id);
+    tag.write_to_path(&path)
+}
+
+/// Run yt-dlp once, reporting its progress on job `id`. Returns the printed metadata JSON
+/// and the name of the file in `MUSIC_DIR`, which yt-dlp sanitizes from the title.
+async fn yt_dlp(
+    state: &AppState,
+    id: u64,
+    url: &str,
+    format: AudioFormat,
+    cancel: &mut watch::Receiver<bool>,
+) -> Result<(String, String), String> {
+    state
+        .jobs
+        .set_status(id, JobStatus::Downloading { percent: None })
+        .await;
+
+    let mut child = Command::new("yt-dlp")
+        .args(format.yt_dlp_args())
+        .args([
+            "--no-playlist",
+            "--no-warning",
+            "--embed-thumbnail",
+            "--embed-metadata",
+            "--print-json",
+            "--newline",
+            "--progress",
+            "--progress-template",
+            &format!(
+                "download:{PROGRESS_PREFIX} %(progress.downloaded_bytes)s \
+                %(progress.total_bytes)s %(progress.total_bytes_estimate)s"
+            ),
+            "--progress-template",
+            &format!("postprocess:{POSTPROCESS_PREFIX} %(progress.postprocessor)s"),
+            "--print",
+            &format!("after_move:{FILEPATH_PREFIX} %(filepath)s"),
+            "-P",
+            &format!("temp:{PARTIAL_DIR}/{id}"),
+            "-o",
+            &format!("{MUSIC_DIR}/%(title)s.%(ext)s"),
+            "--",
+            url,
+        ])
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| format!("Failed to spawn yt-dlp: {e}"))?;
+
+    // yt-dlp sends progress to stderr when printing JSON, so read both as they come
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    tokio::spawn(forward_lines(
+        child.stdout.take().expect("stdout is piped"),
+        false,
+        tx.clone(),
+    ));
+    tokio::spawn(forward_lines(
+        child.stderr.take().expect("stderr is piped"),
+        true,
+        tx,
+    ));
+
+    let mut json = None;
+    let mut filepath = None;
+    let mut errors = vec![];
+    let mut last_percent = None;
+    let mut postprocessing = false;
+
+    loop {
+        let (from_stderr, line) = tokio::select! {
+            line = rx.recv() => match line {
+                Some(l) => l,
+                None => break,
+            },
+            _ = cancelled(cancel) => {
+                _ = child.kill().await;
+                return Err("Cancelled".to_string());
+            }
+        };
+
+        if let Some(progress) = line.strip_prefix(PROGRESS_PREFIX) {
+            let percent = parse_percent(progress);
+
+            // One event per whole percent is plenty
+            if percent.map(|p| p as u32) != last_percent {
+                last_percent = percent.map(|p| p as u32);
+                state
+                    .jobs
+                    .set_status(id, JobStatus::Downloading { percent })
+                    .await;
+            }
+        } else if line.starts_with(POSTPROCESS_PREFIX) || line.starts_with("[ExtractAudio]") {
+            if !postprocessing {
+                postprocessing = true;
+                state.jobs.set_status(id, JobStatus::Postprocessing).await;
+            }
+        } else if !from_stderr && line.starts_with('{') {
+            json = Some(line);
+        } else if let Some(path) = line.strip_prefix(FILEPATH_PREFIX) {
+            filepath = Some(path.trim().to_string());
+        } else if from_stderr {
+            state.jobs.log(id, line.clone()).await;
+            errors.push(line);
+        }
+    }
+
+    let status = child
+        .wait()
+        .await
+        .map_err(|e| format!("Failed to wait for yt-dlp: {e}"))?;
+    if !status.success() {
+        return Err(if errors.is_empty() {
+            format!("yt-dlp exited with {status}")
+        } else {
+            errors.join("\n")
+        });
+    }
+
+    let json = json.ok_or_else(|| "yt-dlp printed no metadata".to_string())?;
+    let filename = filepath
+        .as_deref()
+        .and_then(|p| std::path::Path::new(p).file_name())
+        .map(|f| f.to_string_lossy().into_owned())
+        .ok_or_else(|| "yt-dlp printed no file path".to_string())?;
+
+    Ok((json, filename))
+}
+
+async fn forward_lines(
+    reader: impl AsyncRead + Unpin,
+    from_stderr: bool,
+    tx: mpsc::UnboundedSender<(bool, String)>,
+) {
+    let mut lines = BufReader::new(reader).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        if tx.send((from_stderr, line)).is_err() {
+            break;
+        }
+    }
+}
+
+/// `downloaded total total_estimate`, yt-dlp prints `NA` for what it doesn't know
+fn parse_percent(progress: &str) -> Option<f32> {
+    let mut numbers = progress.split_whitespace().map(|n| n.parse::<f64>().ok());
+
+    let downloaded = numbers.next().flatten()?;
+    let total = numbers.next().flatten();
+    let estimate = numbers.next().flatten();
+
+    total
+        .or(estimate)
+        .filter(|t| *t > 0.0)
+        .map(|t| (downloaded / t * 100.0).min(100.0) as f32)
+}
+
+async fn create_job(
+    State(state): State<AppState>,
+    Query(query): Query<FormatQuery>,
+    body: String,
+) -> Result<(StatusCode, Json<serde_json::Value>), (StatusCode, String)> {
+    let format = audio_format::requested(&state, &query).await?;
+    let id = enqueue(&state, body, format).await;
+
+    Ok((StatusCode::ACCEPTED, Json(json!({ "id": id }))))
+}
+
+/// Newest first
+async fn list_jobs(State(state): State<AppState>) -> Json<Vec<Job>> {
+    Json(
+        state
+            .jobs
+            .jobs
+            .lock()
+            .await
+            .values()
+            .rev()
+            .cloned()
+            .collect(),
+    )
+}
+
+async fn get_job(
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<Json<Job>, (StatusCode, &'static str)> {
+    state
+        .jobs
+        .jobs
+        .lock()
+        .await
+        .get(&id)
+        .cloned()
+        .map(Json)
+        .ok_or((StatusCode::NOT_FOUND, "Job not found"))
+}
+
+/// Sends the current status first, the stream ends once the job is done or failed
+async fn job_events(
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<Sse<impl Stream<Item = Result<sse::Event, Infallible>>>, (StatusCode, &'static str)> {
+    let (status, mut events) = state
+        .jobs
+        .subscribe(id)
+        .await
+        .ok_or((StatusCode::NOT_FOUND, "Job not found"))?;
+
+    let (tx, rx) = mpsc::channel(16);
+    tokio::spawn(async move {
+        let mut status = status;
+        loop {
+            let finished = status.is_finished();
+            if tx.send(status).await.is_err() || finished {
+                break;
+            }
+
+            status = loop {
+                match events.recv().await {
+                    Ok(s) => break s,
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => return,
+                }
+            };
+        }
+    });
+
+    let stream = ReceiverStream::new(rx).map(|status| Ok(status.into()));
+
+    Ok(Sse::new(stream).keep_alive(KeepAlive::default()))
+}
+
+async fn cancel_job(
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<(StatusCode, &'static str), (StatusCode, &'static str)> {
+    let jobs = state.jobs.jobs.lock().await;
+    let job = jobs
+        .get(&id)
+        .ok_or((StatusCode::NOT_FOUND, "Job not found"))?;
+    if job.status.is_finished() {
+        return Err((StatusCode::CONFLICT, "Job already finished"));
+    }
+
+    job.cancel.send_replace(true);
+
+    Ok((StatusCode::OK, "OK"))
+}
+
+/// Run a failed or cancelled job again under the same id
+async fn retry_job(
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<(StatusCode, Json<Job>), (StatusCode, &'static str)> {
+    let mut jobs = state.jobs.jobs.lock().await;
+    let job = jobs
+        .get_mut(&id)
+        .ok_or((StatusCode::NOT_FOUND, "Job not found"))?;
+    if !matches!(job.status, JobStatus::Failed { .. } | JobStatus::Cancelled) {
+        return Err((
+            StatusCode::CONFLICT,
+            "Only failed or cancelled jobs can be retried",
+        ));
+    }
+
+    let (cancel, cancelled) = watch::channel(false);
+    job.cancel = cancel;
+    job.status = JobStatus::Queued;
+    job.stderr.clear();
+    job.updated_at = utils::unix_now();
+    _ = job.events.send(JobStatus::Queued);
+
+    let job = job.clone();
+    drop(jobs);
+
+    tokio::spawn(run(
+        state.clone(),
+        id,
+        job.url.clone(),
+        job.audio_format,
+        cancelled,
+    ));
+
+    Ok((StatusCode::ACCEPTED, Json(job)))
+}
+
+#[derive(Deserialize)]
+struct FlatPlaylist {
+    title: Option<String>,
+    #[serde(default)]
+    entries: Vec<Option<FlatEntry>>,
+}
+
+#[derive(Deserialize)]
+struct FlatEntry {
+    id: Option<String>,
+    url: Option<String>,
+}
+
+/// Bare ids are turned into URLs yt-dlp understands, anything else is passed through
+fn playlist_url(input: &str) -> String {
+    if input.contains("://") {
+        input.to_string()
+    } else if input.starts_with("MPREb") {
+        // YouTube Music album
+        format!("https://music.youtube.com/browse/{input}")
+    } else {
+        format!("https://www.youtube.com/playlist?list={input}")
+    }
+}
+
+/// List the videos of a playlist or album without downloading anything
+async fn expand(url: &str) -> Result<(Option<String>, Vec<String>), String> {
+    let output = Command::new("yt-dlp")
+        .args(["--flat-playlist", "-J", "--no-warning", "--", url])
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .output()
+        .await
+        .map_err(|e| format!("Failed to spawn yt-dlp: {e}"))?;
+
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stderr).to_string());
+    }
+
+    let playlist: FlatPlaylist =
+        serde_json::from_slice(&output.stdout).map_err(|e| format!("Failed to parse JSON: {e}"))?;
+
+    let urls = playlist
+        .entries
+        .into_iter()
+        .flatten()
+        .filter_map(|e| e.id.map(|id| utils::youtube_url(&id)).or(e.url))
+        .collect();
+
+    Ok((playlist.title, urls))
+}
+
+#[derive(Deserialize)]
+struct PlaylistDownloadRequest {
+    /// Playlist or album URL, or just its id
+    url: String,
+
+    /// Also save the tracks as a playlist in the source order
+    #[serde(default)]
+    save: bool,
+    /// Name of the saved playlist, the source title by default
+    name: Option<String>,
+}
+
+#[derive(Serialize)]
+struct PlaylistDownload {
+    title: Option<String>,
+    jobs: Vec<u64>,
+    playlist: Option<i64>,
+}
+
+/// Queue one job per video of a playlist or album
+async fn download_playlist(
+    State(state): State<AppState>,
+    Query(query): Query<FormatQuery>,
+    Json(body): Json<PlaylistDownloadRequest>,
+) -> Result<(StatusCode, Json<PlaylistDownload>), (StatusCode, String)> {
+    let format = audio_format::requested(&state, &query).await?;
+    let url = playlist_url(body.url.trim());
+    tracing::info!("Expanding playlist: {}", url);
+
+    let (title, urls) = expand(&url).await.map_err(|e| {
+        tracing::error!("Cannot expand {}: {}", url, e);
+        (StatusCode::BAD_REQUEST, e)
+    })?;
+    if urls.is_empty() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "Not a playlist or empty".to_string(),
+        ));
+    }
+
+    // Created first so a failure doesn't leave pinned jobs behind
+    let playlist = if body.save {
+        let name = body
+            .name
+            .or(title.clone())
+            .map(|n| n.trim().to_string())
+            .filter(|n| !n.is_empty())
+            .unwrap_or_else(|| "Downloaded playlist".to_string());
+
+        Some(playlists::create(&state, &name).await?)
+    } else {
+        None
+    };
+
+    // Pinned so they are still there when fill_playlist gets to them
+    let mut jobs = Vec::with_capacity(urls.len());
+    for url in urls {
+        jobs.push(push(&state, url, format, playlist.is_some()).await);
+    }
+
+    if let Some(id) = playlist {
+        tokio::spawn(fill_playlist(state.clone(), id, jobs.clone()));
+    }
+
+    Ok((
+        StatusCode::ACCEPTED,
+        Json(PlaylistDownload {
+            title,
+            jobs,
+            playlist,
+        }),
+    ))
+}
+
+/// Add the tracks of `jobs` to `playlist` in order once they are all finished.
+/// Failed jobs are left out, retrying them later doesn't add them back.
+async fn fill_playlist(state: AppState, playlist: i64, jobs: Vec<u64>) {
+    let mut items = vec![];
+    for id in jobs {
+        let waited = wait(&state, id).await;
+        state.jobs.unpin(id).await;
+
+        let track = match waited {
+            Ok(t) => t,
+            Err(e) => {
+                tracing::warn!("Leaving job {} out of playlist {}: {}", id, playlist, e);
+                continue;
+            }
+        };
+
+        match library::track(&state, &track.filename).await {
+            Ok(Some(t)) => items.push(QueueItem::from(t)),
+            Ok(None) => tracing::warn!("{} is not in the library", track.filename),
+            Err(e) => tracing::error!("{} ({})", e, track.filename),
+        }
+    }
+
+    if let Err((_, e)) = playlists::insert_items(&state, playlist, &items).await {
+        tracing::error!("Failed to fill playlist {}: {}", playlist, e);
+    }
+}
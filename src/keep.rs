@@ -0,0 +1,159 @@
+//! Moving a previewed track from the temp cache into the library instead of downloading it again
+
+use std::process::Stdio;
+
+use audiotags::{MimeType, Picture};
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    Json,
+};
+use tokio::process::Command;
+
+use crate::{
+    audio_format::{self, FormatQuery},
+    jobs::{self, DownloadResponse},
+    library, tags, utils, AppState, Track, MUSIC_DIR, TEMP_DIR,
+};
+
+type ApiResult<T> = Result<T, (StatusCode, String)>;
+
+const FILENAME_PREFIX: &str = "[filename]";
+
+/// What a download of video `id` as `ext` would have printed, the file name yt-dlp would have
+/// given it, and its thumbnail if there is one
+async fn metadata(
+    id: &str,
+    ext: &str,
+) -> Result<(DownloadResponse, String, Option<Vec<u8>>), String> {
+    let thumbnail = format!("{TEMP_DIR}/{id}.cover");
+
+    let proc = Command::new("yt-dlp")
+        .args([
+            "--skip-download",
+            "--write-thumbnail",
+            "--convert-thumbnails",
+            "jpg",
+            "-j",
+            "--no-simulate",
+            "--no-playlist",
+            "--no-warning",
+            "--print",
+            &format!("{FILENAME_PREFIX} %(filename)s"),
+            "-o",
+            &format!("%(title)s.{ext}"),
+            "-o",
+            &format!("thumbnail:{thumbnail}.%(ext)s"),
+            "--",
+            id,
+        ])
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .output()
+        .await
+        .map_err(|e| format!("Failed to spawn yt-dlp: {e}"))?;
+
+    if !proc.status.success() {
+        return Err(format!(
+            "yt-dlp error: {}",
+            String::from_utf8_lossy(&proc.stderr)
+        ));
+    }
+
+    let stdout = String::from_utf8_lossy(&proc.stdout);
+    let mut json = None;
+    let mut filename = None;
+    for line in stdout.lines() {
+        if let Some(name) = line.strip_prefix(FILENAME_PREFIX) {
+            filename = Some(name.trim().to_string());
+        } else if line.starts_with('{') {
+            json = Some(line);
+        }
+    }
+
+    let json = json.ok_or_else(|| "yt-dlp printed no metadata".to_string())?;
+    let parsed = serde_json::from_str(json).map_err(|e| format!("Failed to parse JSON: {e}"))?;
+    let filename = filename
+        .as_deref()
+        .and_then(|name| std::path::Path::new(name).file_name())
+        .map(|name| name.to_string_lossy().into_owned())
+        .ok_or_else(|| "yt-dlp printed no usable file name".to_string())?;
+
+    let path = format!("{thumbnail}.jpg");
+    let cover = std::fs::read(&path).ok();
+    _ = std::fs::remove_file(&path);
+
+    Ok((parsed, filename, cover))
+}
+
+/// Move `temp/{id}.{ext}` into the library, tagged and named like a normal download
+pub async fn keep(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Query(query): Query<FormatQuery>,
+) -> ApiResult<Json<Track>> {
+    let internal = |e: String| (StatusCode::INTERNAL_SERVER_ERROR, e);
+
+    // Part of the paths below
+    let video_id = utils::youtube_id(&id)
+        .filter(|_| !id.contains(['/', '\\']) && !id.contains(".."))
+        .ok_or_else(|| (StatusCode::BAD_REQUEST, format!("Invalid video id {id}")))?;
+
+    let format = audio_format::requested(&state, &query).await?;
+    let temp_path = format!("{TEMP_DIR}/{id}.{}", format.extension());
+    if !std::path::Path::new(&temp_path).exists() {
+        return Err((
+            StatusCode::NOT_FOUND,
+            format!("{id} is not in the temp cache"),
+        ));
+    }
+
+    if let Some(track) = library::find_by_source(&state, &video_id)
+        .await
+        .map_err(internal)?
+    {
+        tracing::info!("{} is already kept as {}", video_id, track.filename);
+        return Ok(Json(track));
+    }
+
+    tracing::info!("Keeping {}", id);
+    let (info, filename, thumbnail) = metadata(&id, format.extension()).await.map_err(|e| {
+        tracing::error!("Cannot get metadata of {}: {}", id, e);
+        (StatusCode::BAD_GATEWAY, e)
+    })?;
+
+    let music_path = format!("{MUSIC_DIR}/{filename}");
+    if std::path::Path::new(&music_path).exists() {
+        return Err((StatusCode::CONFLICT, format!("{filename} already exists")));
+    }
+
+    // Tagged while still in temp so the watcher only ever sees the finished file
+    let is_music = info.is_music();
+    let mut tag = tags::read_or_create(&state, &temp_path).map_err(internal)?;
+    tag.set_title(&info.title);
+    tag.set_artist(&info.artist.get());
+    tag.set_source_id(&info.id);
+    match thumbnail.map(|t| jobs::cover_jpeg(&t, is_music)) {
+        Some(Ok(cover)) => tag.set_album_cover(Picture::new(&cover, MimeType::Jpeg)),
+        Some(Err(e)) => tracing::warn!("Cannot embed cover of {}: {}", filename, e),
+        None => tracing::warn!("No thumbnail for {}", filename),
+    }
+    tag.write_to_path(&temp_path).map_err(internal)?;
+
+    // Renaming fails across filesystems
+    if std::fs::rename(&temp_path, &music_path).is_err() {
+        std::fs::copy(&temp_path, &music_path).map_err(|e| internal(e.to_string()))?;
+        _ = std::fs::remove_file(&temp_path);
+    }
+
+    library::index_file(&state, &filename)
+        .await
+        .map_err(internal)?;
+    let track = library::track(&state, &filename)
+        .await
+        .map_err(internal)?
+        .ok_or_else(|| internal(format!("{filename} was not indexed")))?;
+
+    Ok(Json(track))
+}
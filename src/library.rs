@@ -0,0 +1,459 @@
+use std::{
+    collections::{HashMap, HashSet},
+    time::UNIX_EPOCH,
+};
+
+use audiotags::{MimeType, Picture};
+use axum::response::sse;
+use sqlx::{
+    sqlite::{SqliteConnectOptions, SqliteJournalMode},
+    FromRow, SqlitePool,
+};
+
+use crate::{fingerprint, probe, tags, utils, AppState, Track, IMG_DIR, MUSIC_DIR};
+
+const DATABASE_PATH: &str = "library.db";
+
+/// Columns of `library` that make up a [`LibraryRow`], play counts come from `plays`
+const TRACK_COLUMNS: &str = "filename, title, artist, cover, duration, bitrate, sample_rate, \
+    channels, codec, album, album_artist, track_number, disc_number, year, genre, source_id, \
+    (SELECT COUNT(*) FROM plays p WHERE p.filename = library.filename AND p.source = 'local') \
+        AS play_count, \
+    (SELECT MAX(played_at) FROM plays p WHERE p.filename = library.filename AND p.source = 'local') \
+        AS last_played";
+
+#[derive(FromRow)]
+struct LibraryRow {
+    filename: String,
+    title: String,
+    artist: String,
+    cover: Option<String>,
+    duration: Option<i64>,
+    bitrate: Option<i64>,
+    sample_rate: Option<i64>,
+    channels: Option<i64>,
+    codec: Option<String>,
+    album: Option<String>,
+    album_artist: Option<String>,
+    track_number: Option<i64>,
+    disc_number: Option<i64>,
+    year: Option<i64>,
+    genre: Option<String>,
+    source_id: Option<String>,
+    play_count: i64,
+    last_played: Option<i64>,
+}
+
+/// What the index keeps from a file's tags
+#[derive(Default)]
+struct TagInfo {
+    cover: Option<String>,
+    artist: Option<String>,
+    album: Option<String>,
+    album_artist: Option<String>,
+    track_number: Option<u16>,
+    disc_number: Option<u16>,
+    year: Option<i32>,
+    genre: Option<String>,
+    source_id: Option<String>,
+    duration: Option<f64>,
+}
+
+/// Pushed to clients over `/api/events` whenever the index changes
+#[derive(Clone)]
+pub enum LibraryEvent {
+    Indexed(Box<Track>),
+    Removed(String),
+}
+
+impl From<LibraryEvent> for sse::Event {
+    fn from(event: LibraryEvent) -> Self {
+        match event {
+            LibraryEvent::Indexed(track) => sse::Event::default()
+                .event("indexed")
+                .json_data(track)
+                .expect("serialize track to json"),
+            LibraryEvent::Removed(filename) => {
+                sse::Event::default().event("removed").data(filename)
+            }
+        }
+    }
+}
+
+impl From<LibraryRow> for Track {
+    fn from(row: LibraryRow) -> Self {
+        Self {
+            filename: row.filename,
+            title: row.title,
+            artist: row.artist,
+            artists: None,
+            thumbnail: row.cover,
+            duration: row.duration.map(|d| d as u64),
+            artist_thumbnail: None,
+            bitrate: row.bitrate.map(|b| b as u32),
+            sample_rate: row.sample_rate.map(|r| r as u32),
+            channels: row.channels.map(|c| c as u32),
+            codec: row.codec,
+            album: row.album,
+            album_artist: row.album_artist,
+            track_number: row.track_number.map(|n| n as u32),
+            disc_number: row.disc_number.map(|n| n as u32),
+            year: row.year.map(|y| y as i32),
+            genre: row.genre,
+            source_url: row.source_id.as_deref().map(utils::youtube_url),
+            source_id: row.source_id,
+            play_count: Some(row.play_count as u32),
+            last_played: row.last_played,
+        }
+    }
+}
+
+pub async fn connect() -> Result<SqlitePool, String> {
+    let options = SqliteConnectOptions::new()
+        .filename(DATABASE_PATH)
+        .create_if_missing(true)
+        .journal_mode(SqliteJournalMode::Wal);
+
+    let pool = SqlitePool::connect_with(options)
+        .await
+        .map_err(|e| e.to_string())?;
+    sqlx::migrate!()
+        .run(&pool)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    Ok(pool)
+}
+
+/// Walk `MUSIC_DIR` and bring the index up to date.
+/// Files whose mtime and size didn't change since the last scan are not opened again.
+pub async fn scan(state: AppState) {
+    let entries = match std::fs::read_dir(MUSIC_DIR) {
+        Ok(e) => e,
+        Err(e) => {
+            tracing::error!("Cannot read {}: {}", MUSIC_DIR, e);
+            return;
+        }
+    };
+
+    let mut seen = HashSet::new();
+    for entry in entries {
+        let entry = match entry {
+            Ok(e) => e,
+            Err(e) => {
+                tracing::error!("{}", e);
+                continue;
+            }
+        };
+
+        let filename = entry.file_name().to_string_lossy().to_string();
+        seen.insert(filename.clone());
+
+        let (mtime, size) = match file_stat(&filename) {
+            Ok(s) => s,
+            Err(e) => {
+                tracing::error!("{} ({})", e, filename);
+                continue;
+            }
+        };
+
+        let indexed: Option<(i64, i64)> =
+            sqlx::query_as("SELECT mtime, size FROM library WHERE filename = ?")
+                .bind(&filename)
+                .fetch_optional(&state.db)
+                .await
+                .unwrap_or(None);
+        if indexed == Some((mtime, size)) {
+            continue;
+        }
+
+        if let Err(e) = index_file(&state, &filename).await {
+            tracing::error!("{} ({})", e, filename);
+        }
+    }
+
+    let indexed: Vec<String> = match sqlx::query_scalar("SELECT filename FROM library")
+        .fetch_all(&state.db)
+        .await
+    {
+        Ok(i) => i,
+        Err(e) => {
+            tracing::error!("Cannot list library: {}", e);
+            return;
+        }
+    };
+
+    for filename in indexed.into_iter().filter(|f| !seen.contains(f)) {
+        tracing::info!("Removing missing file from library: {}", filename);
+        if let Err(e) = remove(&state, &filename).await {
+            tracing::error!("{} ({})", e, filename);
+        }
+    }
+
+    tracing::info!("Library scan finished");
+}
+
+/// Read tags of `MUSIC_DIR/{filename}`, extract its cover into `IMG_DIR` and upsert it into the index.
+pub async fn index_file(state: &AppState, filename: &str) -> Result<(), String> {
+    let path = format!("{MUSIC_DIR}/{filename}");
+    let title = utils::without_extension(filename);
+
+    if !tags::is_supported(filename) {
+        return Err("Unrecognize format".to_string());
+    }
+
+    // Decoding the whole file is slow, and the watcher reindexes files that were just indexed.
+    // The print only depends on the audio, so it is kept while the file looks the same.
+    let stored: Option<(i64, i64, Option<Vec<u8>>)> =
+        sqlx::query_as("SELECT mtime, size, fingerprint FROM library WHERE filename = ?")
+            .bind(filename)
+            .fetch_optional(&state.db)
+            .await
+            .map_err(|e| e.to_string())?;
+    let unchanged_print = match stored {
+        Some((mtime, size, Some(print))) if file_stat(filename) == Ok((mtime, size)) => Some(print),
+        _ => None,
+    };
+
+    // Untagged files are still listed, just without artist and cover
+    let tag = match tags::read(state, &path) {
+        Ok(mut tag) => TagInfo {
+            cover: save_cover(&mut tag, &path, title)?,
+            artist: tag.artist().map(|a| a.to_string()),
+            album: tag.album_title().map(|a| a.to_string()),
+            album_artist: tag.album_artist().map(|a| a.to_string()),
+            track_number: tag.track_number(),
+            disc_number: tag.disc_number(),
+            year: tag.year(),
+            genre: tag.genre().map(|g| g.to_string()),
+            source_id: tag.source_id(),
+            duration: tag.duration(),
+        },
+        Err(e) => {
+            tracing::warn!("Cannot read tags of {}: {}", filename, e);
+            TagInfo::default()
+        }
+    };
+
+    let info = probe::probe(&path).await.unwrap_or_else(|e| {
+        tracing::debug!("Cannot probe {}: {}", filename, e);
+        probe::AudioInfo::default()
+    });
+    let print = match unchanged_print {
+        Some(print) => Some(print),
+        None => {
+            let print = fingerprint::compute(&path).await.unwrap_or_else(|e| {
+                tracing::debug!("Cannot fingerprint {}: {}", filename, e);
+                Vec::new()
+            });
+            (!print.is_empty()).then(|| fingerprint::to_bytes(&print))
+        }
+    };
+
+    // Converting the cover rewrites the file, so stat it only after that
+    let (mtime, size) = file_stat(filename)?;
+
+    sqlx::query(
+        "INSERT INTO library (
+            filename, title, artist, album, duration, cover, mtime, size,
+            bitrate, sample_rate, channels, codec,
+            album_artist, track_number, disc_number, year, genre, source_id, fingerprint
+        )
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+        ON CONFLICT (filename) DO UPDATE SET
+            title = excluded.title,
+            artist = excluded.artist,
+            album = excluded.album,
+            duration = excluded.duration,
+            cover = excluded.cover,
+            mtime = excluded.mtime,
+            size = excluded.size,
+            bitrate = excluded.bitrate,
+            sample_rate = excluded.sample_rate,
+            channels = excluded.channels,
+            codec = excluded.codec,
+            album_artist = excluded.album_artist,
+            track_number = excluded.track_number,
+            disc_number = excluded.disc_number,
+            year = excluded.year,
+            genre = excluded.genre,
+            source_id = excluded.source_id,
+            fingerprint = excluded.fingerprint",
+    )
+    .bind(filename)
+    .bind(title)
+    .bind(tag.artist.unwrap_or_else(|| "Unknown".to_string()))
+    .bind(tag.album)
+    .bind(info.duration.or(tag.duration).map(|d| d.round() as i64))
+    .bind(tag.cover)
+    .bind(mtime)
+    .bind(size)
+    .bind(info.bitrate)
+    .bind(info.sample_rate)
+    .bind(info.channels)
+    .bind(info.codec)
+    .bind(tag.album_artist)
+    .bind(tag.track_number)
+    .bind(tag.disc_number)
+    .bind(tag.year)
+    .bind(tag.genre)
+    .bind(tag.source_id)
+    .bind(print)
+    .execute(&state.db)
+    .await
+    .map_err(|e| e.to_string())?;
+
+    if let Some(track) = track(state, filename).await? {
+        _ = state
+            .library_events
+            .send(LibraryEvent::Indexed(Box::new(track)));
+    }
+
+    Ok(())
+}
+
+/// Drop `filename` from the index and delete its extracted cover
+pub async fn remove(state: &AppState, filename: &str) -> Result<(), String> {
+    let cover: Option<Option<String>> =
+        sqlx::query_scalar("DELETE FROM library WHERE filename = ? RETURNING cover")
+            .bind(filename)
+            .fetch_optional(&state.db)
+            .await
+            .map_err(|e| e.to_string())?;
+
+    let Some(cover) = cover else {
+        return Ok(());
+    };
+
+    if let Some(c) = cover {
+        _ = std::fs::remove_file(&c[1..]);
+    }
+
+    _ = state
+        .library_events
+        .send(LibraryEvent::Removed(filename.to_string()));
+
+    Ok(())
+}
+
+pub async fn track(state: &AppState, filename: &str) -> Result<Option<Track>, String> {
+    let row: Option<LibraryRow> = sqlx::query_as(&format!(
+        "SELECT {TRACK_COLUMNS} FROM library WHERE filename = ?"
+    ))
+    .bind(filename)
+    .fetch_optional(&state.db)
+    .await
+    .map_err(|e| e.to_string())?;
+
+    Ok(row.map(Track::from))
+}
+
+/// The track downloaded from YouTube video `id`, if any
+pub async fn find_by_source(state: &AppState, id: &str) -> Result<Option<Track>, String> {
+    let row: Option<LibraryRow> = sqlx::query_as(&format!(
+        "SELECT {TRACK_COLUMNS} FROM library WHERE source_id = ? LIMIT 1"
+    ))
+    .bind(id)
+    .fetch_optional(&state.db)
+    .await
+    .map_err(|e| e.to_string())?;
+
+    Ok(row.map(Track::from))
+}
+
+/// Fingerprints of every file that has one, by filename
+pub async fn fingerprints(state: &AppState) -> Result<HashMap<String, Vec<u32>>, String> {
+    let rows: Vec<(String, Vec<u8>)> =
+        sqlx::query_as("SELECT filename, fingerprint FROM library WHERE fingerprint IS NOT NULL")
+            .fetch_all(&state.db)
+            .await
+            .map_err(|e| e.to_string())?;
+
+    Ok(rows
+        .into_iter()
+        .map(|(filename, print)| (filename, fingerprint::from_bytes(&print)))
+        .collect())
+}
+
+pub async fn tracks(state: &AppState) -> Result<Vec<Track>, String> {
+    let rows: Vec<LibraryRow> = sqlx::query_as(&format!(
+        "SELECT {TRACK_COLUMNS} FROM library ORDER BY title COLLATE NOCASE"
+    ))
+    .fetch_all(&state.db)
+    .await
+    .map_err(|e| e.to_string())?;
+
+    Ok(rows.into_iter().map(Track::from).collect())
+}
+
+/// Tracks whose album artist, or artist when they have none, is `artist`
+pub async fn tracks_by_album_artist(state: &AppState, artist: &str) -> Result<Vec<Track>, String> {
+    let rows: Vec<LibraryRow> = sqlx::query_as(&format!(
+        "SELECT {TRACK_COLUMNS} FROM library WHERE COALESCE(album_artist, artist) = ?
+        ORDER BY title COLLATE NOCASE"
+    ))
+    .bind(artist)
+    .fetch_all(&state.db)
+    .await
+    .map_err(|e| e.to_string())?;
+
+    Ok(rows.into_iter().map(Track::from).collect())
+}
+
+fn file_stat(filename: &str) -> Result<(i64, i64), String> {
+    let metadata =
+        std::fs::metadata(format!("{MUSIC_DIR}/{filename}")).map_err(|e| e.to_string())?;
+    let mtime = metadata
+        .modified()
+        .map_err(|e| e.to_string())?
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0);
+
+    Ok((mtime, metadata.len() as i64))
+}
+
+/// Save the embedded cover as `IMG_DIR/{title}.jpeg`.
+/// Non-jpeg covers are converted and written back into the file as well.
+fn save_cover(tag: &mut tags::Tag, path: &str, title: &str) -> Result<Option<String>, String> {
+    let image_path = format!("{IMG_DIR}/{title}.jpeg");
+
+    let buffer = match tag.album_cover() {
+        Some(c) if c.mime_type == MimeType::Jpeg => {
+            std::fs::write(&image_path, c.data).map_err(|e| e.to_string())?;
+            return Ok(Some(format!("/{image_path}")));
+        }
+        Some(c) => {
+            tracing::info!("Converting image for: {}...", path);
+
+            let img = image::load_from_memory_with_format(
+                c.data,
+                match c.mime_type {
+                    MimeType::Jpeg => unreachable!("Should not be jpeg"),
+                    MimeType::Png => image::ImageFormat::Png,
+                    MimeType::Bmp => image::ImageFormat::Bmp,
+                    MimeType::Gif => image::ImageFormat::Gif,
+                    MimeType::Tiff => image::ImageFormat::Tiff,
+                },
+            )
+            .map_err(|e| e.to_string())?
+            .into_rgb8();
+
+            let mut buffer = Vec::with_capacity(img.len());
+            img.write_to(
+                &mut std::io::Cursor::new(&mut buffer),
+                image::ImageFormat::Jpeg,
+            )
+            .map_err(|e| e.to_string())?;
+
+            buffer
+        }
+        None => return Ok(None),
+    };
+
+    tag.set_album_cover(Picture::new(&buffer, MimeType::Jpeg));
+    tag.write_to_path(path)?;
+    std::fs::write(&image_path, buffer).map_err(|e| e.to_string())?;
+
+    Ok(Some(format!("/{image_path}")))
+}
@@ -1,21 +1,54 @@
+mod audio_format;
+mod duplicates;
+mod fingerprint;
+mod history;
+mod hls;
+mod jobs;
+mod keep;
+mod library;
+mod mpd;
+mod ogg;
+mod persist;
+mod player;
+mod playlist_files;
+mod playlists;
+mod probe;
+mod radio;
+mod sessions;
+mod stream;
+mod subsonic;
+mod tags;
+mod temp_cache;
+mod temp_stream;
 mod utils;
+mod watcher;
 
 use audiotags::{MimeType, Picture};
 use axum::{
-    extract::{Multipart, Path, State},
-    http::{header, StatusCode},
-    response::{Html, IntoResponse},
-    routing::{get, post},
-    Json, Router,
+    extract::{DefaultBodyLimit, Multipart, Path, Query, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    middleware,
+    response::{
+        sse::{self, KeepAlive, Sse},
+        Html, IntoResponse, Response,
+    },
+    routing::{get, post, put},
+    Extension, Json, Router,
 };
 use serde::{Deserialize, Serialize};
-use serde_json::json;
+use sessions::Device;
 use std::{
     collections::{HashMap, VecDeque},
+    convert::Infallible,
     process::Stdio,
     sync::Arc,
 };
-use tokio::{process::Command, sync::Mutex};
+use tokio::{
+    process::Command,
+    sync::{broadcast, Mutex},
+};
+use tokio_stream::{wrappers::BroadcastStream, Stream, StreamExt};
+use tower::ServiceBuilder;
 use tower_http::services::ServeDir;
 use tower_http::trace::TraceLayer;
 use ytmapi_rs::{auth::BrowserToken, common::YoutubeID};
@@ -24,6 +57,8 @@ const MUSIC_DIR: &str = "music";
 const IMG_DIR: &str = "img";
 const TEMP_DIR: &str = "temp";
 const PUBLIC_DIR: &str = "public";
+/// Bytes, uploads to identify are whole audio files
+const IDENTIFY_LIMIT: usize = 100 * 1024 * 1024;
 
 #[cfg_attr(debug_assertions, derive(Debug))]
 #[derive(Serialize, Deserialize, Clone)]
@@ -41,23 +76,13 @@ pub struct QueueItem {
 #[cfg_attr(debug_assertions, derive(Debug))]
 #[derive(Clone, Serialize, Deserialize)]
 pub struct PlaylistSession {
-    #[serde(skip)]
-    pub is_empty: bool,
-
     pub current_time: f32,
     pub current_index: u32,
     pub queue: Vec<QueueItem>,
-}
 
-impl Default for PlaylistSession {
-    fn default() -> Self {
-        Self {
-            is_empty: true,
-            current_time: 0.0,
-            current_index: 0,
-            queue: vec![],
-        }
-    }
+    /// Set by the server on every save
+    #[serde(default)]
+    pub updated_at: i64,
 }
 
 #[derive(Clone)]
@@ -66,8 +91,19 @@ pub struct AppState {
     youtube_music_search: Arc<ytmapi_rs::YtMusic<BrowserToken>>,
     mp3_reader: Arc<audiotags::Tag>,
     mp4_reader: Arc<audiotags::Tag>,
+    flac_reader: Arc<audiotags::Tag>,
     recently_played: Arc<Mutex<VecDeque<Track>>>,
-    playlist_session: Arc<Mutex<PlaylistSession>>,
+    /// Keyed by device id
+    sessions: Arc<Mutex<HashMap<String, PlaylistSession>>>,
+    db: sqlx::SqlitePool,
+    library_events: broadcast::Sender<library::LibraryEvent>,
+    jobs: Arc<jobs::Jobs>,
+    /// Used for downloads that don't ask for a format
+    audio_format: Arc<Mutex<audio_format::AudioFormat>>,
+    temp_cache: Arc<temp_cache::TempCache>,
+    /// Keyed by station name, format and mp3 bitrate
+    radio: Arc<radio::Stations>,
+    hls: Arc<hls::Encodes>,
 }
 
 #[tokio::main]
@@ -94,6 +130,16 @@ async fn main() {
         tracing::warn!("Cannot check for yt-dlp update: {}", e);
     }
 
+    let db = library::connect().await.expect("Open library database");
+
+    let sessions = sessions::load_all(&db).await;
+    let recently_played = persist::load(&db, persist::RECENTLY_PLAYED)
+        .await
+        .unwrap_or_else(|| VecDeque::with_capacity(10));
+    let audio_format = persist::load(&db, persist::AUDIO_FORMAT)
+        .await
+        .unwrap_or_default();
+
     let state = AppState {
         youtube_search: Arc::new(rusty_ytdl::search::YouTube::new().unwrap()),
         youtube_music_search: Arc::new(
@@ -103,105 +149,42 @@ async fn main() {
         ),
         mp3_reader: Arc::new(audiotags::Tag::new().with_tag_type(audiotags::TagType::Id3v2)),
         mp4_reader: Arc::new(audiotags::Tag::new().with_tag_type(audiotags::TagType::Mp4)),
-        recently_played: Arc::new(Mutex::new(VecDeque::with_capacity(10))),
-        playlist_session: Arc::new(Mutex::new(PlaylistSession::default())),
+        flac_reader: Arc::new(audiotags::Tag::new().with_tag_type(audiotags::TagType::Flac)),
+        recently_played: Arc::new(Mutex::new(recently_played)),
+        sessions: Arc::new(Mutex::new(sessions)),
+        db,
+        library_events: broadcast::channel(64).0,
+        jobs: Arc::new(jobs::Jobs::new(jobs::WORKERS)),
+        audio_format: Arc::new(Mutex::new(audio_format)),
+        temp_cache: Arc::new(temp_cache::TempCache::new()),
+        radio: Arc::new(radio::Stations::new()),
+        hls: Arc::new(hls::Encodes::new()),
     };
 
-    tokio::spawn(async move {
-        let mut interval = tokio::time::interval(std::time::Duration::from_secs(3600));
-
-        loop {
-            interval.tick().await;
-            _ = std::fs::remove_dir_all(TEMP_DIR);
-            _ = std::fs::create_dir(TEMP_DIR);
-        }
-    });
-
     _ = std::fs::create_dir(MUSIC_DIR);
     _ = std::fs::create_dir(IMG_DIR);
+    _ = std::fs::create_dir(TEMP_DIR);
     _ = std::fs::create_dir(PUBLIC_DIR);
 
-    let entries = std::fs::read_dir(MUSIC_DIR).map_err(|e| e.to_string());
+    tokio::spawn(temp_cache::run(state.temp_cache.clone()));
 
-    if let Ok(entries) = entries {
-        for entry in entries {
-            let entry = match entry {
-                Ok(e) => e,
-                Err(e) => {
-                    tracing::error!("{}", e);
-                    continue;
-                }
-            };
-
-            let mp3_reader_clone = state.mp3_reader.clone();
-            let mp4_reader_clone = state.mp4_reader.clone();
-            tokio::spawn(async move {
-                let filename = entry.file_name().to_string_lossy().to_string();
-                let (title, ext) = {
-                    let last_dot = filename.rfind('.');
-
-                    match last_dot {
-                        Some(d) => (filename[0..d].to_string(), filename[d + 1..].to_string()),
-                        None => (filename.clone(), "mp3".to_string()),
-                    }
-                };
+    tokio::spawn(library::scan(state.clone()));
 
-                let reader = match ext.as_str() {
-                    "mp3" => mp3_reader_clone,
-                    "mp4" | "m4a" => mp4_reader_clone,
-                    _ => {
-                        tracing::error!("Unrecognize format ({})", filename);
-                        return;
-                    }
-                };
+    if let Ok(addr) = std::env::var("MPD_ADDR") {
+        tokio::spawn(mpd::serve(state.clone(), addr));
+    }
 
-                match reader.read_from_path(format!("{}/{}", MUSIC_DIR, filename)) {
-                    Ok(mut tag) => {
-                        let cover = tag.album_cover();
-                        if let Some(c) = cover {
-                            let path = format!("img/{}.jpeg", title);
-                            match c.mime_type {
-                                MimeType::Jpeg => {
-                                    std::fs::write(path, c.data).unwrap();
-                                }
-                                _ => {
-                                    tracing::info!("Converting image for: {}...", filename);
-
-                                    let img = image::load_from_memory_with_format(
-                                        c.data,
-                                        match c.mime_type {
-                                            MimeType::Jpeg => unreachable!("Should not be jpeg"),
-                                            MimeType::Png => image::ImageFormat::Png,
-                                            MimeType::Bmp => image::ImageFormat::Bmp,
-                                            MimeType::Gif => image::ImageFormat::Gif,
-                                            MimeType::Tiff => image::ImageFormat::Tiff,
-                                        },
-                                    )
-                                    .unwrap()
-                                    .into_rgb8();
-
-                                    let mut buffer = Vec::with_capacity(img.len());
-                                    img.write_to(
-                                        &mut std::io::Cursor::new(&mut buffer),
-                                        image::ImageFormat::Jpeg,
-                                    )
-                                    .unwrap();
-
-                                    tag.set_album_cover(Picture::new(&buffer, MimeType::Jpeg));
-                                    tag.write_to_path(&format!("{}/{}", MUSIC_DIR, filename))
-                                        .unwrap();
-                                    std::fs::write(path, buffer).unwrap();
-                                }
-                            }
-                        }
-                    }
-                    Err(e) => {
-                        tracing::error!("{} ({})", e, filename);
-                    }
-                };
-            });
+    let _watcher = match watcher::spawn(state.clone()) {
+        Ok(w) => Some(w),
+        Err(e) => {
+            tracing::warn!(
+                "Cannot watch {}, changes made outside the UI need a restart: {}",
+                MUSIC_DIR,
+                e
+            );
+            None
         }
-    }
+    };
 
     let api = Router::new()
         .route("/files", get(list_file))
@@ -210,20 +193,62 @@ async fn main() {
         .route("/crop", post(crop_api))
         .route("/edit", post(edit_api))
         .route("/delete", post(delete_api))
-        .route("/artist-playlist", get(group_by_artist));
+        .route("/artist-playlist", get(group_by_artist))
+        .route("/albums", get(group_by_album))
+        .route("/genres", get(group_by_genre))
+        .route("/events", get(library_events))
+        .nest("/playlists", playlists::router())
+        .nest(
+            "/sessions",
+            sessions::router().route_layer(middleware::from_fn(sessions::assign_device)),
+        )
+        .nest("/jobs", jobs::router())
+        .nest("/stats", history::router())
+        .nest("/duplicates", duplicates::router())
+        .route(
+            "/identify",
+            post(fingerprint::identify).layer(DefaultBodyLimit::max(IDENTIFY_LIMIT)),
+        )
+        .route("/cache", get(temp_cache::stats))
+        .route("/plays/:id", put(history::update_play))
+        .route(
+            "/settings/audio-format",
+            get(audio_format::get_default).put(audio_format::set_default),
+        );
 
-    let app = Router::new()
+    let serving =
+        middleware::from_fn_with_state(state.temp_cache.clone(), temp_cache::track_serving);
+
+    // Handlers working on the caller's own session. The page sets the cookie,
+    // so the requests it makes next all agree on the device.
+    let per_device = Router::new()
         .route("/", get(index))
-        .route("/download", post(download_file))
-        .route("/temp-download/:id", get(temp_download))
-        .route("/history", post(add_to_history))
         .route("/save-playlist", post(save_playlist))
         .route("/load-playlist", get(load_playlist))
         .route("/clear-playlist", post(clear_playlist))
+        .route("/export-playlist", get(export_session))
+        .route_layer(middleware::from_fn(sessions::assign_device));
+
+    let app = Router::new()
+        .merge(per_device)
+        .route("/download", post(download_file))
+        .route("/temp-download/:id", get(temp_download))
+        .route("/temp-stream/:id", get(temp_stream::temp_stream))
+        .route("/stream/:filename", get(stream::stream))
+        .nest("/hls", hls::router())
+        .nest("/rest", subsonic::router())
+        .route("/radio/:name", get(radio::radio))
+        .route("/keep/:id", post(keep::keep))
+        .route("/history", post(add_to_history))
         .nest("/api", api)
         .with_state(state)
         .nest_service("/m", ServeDir::new(MUSIC_DIR))
-        .nest_service("/td", ServeDir::new(TEMP_DIR))
+        .nest_service(
+            "/td",
+            ServiceBuilder::new()
+                .layer(serving)
+                .service(ServeDir::new(TEMP_DIR)),
+        )
         .nest_service("/img", ServeDir::new(IMG_DIR))
         .fallback_service(ServeDir::new(PUBLIC_DIR))
         .layer(TraceLayer::new_for_http());
@@ -233,7 +258,7 @@ async fn main() {
     axum::serve(listener, app).await.unwrap();
 }
 
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, Default)]
 pub struct Track {
     filename: String,
     title: String,
@@ -242,6 +267,39 @@ pub struct Track {
     thumbnail: Option<String>,
     duration: Option<u64>,
     artist_thumbnail: Option<String>,
+
+    // Only known for local files
+    bitrate: Option<u32>,
+    sample_rate: Option<u32>,
+    channels: Option<u32>,
+    codec: Option<String>,
+    album: Option<String>,
+    album_artist: Option<String>,
+    track_number: Option<u32>,
+    disc_number: Option<u32>,
+    year: Option<i32>,
+    genre: Option<String>,
+    /// YouTube id the file was downloaded from
+    source_id: Option<String>,
+    source_url: Option<String>,
+    play_count: Option<u32>,
+    /// Unix timestamp
+    last_played: Option<i64>,
+}
+
+impl From<Track> for QueueItem {
+    fn from(track: Track) -> Self {
+        Self {
+            url: format!("/m/{}", track.filename),
+            filename: track.filename,
+            title: track.title,
+            artist: track.artist,
+            artists: track.artists,
+            thumbnail: track.thumbnail,
+            duration: track.duration,
+            artist_thumbnail: track.artist_thumbnail,
+        }
+    }
 }
 
 impl PartialEq for Track {
@@ -250,28 +308,48 @@ impl PartialEq for Track {
     }
 }
 
+#[derive(Deserialize)]
+struct HistoryRequest {
+    #[serde(flatten)]
+    track: Track,
+
+    /// Seconds, when the client already knows it
+    listened: Option<u64>,
+}
+
+/// Record a play. The id of the play is sent back in `X-Play-Id`
+/// so the listened time can be reported through `/api/plays/:id`.
 async fn add_to_history(
     State(state): State<AppState>,
-    Json(track): Json<Track>,
+    Json(HistoryRequest { track, listened }): Json<HistoryRequest>,
 ) -> impl IntoResponse {
     tracing::debug!("Adding to history: {}", track.filename);
 
+    let play_id = history::record(&state, &track, listened)
+        .await
+        .inspect_err(|e| tracing::error!("Failed to record play of {}: {}", track.filename, e))
+        .ok();
+
     let mut recently_played = state.recently_played.lock().await;
 
     if recently_played.contains(&track) {
         let pos = recently_played.iter().position(|x| *x == track).unwrap();
         recently_played.remove(pos);
-        recently_played.push_front(track);
+    } else if recently_played.len() >= 10 {
+        recently_played.pop_back();
+    }
+    recently_played.push_front(track);
 
-        return (StatusCode::OK, Json(recently_played.clone())).into_response();
+    if let Err(e) = persist::save(&state.db, persist::RECENTLY_PLAYED, &*recently_played).await {
+        tracing::error!("Failed to save recently played: {}", e);
     }
 
-    if recently_played.len() >= 10 {
-        recently_played.pop_back();
+    let mut res = (StatusCode::OK, Json(recently_played.clone())).into_response();
+    if let Some(id) = play_id {
+        res.headers_mut().insert("x-play-id", HeaderValue::from(id));
     }
-    recently_played.push_front(track);
 
-    (StatusCode::OK, Json(recently_played.clone())).into_response()
+    res
 }
 
 #[derive(Serialize)]
@@ -281,105 +359,21 @@ struct FileApiResponse {
 }
 
 async fn list_file(State(state): State<AppState>) -> Result<Json<FileApiResponse>, String> {
-    let entries = std::fs::read_dir(MUSIC_DIR).map_err(|e| e.to_string())?;
-    let mut files = vec![];
-
-    for entry in entries {
-        let entry = match entry {
-            Ok(e) => e,
-            Err(e) => {
-                return Err(e.to_string());
-            }
-        };
-
-        let filename = entry.file_name().to_string_lossy().to_string();
-        let (title, ext) = {
-            let last_dot = filename.rfind('.');
-
-            match last_dot {
-                Some(d) => (filename[0..d].to_string(), filename[d + 1..].to_string()),
-                None => (filename.clone(), "mp3".to_string()),
-            }
-        };
-
-        let reader = match ext.as_str() {
-            "mp3" => state.mp3_reader.clone(),
-            "mp4" | "m4a" => state.mp4_reader.clone(),
-            _ => {
-                tracing::error!("Unrecognize format: {}", filename);
-                continue;
-            }
-        };
-        let image = format!("/img/{}.jpeg", title);
-
-        let artist = match reader.read_from_path(format!("{}/{}", MUSIC_DIR, filename)) {
-            Ok(mut tag) => {
-                if !std::path::Path::new(&image[1..]).exists() {
-                    let cover = tag.album_cover();
-                    if let Some(c) = cover {
-                        match c.mime_type {
-                            MimeType::Jpeg => {
-                                if let Err(e) = std::fs::write(&image[1..], c.data) {
-                                    tracing::error!("Failed to save image ({}): {}", filename, e);
-                                }
-                            }
-                            _ => {
-                                tracing::info!("Converting image for: {}...", filename);
-
-                                let img = image::load_from_memory_with_format(
-                                    c.data,
-                                    match c.mime_type {
-                                        MimeType::Jpeg => unreachable!("Should not be jpeg"),
-                                        MimeType::Png => image::ImageFormat::Png,
-                                        MimeType::Bmp => image::ImageFormat::Bmp,
-                                        MimeType::Gif => image::ImageFormat::Gif,
-                                        MimeType::Tiff => image::ImageFormat::Tiff,
-                                    },
-                                )
-                                .unwrap()
-                                .into_rgb8();
-
-                                let mut buffer = Vec::with_capacity(img.len());
-                                img.write_to(
-                                    &mut std::io::Cursor::new(&mut buffer),
-                                    image::ImageFormat::Jpeg,
-                                )
-                                .unwrap();
-
-                                tag.set_album_cover(Picture::new(&buffer, MimeType::Jpeg));
-                                tag.write_to_path(&format!("{}/{}", MUSIC_DIR, filename))
-                                    .unwrap();
-                                std::fs::write(&image[1..], buffer).unwrap();
-                            }
-                        }
-                    }
-                }
-
-                tag.artist().map(|a| a.to_string())
-            }
-            Err(e) => {
-                tracing::error!("{}\n{}", e, filename);
-                None
-            }
-        };
-
-        files.push(Track {
-            filename,
-            title,
-            artist: artist.unwrap_or_else(|| "Unknown".to_string()),
-            artists: None,
-            thumbnail: Some(image),
-            duration: None,
-            artist_thumbnail: None,
-        });
-    }
-
     Ok(Json(FileApiResponse {
         recently_played: state.recently_played.lock().await.clone(),
-        files,
+        files: library::tracks(&state).await?,
     }))
 }
 
+async fn library_events(
+    State(state): State<AppState>,
+) -> Sse<impl Stream<Item = Result<sse::Event, Infallible>>> {
+    let stream = BroadcastStream::new(state.library_events.subscribe())
+        .filter_map(|event| event.ok().map(|e| Ok(e.into())));
+
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
+
 async fn search_api(
     State(state): State<AppState>,
     body: String,
@@ -407,6 +401,7 @@ async fn search_api(
                 thumbnail: Some(x.thumbnails.swap_remove(x.thumbnails.len() - 1).url),
                 duration: Some(x.duration / 1000),
                 artist_thumbnail: Some(x.channel.icon.swap_remove(x.channel.icon.len() - 1).url),
+                ..Default::default()
             },
             _ => unreachable!(),
         })
@@ -470,6 +465,7 @@ async fn search_music_api(
                             .replace("w120-h120", "w300-h300"),
                     ),
                     artist_thumbnail: None,
+                    ..Default::default()
                 }
             })
             .collect(),
@@ -493,152 +489,85 @@ async fn index() -> Result<Html<&'static str>, String> {
 
 async fn save_playlist(
     State(state): State<AppState>,
+    Extension(Device(device)): Extension<Device>,
     Json(session): Json<PlaylistSession>,
 ) -> impl IntoResponse {
-    let mut prev_session = state.playlist_session.lock().await;
-    *prev_session = session;
-    prev_session.is_empty = false;
+    let session = PlaylistSession {
+        updated_at: utils::unix_now(),
+        ..session
+    };
+
+    let mut sessions = state.sessions.lock().await;
+    if let Err(e) = sessions::save(&state.db, &device, &session).await {
+        tracing::error!("Failed to save session: {}", e);
+        return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to save session");
+    }
+    sessions.insert(device, session);
 
     (StatusCode::OK, "success")
 }
 
-async fn load_playlist(State(state): State<AppState>) -> impl IntoResponse {
-    let session = state.playlist_session.lock().await;
-    if session.is_empty {
+async fn load_playlist(
+    State(state): State<AppState>,
+    Extension(Device(device)): Extension<Device>,
+) -> impl IntoResponse {
+    let sessions = state.sessions.lock().await;
+    let Some(session) = sessions.get(&device) else {
         return (
             StatusCode::NOT_FOUND,
             [(header::CONTENT_TYPE, "text/plain")],
             "No session stored".to_string(),
         );
-    }
+    };
 
     (
         StatusCode::OK,
         [(header::CONTENT_TYPE, "application/json")],
-        serde_json::to_string(&*session).expect("serialize session to json"),
+        serde_json::to_string(session).expect("serialize session to json"),
     )
 }
 
-async fn clear_playlist(State(state): State<AppState>) -> impl IntoResponse {
-    let mut session = state.playlist_session.lock().await;
-    if session.is_empty {
+async fn clear_playlist(
+    State(state): State<AppState>,
+    Extension(Device(device)): Extension<Device>,
+) -> impl IntoResponse {
+    let mut sessions = state.sessions.lock().await;
+    if sessions.remove(&device).is_none() {
         return (StatusCode::OK, "Ok");
     }
 
-    *session = PlaylistSession::default();
+    if let Err(e) = sessions::remove(&state.db, &device).await {
+        tracing::error!("Failed to clear saved session: {}", e);
+    }
 
     (StatusCode::OK, "Ok")
 }
 
+/// Download the current queue as a playlist file
+async fn export_session(
+    State(state): State<AppState>,
+    Extension(Device(device)): Extension<Device>,
+    Query(query): Query<playlist_files::ExportQuery>,
+    headers: HeaderMap,
+) -> Response {
+    let sessions = state.sessions.lock().await;
+    let Some(session) = sessions.get(&device) else {
+        return (StatusCode::NOT_FOUND, "No session stored").into_response();
+    };
+
+    playlist_files::export(query, &headers, "Queue", &session.queue)
+}
+
 async fn group_by_artist(
     State(state): State<AppState>,
 ) -> Result<Json<HashMap<String, Vec<Track>>>, String> {
-    let entries = std::fs::read_dir(MUSIC_DIR).map_err(|e| e.to_string())?;
     let mut map: HashMap<String, Vec<Track>> = HashMap::new();
 
-    for entry in entries {
-        let entry = match entry {
-            Ok(e) => e,
-            Err(e) => {
-                return Err(e.to_string());
-            }
-        };
-
-        let filename = entry.file_name().to_string_lossy().to_string();
-        let (title, ext) = {
-            let last_dot = filename.rfind('.');
-
-            match last_dot {
-                Some(d) => (filename[0..d].to_string(), filename[d + 1..].to_string()),
-                None => (filename.clone(), "mp3".to_string()),
-            }
-        };
-
-        let reader = match ext.as_str() {
-            "mp3" => state.mp3_reader.clone(),
-            "mp4" | "m4a" => state.mp4_reader.clone(),
-            _ => {
-                tracing::error!("Unrecognize format: {}", filename);
-                continue;
-            }
-        };
-        let image = format!("/img/{}.jpeg", title);
-
-        let artist = match reader.read_from_path(format!("{}/{}", MUSIC_DIR, filename)) {
-            Ok(mut tag) => {
-                if !std::path::Path::new(&image[1..]).exists() {
-                    let cover = tag.album_cover();
-                    if let Some(c) = cover {
-                        match c.mime_type {
-                            MimeType::Jpeg => {
-                                if let Err(e) = std::fs::write(&image[1..], c.data) {
-                                    tracing::error!("Failed to save image ({}): {}", filename, e);
-                                }
-                            }
-                            _ => {
-                                tracing::info!("Converting image for: {}...", filename);
-
-                                let img = image::load_from_memory_with_format(
-                                    c.data,
-                                    match c.mime_type {
-                                        MimeType::Jpeg => unreachable!("Should not be jpeg"),
-                                        MimeType::Png => image::ImageFormat::Png,
-                                        MimeType::Bmp => image::ImageFormat::Bmp,
-                                        MimeType::Gif => image::ImageFormat::Gif,
-                                        MimeType::Tiff => image::ImageFormat::Tiff,
-                                    },
-                                )
-                                .unwrap()
-                                .into_rgb8();
-
-                                let mut buffer = Vec::with_capacity(img.len());
-                                img.write_to(
-                                    &mut std::io::Cursor::new(&mut buffer),
-                                    image::ImageFormat::Jpeg,
-                                )
-                                .unwrap();
-
-                                tag.set_album_cover(Picture::new(&buffer, MimeType::Jpeg));
-                                tag.write_to_path(&format!("{}/{}", MUSIC_DIR, filename))
-                                    .unwrap();
-                                std::fs::write(&image[1..], buffer).unwrap();
-                            }
-                        }
-                    }
-                }
-
-                tag.artist().unwrap_or("Unknown").to_string()
-            }
-            Err(e) => {
-                tracing::error!("{}\n{}", e, filename);
-                continue;
-            }
-        };
-
-        for alone_artist in artist.split(", ") {
+    for track in library::tracks(&state).await? {
+        for alone_artist in track.artist.split(", ") {
             map.entry(alone_artist.to_uppercase())
-                .and_modify(|e| {
-                    e.push(Track {
-                        filename: filename.clone(),
-                        title: title.clone(),
-                        artist: artist.clone(),
-                        artists: None,
-                        thumbnail: Some(image.clone()),
-                        duration: None,
-                        artist_thumbnail: None,
-                    })
-                })
-                .or_insert_with(|| {
-                    vec![Track {
-                        filename: filename.clone(),
-                        title: title.clone(),
-                        artist: artist.clone(),
-                        artists: None,
-                        thumbnail: Some(image.clone()),
-                        duration: None,
-                        artist_thumbnail: None,
-                    }]
-                });
+                .or_default()
+                .push(track.clone());
         }
     }
 
@@ -647,167 +576,148 @@ async fn group_by_artist(
     Ok(Json(map))
 }
 
-#[derive(Serialize, Deserialize)]
-struct Artist {
-    artist: Option<String>,
-    channel: Option<String>,
-    uploader: Option<String>,
+#[derive(Serialize)]
+struct Album {
+    album: String,
+    artist: String,
+    year: Option<i32>,
+    thumbnail: Option<String>,
+    tracks: Vec<Track>,
 }
 
-impl Artist {
-    fn get(self) -> String {
-        self.artist
-            .or(self.uploader)
-            .or(self.channel)
-            .unwrap_or_else(|| "Unknown".to_string())
-    }
-}
+/// Albums are told apart by album artist when tagged, by name only otherwise
+async fn group_by_album(State(state): State<AppState>) -> Result<Json<Vec<Album>>, String> {
+    let mut map: HashMap<(String, String), Album> = HashMap::new();
 
-#[derive(Deserialize)]
-struct DownloadResponse {
-    title: String,
-    description: Option<String>,
-
-    #[serde(flatten)]
-    artist: Artist,
-
-    thumbnail: String,
-    duration: f32,
-}
+    for track in library::tracks(&state).await? {
+        let Some(album) = track.album.clone() else {
+            continue;
+        };
 
-const MAX_RETRIES: u8 = 3;
+        let key = (
+            album.to_uppercase(),
+            track
+                .album_artist
+                .as_deref()
+                .unwrap_or_default()
+                .to_uppercase(),
+        );
 
-async fn download_file(State(state): State<AppState>, body: String) -> impl IntoResponse {
-    tracing::info!("Downloading: {}", body);
+        let entry = map.entry(key).or_insert_with(|| Album {
+            album,
+            artist: track
+                .album_artist
+                .clone()
+                .unwrap_or_else(|| track.artist.clone()),
+            year: None,
+            thumbnail: None,
+            tracks: vec![],
+        });
 
-    let mut i = 0;
-    let stdout = loop {
-        i += 1;
+        entry.year = entry.year.or(track.year);
+        if entry.thumbnail.is_none() {
+            entry.thumbnail.clone_from(&track.thumbnail);
+        }
+        entry.tracks.push(track);
+    }
 
-        let proc = Command::new("yt-dlp")
-            .args([
-                "-f",
-                "bestaudio/best",
-                "--no-playlist",
-                "--no-warning",
-                "--embed-thumbnail",
-                "--embed-metadata",
-                "--print-json",
-                "-x",
-                "--audio-format",
-                "mp3",
-                "-o",
-                &format!("{MUSIC_DIR}/%(title)s.%(ext)s"),
-                "--",
-                &body,
-            ])
-            .stdin(Stdio::null())
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped())
-            .output()
-            .await;
+    let mut albums = map.into_values().collect::<Vec<Album>>();
+    for album in &mut albums {
+        album.tracks.sort_by_key(|t| {
+            (
+                t.disc_number.unwrap_or(1),
+                t.track_number.unwrap_or(u32::MAX),
+            )
+        });
+    }
+    albums.sort_by_cached_key(|a| (a.album.to_lowercase(), a.artist.to_lowercase()));
 
-        let proc = match proc {
-            Ok(proc) => proc,
-            Err(e) => {
-                let message = format!("Failed to spawn and capture output: {}\n{}", e, e);
-                tracing::error!("{}", message);
-                if i == MAX_RETRIES {
-                    return (StatusCode::INTERNAL_SERVER_ERROR, message).into_response();
-                } else {
-                    continue;
-                }
-            }
-        };
+    Ok(Json(albums))
+}
 
-        if !proc.stderr.is_empty() {
-            let message = unsafe { String::from_utf8_unchecked(proc.stderr) };
-            tracing::error!("Yt-DLP stderr: {}", message);
-            if i == MAX_RETRIES {
-                return (StatusCode::BAD_REQUEST, message).into_response();
-            } else {
-                continue;
-            }
-        }
+#[derive(Serialize)]
+struct Genre {
+    genre: String,
+    thumbnail: Option<String>,
+    tracks: Vec<Track>,
+}
 
-        break proc.stdout;
-    };
+async fn group_by_genre(State(state): State<AppState>) -> Result<Json<Vec<Genre>>, String> {
+    let mut map: HashMap<String, Genre> = HashMap::new();
 
-    #[cfg(debug_assertions)]
-    tracing::debug!("Parsing JSON from yt-dlp...");
+    for track in library::tracks(&state).await? {
+        let Some(genres) = track.genre.clone() else {
+            continue;
+        };
 
-    let parsed: DownloadResponse = match serde_json::from_slice(&stdout) {
-        Ok(j) => j,
-        Err(e) => {
-            let message = format!("Failed to parse JSON: {}\n{}", e, unsafe {
-                String::from_utf8_unchecked(stdout)
+        // Multiple genres are commonly separated by semicolons
+        for genre in genres
+            .split(';')
+            .map(|g| g.trim())
+            .filter(|g| !g.is_empty())
+        {
+            let entry = map.entry(genre.to_uppercase()).or_insert_with(|| Genre {
+                genre: genre.to_string(),
+                thumbnail: None,
+                tracks: vec![],
             });
-            tracing::error!("{}", message);
-            return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to parse JSON").into_response();
+
+            if entry.thumbnail.is_none() {
+                entry.thumbnail.clone_from(&track.thumbnail);
+            }
+            entry.tracks.push(track.clone());
         }
-    };
+    }
 
-    let mut image_path = parsed.thumbnail;
+    let mut genres = map.into_values().collect::<Vec<Genre>>();
+    for genre in &mut genres {
+        genre.tracks.sort_by_cached_key(|t| {
+            (
+                t.album_artist.as_ref().unwrap_or(&t.artist).to_lowercase(),
+                t.album.as_deref().unwrap_or_default().to_lowercase(),
+                t.disc_number.unwrap_or(1),
+                t.track_number.unwrap_or(u32::MAX),
+            )
+        });
+    }
+    genres.sort_by_cached_key(|g| g.genre.to_lowercase());
 
-    if let Some(d) = parsed.description {
-        if d.starts_with("Provided to YouTube by") {
-            tracing::info!("Cropping image for {}...", parsed.title);
-            let music_path = format!("{MUSIC_DIR}/{}.mp3", parsed.title);
-            image_path = format!("{IMG_DIR}/{}.jpeg", parsed.title);
+    Ok(Json(genres))
+}
 
-            let mut tag = match state.mp3_reader.read_from_path(&music_path) {
-                Ok(t) => t,
-                Err(e) => {
-                    let message = format!("Open music file error: {e}");
-                    tracing::error!("{message} | path: {music_path}");
-                    return (StatusCode::INTERNAL_SERVER_ERROR, message).into_response();
-                }
-            };
-
-            let cover = tag.album_cover().unwrap();
-            let mut img = image::load_from_memory(cover.data).unwrap().into_rgb8();
-
-            let (width, height) = img.dimensions();
-            if width != height {
-                let offset = utils::find_offset_to_center(width, height);
-                let cropped = image::imageops::crop(&mut img, offset, 0, height, height).to_image();
-
-                let mut buffer = Vec::with_capacity(cropped.len());
-                if let Err(e) = cropped.write_to(
-                    &mut std::io::Cursor::new(&mut buffer),
-                    image::ImageFormat::Jpeg,
-                ) {
-                    let message = format!("Crop image error: {e}");
-                    tracing::error!("{}", message);
-                    return (StatusCode::INTERNAL_SERVER_ERROR, message).into_response();
-                }
+const MAX_RETRIES: u8 = 3;
 
-                _ = std::fs::write(&image_path, &buffer);
+/// Kept for clients that want to wait for the whole download, see `/api/jobs` otherwise
+async fn download_file(
+    State(state): State<AppState>,
+    Query(query): Query<audio_format::FormatQuery>,
+    body: String,
+) -> impl IntoResponse {
+    let format = match audio_format::requested(&state, &query).await {
+        Ok(f) => f,
+        Err(e) => return e.into_response(),
+    };
+    let id = jobs::enqueue(&state, body, format).await;
 
-                tag.set_album_cover(Picture::new(&buffer, MimeType::Jpeg));
-                tag.write_to_path(&music_path).unwrap();
-            }
-        }
+    match jobs::wait(&state, id).await {
+        Ok(track) => (StatusCode::OK, Json(track)).into_response(),
+        Err(e) => (StatusCode::BAD_REQUEST, e).into_response(),
     }
-
-    (
-        StatusCode::OK,
-        Json(json!({
-            "title": parsed.title,
-            "artist": parsed.artist.get(),
-            "thumbnail": image_path,
-            "duration": parsed.duration
-        })),
-    )
-        .into_response()
 }
 
-async fn temp_download(Path(id): Path<String>) -> impl IntoResponse {
+async fn temp_download(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Query(query): Query<audio_format::FormatQuery>,
+) -> impl IntoResponse {
     tracing::info!("Downloading to temp: {}", id);
-    let fp = format!("temp/{id}.mp3");
-    let path = std::path::Path::new(&fp);
-    if path.exists() {
-        return Ok((StatusCode::OK, format!("/td/{id}.mp3")));
+    let format = audio_format::requested(&state, &query).await?;
+    let ext = format.extension();
+
+    let name = format!("{id}.{ext}");
+    let fp = format!("{TEMP_DIR}/{name}");
+    if state.temp_cache.lookup(&name) {
+        return Ok((StatusCode::OK, format!("/td/{name}")));
     }
 
     let mut i = 0;
@@ -815,19 +725,8 @@ async fn temp_download(Path(id): Path<String>) -> impl IntoResponse {
         i += 1;
 
         let proc = tokio::process::Command::new("yt-dlp")
-            .args([
-                "-f",
-                "bestaudio/best",
-                "--no-playlist",
-                "--no-warning",
-                "-x",
-                "--audio-format",
-                "mp3",
-                "-o",
-                &fp,
-                "--",
-                &id,
-            ])
+            .args(format.yt_dlp_args())
+            .args(["--no-playlist", "--no-warning", "-o", &fp, "--", &id])
             .stdout(Stdio::null())
             .stderr(Stdio::piped())
             .stdin(Stdio::null())
@@ -857,7 +756,8 @@ async fn temp_download(Path(id): Path<String>) -> impl IntoResponse {
             }
         }
 
-        return Ok((StatusCode::OK, format!("/td/{id}.mp3")));
+        state.temp_cache.touch(&name);
+        return Ok((StatusCode::OK, format!("/td/{name}")));
     }
 }
 
@@ -875,7 +775,7 @@ async fn edit_api(State(state): State<AppState>, mut multipart: Multipart) -> im
                 filename = field.text().await.unwrap();
                 path = format!("{MUSIC_DIR}/{filename}");
 
-                match state.mp3_reader.read_from_path(&path) {
+                match tags::read_or_create(&state, &path) {
                     Ok(t) => tag = Some(t),
                     Err(e) => {
                         return (StatusCode::BAD_REQUEST, format!("Failed to read tag: {e}"))
@@ -943,13 +843,38 @@ async fn edit_api(State(state): State<AppState>, mut multipart: Multipart) -> im
 
     tag.as_mut().unwrap().write_to_path(&path).unwrap();
 
+    let renamed_from = (!matched_title).then(|| filename.clone());
     if !matched_title {
-        let new_filename = format!(
-            "{MUSIC_DIR}/{title}{}",
-            &filename[filename.rfind('.').unwrap()..]
-        );
-        tracing::debug!("Renaming {path} to {new_filename}");
-        std::fs::rename(path, new_filename).unwrap();
+        let new_filename = format!("{title}{}", &filename[filename.rfind('.').unwrap()..]);
+        let new_path = format!("{MUSIC_DIR}/{new_filename}");
+        tracing::debug!("Renaming {path} to {new_path}");
+        std::fs::rename(path, new_path).unwrap();
+
+        if let Err(e) = library::remove(&state, &filename).await {
+            tracing::error!("Failed to remove {} from library: {}", filename, e);
+        }
+        if let Err(e) = history::rename(&state, &filename, &new_filename).await {
+            tracing::error!("Failed to move play history of {}: {}", filename, e);
+        }
+        filename = new_filename;
+    }
+
+    if let Err(e) = library::index_file(&state, &filename).await {
+        tracing::error!("Failed to index {}: {}", filename, e);
+    }
+
+    // Saved playlists follow the file to its new name
+    if let Some(old_filename) = renamed_from {
+        match library::track(&state, &filename).await {
+            Ok(Some(track)) => {
+                let item = QueueItem::from(track);
+                if let Err((_, e)) = playlists::replace_file(&state, &old_filename, &item).await {
+                    tracing::error!("Failed to update playlists of {}: {}", old_filename, e);
+                }
+            }
+            Ok(None) => tracing::warn!("{} is not in the library", filename),
+            Err(e) => tracing::error!("{} ({})", e, filename),
+        }
     }
 
     (StatusCode::OK, "OK").into_response()
@@ -958,8 +883,8 @@ async fn edit_api(State(state): State<AppState>, mut multipart: Multipart) -> im
 async fn delete_api(State(state): State<AppState>, body: String) -> impl IntoResponse {
     let path = format!("{MUSIC_DIR}/{body}");
 
-    let tag = state.mp3_reader.read_from_path(&path).unwrap();
-    let cover = tag.album_cover();
+    let tag = tags::read(&state, &path);
+    let cover = tag.as_ref().ok().and_then(|t| t.album_cover());
 
     if let Some(c) = cover {
         if let Err(e) = std::fs::remove_file(format!(
@@ -975,6 +900,13 @@ async fn delete_api(State(state): State<AppState>, body: String) -> impl IntoRes
         return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response();
     }
 
+    if let Err(e) = library::remove(&state, &body).await {
+        return (StatusCode::INTERNAL_SERVER_ERROR, e).into_response();
+    }
+    if let Err(e) = playlists::remove_file(&state, &body).await {
+        return e.into_response();
+    }
+
     (StatusCode::OK, "OK").into_response()
 }
 
@@ -989,46 +921,61 @@ async fn crop_api(
     Json(body): Json<CropRequest>,
 ) -> impl IntoResponse {
     let music_path = format!("{MUSIC_DIR}/{}", body.filename);
-    let image_path = if let Some(i) = body.image.rfind('?') {
-        body.image[..i].strip_prefix('/').unwrap()
-    } else {
-        body.image.strip_prefix('/').unwrap()
+    let image = body.image.split('?').next().unwrap_or_default();
+    let Some(image_path) = image.strip_prefix('/') else {
+        return (
+            StatusCode::BAD_REQUEST,
+            "Image must be a path on this server",
+        )
+            .into_response();
     };
-    let mut img = match image::open(image_path) {
-        Ok(img) => img.into_rgb8(),
+    let data = match std::fs::read(image_path) {
+        Ok(data) => data,
         Err(e) => {
             let message = format!("Open image error: {e}");
             tracing::error!("{message} | path: {image_path}");
-            return (StatusCode::INTERNAL_SERVER_ERROR, message).into_response();
+            return (StatusCode::BAD_REQUEST, message).into_response();
         }
     };
 
-    let (width, height) = img.dimensions();
-    if width == height {
-        return (StatusCode::BAD_REQUEST, "Already square").into_response();
+    match image::load_from_memory(&data).map(|img| (img.width(), img.height())) {
+        Ok((width, height)) if width == height => {
+            return (StatusCode::BAD_REQUEST, "Already square").into_response();
+        }
+        Ok(_) => {}
+        Err(e) => {
+            let message = format!("Load image error: {e}");
+            tracing::error!("{message} | path: {image_path}");
+            return (StatusCode::BAD_REQUEST, message).into_response();
+        }
     }
 
-    let offset = utils::find_offset_to_center(width, height);
-    let cropped = image::imageops::crop(&mut img, offset, 0, height, height).to_image();
-
-    let mut buffer = Vec::with_capacity(img.len());
-    if let Err(e) = cropped.write_to(
-        &mut std::io::Cursor::new(&mut buffer),
-        image::ImageFormat::Jpeg,
-    ) {
-        let message = format!("Crop image error: {e}");
-        tracing::error!("{}", message);
-        return (StatusCode::INTERNAL_SERVER_ERROR, message).into_response();
-    }
+    let buffer = match jobs::cover_jpeg(&data, true) {
+        Ok(buffer) => buffer,
+        Err(e) => {
+            tracing::error!("{}", e);
+            return (StatusCode::INTERNAL_SERVER_ERROR, e).into_response();
+        }
+    };
 
     _ = std::fs::write(
         format!("{}.jpeg", utils::without_extension(image_path)),
         &buffer,
     );
 
-    let mut tag = state.mp3_reader.read_from_path(&music_path).unwrap();
+    let mut tag = match tags::read(&state, &music_path) {
+        Ok(tag) => tag,
+        Err(e) => return (StatusCode::BAD_REQUEST, e).into_response(),
+    };
     tag.set_album_cover(Picture::new(&buffer, MimeType::Jpeg));
-    tag.write_to_path(&music_path).unwrap();
+    if let Err(e) = tag.write_to_path(&music_path) {
+        tracing::error!("Failed to write tags of {}: {}", body.filename, e);
+        return (StatusCode::INTERNAL_SERVER_ERROR, e).into_response();
+    }
+
+    if let Err(e) = library::index_file(&state, &body.filename).await {
+        tracing::error!("Failed to index {}: {}", body.filename, e);
+    }
 
     (StatusCode::OK, "OK").into_response()
 }
@@ -0,0 +1,989 @@
+//! A subset of the MPD protocol, so ncmpcpp and MPD phone apps can drive playback through
+//! speakers plugged into the machine running this server. Only started when `MPD_ADDR` is set,
+//! e.g. `MPD_ADDR=0.0.0.0:6600`.
+//!
+//! The queue is the session of device [`DEVICE`], so it can be transferred to and from browsers
+//! through `/api/sessions`. Song ids are queue positions. The library has no directories,
+//! every song is at the root.
+
+use std::{
+    collections::BTreeSet,
+    fmt::Write,
+    net::SocketAddr,
+    ops::Range,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
+
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines},
+    net::{tcp::OwnedReadHalf, TcpListener, TcpStream},
+    sync::{broadcast, mpsc, Mutex},
+};
+
+use crate::{
+    history, library,
+    player::{self, Player},
+    sessions, utils, AppState, PlaylistSession, QueueItem, Track, MUSIC_DIR,
+};
+
+/// Device id of the session played by the server
+pub const DEVICE: &str = "mpd";
+const PROTOCOL_VERSION: &str = "0.23.0";
+
+// ACK codes of the protocol
+const ACK_ERROR_ARG: u32 = 2;
+const ACK_ERROR_UNKNOWN: u32 = 5;
+const ACK_ERROR_NO_EXIST: u32 = 50;
+const ACK_ERROR_SYSTEM: u32 = 52;
+
+const COMMANDS: &[&str] = &[
+    "add",
+    "addid",
+    "clear",
+    "close",
+    "commands",
+    "currentsong",
+    "find",
+    "idle",
+    "list",
+    "listall",
+    "listallinfo",
+    "lsinfo",
+    "next",
+    "noidle",
+    "notcommands",
+    "outputs",
+    "pause",
+    "ping",
+    "play",
+    "playid",
+    "playlistid",
+    "playlistinfo",
+    "plchanges",
+    "previous",
+    "search",
+    "seekcur",
+    "stats",
+    "status",
+    "stop",
+    "tagtypes",
+];
+
+const TAG_TYPES: &[&str] = &[
+    "Artist",
+    "AlbumArtist",
+    "Album",
+    "Title",
+    "Track",
+    "Disc",
+    "Date",
+    "Genre",
+];
+
+struct Ack {
+    code: u32,
+    message: String,
+}
+
+fn ack(code: u32, message: impl Into<String>) -> Ack {
+    Ack {
+        code,
+        message: message.into(),
+    }
+}
+
+/// Lines of the response, without the final `OK`
+type Reply = Result<String, Ack>;
+
+struct Mpd {
+    state: AppState,
+    player: Mutex<Player>,
+    /// Bumped on every change of the queue
+    playlist_version: AtomicU32,
+    /// Names of changed subsystems, for `idle`
+    changes: broadcast::Sender<&'static str>,
+    started: Instant,
+}
+
+pub async fn serve(state: AppState, addr: String) {
+    let listener = match TcpListener::bind(&addr).await {
+        Ok(l) => l,
+        Err(e) => {
+            tracing::error!("Cannot listen for MPD clients on {}: {}", addr, e);
+            return;
+        }
+    };
+    tracing::info!("MPD listening on {}", addr);
+
+    let (finished_tx, finished_rx) = mpsc::unbounded_channel();
+    let mpd = Arc::new(Mpd {
+        state,
+        player: Mutex::new(Player::new(finished_tx)),
+        playlist_version: AtomicU32::new(1),
+        changes: broadcast::channel(64).0,
+        started: Instant::now(),
+    });
+    tokio::spawn(mpd.clone().advance(finished_rx));
+
+    loop {
+        match listener.accept().await {
+            Ok((socket, peer)) => {
+                tokio::spawn(mpd.clone().client(socket, peer));
+            }
+            Err(e) => tracing::warn!("Cannot accept MPD client: {}", e),
+        }
+    }
+}
+
+fn is_local(item: &QueueItem) -> bool {
+    item.url.starts_with("/m/")
+}
+
+/// Split a command line into words, some quoted with `"` and escaped with `\`
+fn parse(line: &str) -> Result<Vec<String>, Ack> {
+    let mut words = vec![];
+    let mut chars = line.chars().peekable();
+
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        let Some(c) = chars.next() else {
+            return Ok(words);
+        };
+
+        let mut word = String::new();
+        if c == '"' {
+            loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some('\\') => word.extend(chars.next()),
+                    Some(c) => word.push(c),
+                    None => return Err(ack(ACK_ERROR_ARG, "Missing closing '\"'")),
+                }
+            }
+        } else {
+            word.push(c);
+            while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                word.push(c);
+            }
+        }
+        words.push(word);
+    }
+}
+
+fn number<T: std::str::FromStr>(args: &[String], i: usize) -> Result<Option<T>, Ack> {
+    args.get(i)
+        .map(|a| {
+            a.parse()
+                .map_err(|_| ack(ACK_ERROR_ARG, format!("Invalid number: {a}")))
+        })
+        .transpose()
+}
+
+/// Positions `START:END`, `START:` or `POS` of a queue of `len` songs
+fn range(arg: &str, len: usize) -> Result<Range<usize>, Ack> {
+    let invalid = || ack(ACK_ERROR_ARG, format!("Invalid range: {arg}"));
+
+    let (start, end) = match arg.split_once(':') {
+        Some((start, "")) => (start.parse().map_err(|_| invalid())?, len),
+        Some((start, end)) => (
+            start.parse().map_err(|_| invalid())?,
+            end.parse().map_err(|_| invalid())?,
+        ),
+        None => {
+            let pos: usize = arg.parse().map_err(|_| invalid())?;
+            (pos, pos.checked_add(1).ok_or_else(invalid)?)
+        }
+    };
+    if end < start {
+        return Err(invalid());
+    }
+    if start >= len {
+        return Err(ack(ACK_ERROR_ARG, "Bad song index"));
+    }
+
+    Ok(start..end.min(len))
+}
+
+/// `key: value`, values can't span lines
+fn line(out: &mut String, key: &str, value: impl std::fmt::Display) {
+    let value = value.to_string().replace('\n', " ");
+    _ = writeln!(out, "{key}: {value}");
+}
+
+/// The value of tag `name` as MPD calls it, or of `any` of them
+fn tag_value(track: &Track, name: &str) -> Option<String> {
+    match name.to_lowercase().as_str() {
+        "artist" => Some(track.artist.clone()),
+        "albumartist" => Some(track.album_artist.clone().unwrap_or(track.artist.clone())),
+        "album" => track.album.clone(),
+        "title" => Some(track.title.clone()),
+        "track" => track.track_number.map(|n| n.to_string()),
+        "disc" => track.disc_number.map(|n| n.to_string()),
+        "date" => track.year.map(|y| y.to_string()),
+        "genre" => track.genre.clone(),
+        "file" => Some(track.filename.clone()),
+        "any" => Some(
+            ["artist", "albumartist", "album", "title", "genre", "file"]
+                .iter()
+                .filter_map(|t| tag_value(track, t))
+                .collect::<Vec<String>>()
+                .join("\n"),
+        ),
+        _ => None,
+    }
+}
+
+/// Whether `track` has every `(tag, value)` pair of `filters`, or contains the values with `exact` off
+fn matches(track: &Track, filters: &[String], exact: bool) -> bool {
+    filters.chunks(2).all(|pair| {
+        let value = tag_value(track, &pair[0]);
+        match (value, exact) {
+            (Some(v), true) => v == pair[1],
+            (Some(v), false) => v.to_lowercase().contains(&pair[1].to_lowercase()),
+            (None, _) => false,
+        }
+    })
+}
+
+fn check_filters(filters: &[String]) -> Result<(), Ack> {
+    if filters.first().is_some_and(|f| f.starts_with('(')) {
+        return Err(ack(ACK_ERROR_ARG, "Filter expressions are not supported"));
+    }
+    if filters.is_empty() || !filters.len().is_multiple_of(2) {
+        return Err(ack(ACK_ERROR_ARG, "Incorrect arguments"));
+    }
+    if let Some(pair) = filters.chunks(2).find(|p| {
+        !TAG_TYPES
+            .iter()
+            .chain(&["file", "any"])
+            .any(|t| t.eq_ignore_ascii_case(&p[0]))
+    }) {
+        return Err(ack(ACK_ERROR_ARG, format!("Unknown tag type: {}", pair[0])));
+    }
+
+    Ok(())
+}
+
+fn write_song(out: &mut String, track: &Track) {
+    line(out, "file", &track.filename);
+    line(out, "Title", &track.title);
+    line(out, "Artist", &track.artist);
+    if let Some(album_artist) = &track.album_artist {
+        line(out, "AlbumArtist", album_artist);
+    }
+    if let Some(album) = &track.album {
+        line(out, "Album", album);
+    }
+    if let Some(n) = track.track_number {
+        line(out, "Track", n);
+    }
+    if let Some(n) = track.disc_number {
+        line(out, "Disc", n);
+    }
+    if let Some(year) = track.year {
+        line(out, "Date", year);
+    }
+    if let Some(genre) = &track.genre {
+        line(out, "Genre", genre);
+    }
+    if let Some(duration) = track.duration {
+        line(out, "Time", duration);
+        line(out, "duration", duration);
+    }
+}
+
+fn empty_session() -> PlaylistSession {
+    PlaylistSession {
+        current_time: 0.0,
+        current_index: 0,
+        queue: vec![],
+        updated_at: 0,
+    }
+}
+
+impl Mpd {
+    async fn session(&self) -> PlaylistSession {
+        let sessions = self.state.sessions.lock().await;
+        sessions.get(DEVICE).cloned().unwrap_or_else(empty_session)
+    }
+
+    /// Locked from read to insert, so a concurrent update isn't lost
+    async fn update_session(&self, update: impl FnOnce(&mut PlaylistSession)) {
+        let mut sessions = self.state.sessions.lock().await;
+        let mut session = sessions.get(DEVICE).cloned().unwrap_or_else(empty_session);
+        update(&mut session);
+        session.updated_at = utils::unix_now();
+
+        if let Err(e) = sessions::save(&self.state.db, DEVICE, &session).await {
+            tracing::error!("Failed to save MPD session: {}", e);
+        }
+        sessions.insert(DEVICE.to_string(), session);
+    }
+
+    fn changed(&self, subsystem: &'static str) {
+        if subsystem == "playlist" {
+            self.playlist_version.fetch_add(1, Ordering::Relaxed);
+        }
+        _ = self.changes.send(subsystem);
+    }
+
+    /// Library metadata of a queued song, what the queue knows for the others
+    async fn queued_track(&self, item: &QueueItem) -> Track {
+        let track = if is_local(item) {
+            library::track(&self.state, &item.filename)
+                .await
+                .ok()
+                .flatten()
+        } else {
+            None
+        };
+
+        track.unwrap_or_else(|| Track {
+            filename: item.url.clone(),
+            title: item.title.clone(),
+            artist: item.artist.clone(),
+            duration: item.duration,
+            ..Default::default()
+        })
+    }
+
+    /// Play song `index` of the queue from `start` seconds in
+    async fn start(&self, player: &mut Player, index: usize, start: f64) -> Reply {
+        let session = self.session().await;
+        let item = session
+            .queue
+            .get(index)
+            .ok_or_else(|| ack(ACK_ERROR_ARG, "Bad song index"))?;
+        if !is_local(item) {
+            return Err(ack(
+                ACK_ERROR_NO_EXIST,
+                "Only library songs can be played on the server",
+            ));
+        }
+
+        let path = format!("{MUSIC_DIR}/{}", item.filename);
+        player
+            .play(&path, start)
+            .map_err(|e| ack(ACK_ERROR_SYSTEM, e))?;
+        tracing::info!("MPD playing {}", item.filename);
+
+        if start == 0.0 {
+            let track = self.queued_track(item).await;
+            if let Err(e) = history::record(&self.state, &track, None).await {
+                tracing::error!("Failed to record play of {}: {}", item.filename, e);
+            }
+        }
+
+        self.update_session(|s| {
+            s.current_index = index as u32;
+            s.current_time = start as f32;
+        })
+        .await;
+        self.changed("player");
+
+        Ok(String::new())
+    }
+
+    /// Remember where playback is so it resumes there, even from a browser
+    async fn stop(&self, player: &mut Player, pause: bool) {
+        if pause {
+            player.pause();
+        } else {
+            player.stop();
+        }
+        let elapsed = player.elapsed() as f32;
+
+        self.update_session(|s| s.current_time = elapsed).await;
+        self.changed("player");
+    }
+
+    /// The first library song after `index`, others are skipped
+    async fn next_local(&self, index: usize) -> Option<usize> {
+        self.session()
+            .await
+            .queue
+            .iter()
+            .enumerate()
+            .skip(index + 1)
+            .find(|(_, item)| is_local(item))
+            .map(|(i, _)| i)
+    }
+
+    /// Move on to the next song whenever ffplay finishes one
+    async fn advance(self: Arc<Self>, mut finished: mpsc::UnboundedReceiver<player::Finished>) {
+        while let Some((generation, success)) = finished.recv().await {
+            let mut player = self.player.lock().await;
+            if player.generation() != generation || player.state() != player::State::Play {
+                continue;
+            }
+
+            let index = self.session().await.current_index as usize;
+            let next = if success {
+                self.next_local(index).await
+            } else {
+                None
+            };
+            let started = match next {
+                Some(next) => self.start(&mut player, next, 0.0).await,
+                None => Err(ack(ACK_ERROR_NO_EXIST, "End of queue")),
+            };
+            if started.is_err() {
+                self.stop(&mut player, false).await;
+            }
+        }
+    }
+
+    async fn client(self: Arc<Self>, socket: TcpStream, peer: SocketAddr) {
+        tracing::debug!("MPD client connected: {}", peer);
+        let (read, mut write) = socket.into_split();
+        let mut lines = BufReader::new(read).lines();
+        // Changes are kept from the start, like MPD does for idle
+        let mut changes = self.changes.subscribe();
+
+        let greeting = format!("OK MPD {PROTOCOL_VERSION}\n");
+        if write.write_all(greeting.as_bytes()).await.is_err() {
+            return;
+        }
+
+        // `Some(list_OK)` while a command list is being sent
+        let mut list: Option<(bool, Vec<String>)> = None;
+        while let Ok(Some(input)) = lines.next_line().await {
+            let input = input.trim().to_string();
+
+            let response = match (&mut list, input.as_str()) {
+                (None, "command_list_begin") => {
+                    list = Some((false, vec![]));
+                    continue;
+                }
+                (None, "command_list_ok_begin") => {
+                    list = Some((true, vec![]));
+                    continue;
+                }
+                (Some(_), "command_list_end") => {
+                    let (list_ok, commands) = list.take().expect("in a command list");
+                    self.run_list(&commands, list_ok).await
+                }
+                (Some((_, commands)), _) => {
+                    commands.push(input);
+                    continue;
+                }
+                (None, "close") => break,
+                // Only meaningful while idle
+                (None, "noidle") => continue,
+                (None, _) if input == "idle" || input.starts_with("idle ") => {
+                    let Some(response) = self.idle(&input, &mut lines, &mut changes).await else {
+                        break;
+                    };
+                    response
+                }
+                (None, _) => self.run_list(&[input], false).await,
+            };
+
+            if write.write_all(response.as_bytes()).await.is_err() {
+                break;
+            }
+        }
+
+        tracing::debug!("MPD client disconnected: {}", peer);
+    }
+
+    /// Responses of `commands` up to the first error, with the final `OK` or `ACK`
+    async fn run_list(&self, commands: &[String], list_ok: bool) -> String {
+        let mut response = String::new();
+
+        for (i, input) in commands.iter().enumerate() {
+            let args = match parse(input) {
+                Ok(args) if args.is_empty() => vec![String::new()],
+                Ok(args) => args,
+                Err(e) => {
+                    _ = writeln!(response, "ACK [{}@{i}] {{}} {}", e.code, e.message);
+                    return response;
+                }
+            };
+
+            match self.execute(&args[0], &args[1..]).await {
+                Ok(out) => {
+                    response.push_str(&out);
+                    if list_ok {
+                        response.push_str("list_OK\n");
+                    }
+                }
+                Err(e) => {
+                    _ = writeln!(
+                        response,
+                        "ACK [{}@{i}] {{{}}} {}",
+                        e.code, args[0], e.message
+                    );
+                    return response;
+                }
+            }
+        }
+
+        response.push_str("OK\n");
+        response
+    }
+
+    /// Wait for a change of one of the subsystems in `input`, or of any.
+    /// `None` when the client left.
+    async fn idle(
+        &self,
+        input: &str,
+        lines: &mut Lines<BufReader<OwnedReadHalf>>,
+        changes: &mut broadcast::Receiver<&'static str>,
+    ) -> Option<String> {
+        let wanted = match parse(input) {
+            Ok(args) => args[1..].to_vec(),
+            Err(e) => return Some(format!("ACK [{}@0] {{idle}} {}\n", e.code, e.message)),
+        };
+        let wanted = |subsystem: &str| wanted.is_empty() || wanted.iter().any(|w| w == subsystem);
+        let mut library_events = self.state.library_events.subscribe();
+
+        let mut changed = BTreeSet::new();
+        // What changed while the client was busy
+        loop {
+            match changes.try_recv() {
+                Ok(subsystem) if wanted(subsystem) => _ = changed.insert(subsystem),
+                Ok(_) => {}
+                Err(broadcast::error::TryRecvError::Lagged(_)) => {
+                    changed.extend(["player", "playlist"].into_iter().filter(|s| wanted(s)))
+                }
+                Err(_) => break,
+            }
+        }
+
+        let everything = ["player", "playlist"];
+        while changed.is_empty() {
+            tokio::select! {
+                input = lines.next_line() => {
+                    match input {
+                        Ok(Some(input)) if input.trim() == "noidle" => break,
+                        Ok(Some(input)) => {
+                            let message = "Only \"noidle\" is allowed during idle";
+                            return Some(format!("ACK [{ACK_ERROR_ARG}@0] {{{}}} {message}\n", input.trim()));
+                        }
+                        _ => return None,
+                    }
+                }
+                subsystem = changes.recv() => {
+                    match subsystem {
+                        Ok(subsystem) if wanted(subsystem) => {
+                            changed.insert(subsystem);
+                        }
+                        Ok(_) => {}
+                        Err(_) => changed.extend(everything.into_iter().filter(|s| wanted(s))),
+                    }
+                }
+                Ok(_) = library_events.recv(), if wanted("database") => {
+                    changed.insert("database");
+                }
+            }
+        }
+
+        let mut response = String::new();
+        for subsystem in changed {
+            line(&mut response, "changed", subsystem);
+        }
+        response.push_str("OK\n");
+        Some(response)
+    }
+
+    async fn execute(&self, command: &str, args: &[String]) -> Reply {
+        match command {
+            "ping" => Ok(String::new()),
+            "commands" => Ok(COMMANDS.iter().fold(String::new(), |mut out, c| {
+                line(&mut out, "command", c);
+                out
+            })),
+            "notcommands" => Ok(String::new()),
+            // Enabling and disabling tag types is accepted, every song has them all anyway
+            "tagtypes" if !args.is_empty() => Ok(String::new()),
+            "tagtypes" => Ok(TAG_TYPES.iter().fold(String::new(), |mut out, t| {
+                line(&mut out, "tagtype", t);
+                out
+            })),
+            "outputs" => Ok(
+                "outputid: 0\noutputname: ffplay\nplugin: ffplay\noutputenabled: 1\n".to_string(),
+            ),
+            "status" => self.status().await,
+            "stats" => self.stats().await,
+            "currentsong" => {
+                let session = self.session().await;
+                let Some(item) = session.queue.get(session.current_index as usize) else {
+                    return Ok(String::new());
+                };
+                let mut out = String::new();
+                write_song(&mut out, &self.queued_track(item).await);
+                line(&mut out, "Pos", session.current_index);
+                line(&mut out, "Id", session.current_index);
+                Ok(out)
+            }
+            "play" | "playid" => {
+                let mut player = self.player.lock().await;
+                match number::<usize>(args, 0)? {
+                    Some(index) => self.start(&mut player, index, 0.0).await,
+                    None if player.state() == player::State::Pause => {
+                        let elapsed = player.elapsed();
+                        let index = self.session().await.current_index as usize;
+                        self.start(&mut player, index, elapsed).await
+                    }
+                    None if player.state() == player::State::Play => Ok(String::new()),
+                    // Pick up where a browser handing over the session left off
+                    None => {
+                        let session = self.session().await;
+                        let start = session.current_time as f64;
+                        self.start(&mut player, session.current_index as usize, start)
+                            .await
+                    }
+                }
+            }
+            "pause" => {
+                let mut player = self.player.lock().await;
+                let pause = match number::<u8>(args, 0)? {
+                    Some(p) => p == 1,
+                    None => player.state() == player::State::Play,
+                };
+                match (pause, player.state()) {
+                    (true, player::State::Play) => self.stop(&mut player, true).await,
+                    (false, player::State::Pause) => {
+                        let elapsed = player.elapsed();
+                        let index = self.session().await.current_index as usize;
+                        return self.start(&mut player, index, elapsed).await;
+                    }
+                    _ => {}
+                }
+                Ok(String::new())
+            }
+            "stop" => {
+                let mut player = self.player.lock().await;
+                self.stop(&mut player, false).await;
+                Ok(String::new())
+            }
+            "next" => {
+                let mut player = self.player.lock().await;
+                let index = self.session().await.current_index as usize;
+                match self.next_local(index).await {
+                    Some(next) => self.start(&mut player, next, 0.0).await,
+                    None => {
+                        self.stop(&mut player, false).await;
+                        Ok(String::new())
+                    }
+                }
+            }
+            "previous" => {
+                let mut player = self.player.lock().await;
+                let session = self.session().await;
+                let previous = session.queue
+                    [..(session.current_index as usize).min(session.queue.len())]
+                    .iter()
+                    .rposition(is_local)
+                    .unwrap_or(session.current_index as usize);
+                self.start(&mut player, previous, 0.0).await
+            }
+            "seekcur" => {
+                let Some(arg) = args.first() else {
+                    return Err(ack(ACK_ERROR_ARG, "Missing time"));
+                };
+                let mut player = self.player.lock().await;
+                let offset = match arg.chars().next() {
+                    Some('+' | '-') => player.elapsed(),
+                    _ => 0.0,
+                };
+                let time = arg
+                    .parse::<f64>()
+                    .map_err(|_| ack(ACK_ERROR_ARG, format!("Invalid time: {arg}")))?;
+                let time = (offset + time).max(0.0);
+                let index = self.session().await.current_index as usize;
+
+                match player.state() {
+                    player::State::Pause => {
+                        player.pause_at(time);
+                        self.update_session(|s| s.current_time = time as f32).await;
+                        self.changed("player");
+                        Ok(String::new())
+                    }
+                    _ => self.start(&mut player, index, time).await,
+                }
+            }
+            "add" | "addid" => {
+                let uri = args
+                    .first()
+                    .ok_or_else(|| ack(ACK_ERROR_ARG, "Missing URI"))?;
+                let tracks = self.resolve(uri).await?;
+                let first = self.session().await.queue.len();
+                let added = tracks.len();
+
+                self.update_session(|s| s.queue.extend(tracks.into_iter().map(QueueItem::from)))
+                    .await;
+                self.changed("playlist");
+                tracing::info!("MPD queued {} songs", added);
+
+                Ok(match command {
+                    "addid" => format!("Id: {first}\n"),
+                    _ => String::new(),
+                })
+            }
+            "clear" => {
+                let mut player = self.player.lock().await;
+                player.stop();
+                self.update_session(|s| {
+                    s.queue.clear();
+                    s.current_index = 0;
+                    s.current_time = 0.0;
+                })
+                .await;
+                self.changed("player");
+                self.changed("playlist");
+                Ok(String::new())
+            }
+            "playlistinfo" | "playlistid" | "plchanges" => {
+                let session = self.session().await;
+                let range = match (command, args.first()) {
+                    // Everything counts as changed, clients replace their copy
+                    ("plchanges", _) | (_, None) => 0..session.queue.len(),
+                    (_, Some(arg)) => range(arg, session.queue.len())?,
+                };
+
+                let mut out = String::new();
+                for (item, pos) in session.queue[range.clone()].iter().zip(range) {
+                    write_song(&mut out, &self.queued_track(item).await);
+                    line(&mut out, "Pos", pos);
+                    line(&mut out, "Id", pos);
+                }
+                Ok(out)
+            }
+            "listall" | "listallinfo" | "lsinfo" => {
+                let tracks = self
+                    .resolve(args.first().map_or("", |a| a.as_str()))
+                    .await?;
+                let mut out = String::new();
+                for track in &tracks {
+                    match command {
+                        "listall" => line(&mut out, "file", &track.filename),
+                        _ => write_song(&mut out, track),
+                    }
+                }
+                Ok(out)
+            }
+            "search" | "find" => {
+                check_filters(args)?;
+                let mut out = String::new();
+                for track in self.tracks().await? {
+                    if matches(&track, args, command == "find") {
+                        write_song(&mut out, &track);
+                    }
+                }
+                Ok(out)
+            }
+            "list" => {
+                let Some(tag) = args.first() else {
+                    return Err(ack(ACK_ERROR_ARG, "Missing tag type"));
+                };
+                let Some(key) = TAG_TYPES
+                    .iter()
+                    .chain(&["file"])
+                    .find(|t| t.eq_ignore_ascii_case(tag))
+                else {
+                    return Err(ack(ACK_ERROR_ARG, format!("Unknown tag type: {tag}")));
+                };
+
+                // `list album ARTIST` from before filters had tags
+                let filters = match &args[1..] {
+                    [artist] if key == &"Album" => vec!["artist".to_string(), artist.clone()],
+                    // Grouping is ignored
+                    [filters @ .., group, _] if group == "group" => filters.to_vec(),
+                    filters => filters.to_vec(),
+                };
+                if !filters.is_empty() {
+                    check_filters(&filters)?;
+                }
+
+                let values = self
+                    .tracks()
+                    .await?
+                    .iter()
+                    .filter(|t| matches(t, &filters, true))
+                    .filter_map(|t| tag_value(t, key))
+                    .collect::<BTreeSet<String>>();
+
+                let mut out = String::new();
+                for value in values {
+                    line(&mut out, key, value);
+                }
+                Ok(out)
+            }
+            "repeat" | "random" | "single" | "consume" => match args.first().map(|a| a.as_str()) {
+                Some("0") => Ok(String::new()),
+                _ => Err(ack(ACK_ERROR_ARG, format!("{command} is not supported"))),
+            },
+            _ => Err(ack(
+                ACK_ERROR_UNKNOWN,
+                format!("unknown command \"{command}\""),
+            )),
+        }
+    }
+
+    async fn tracks(&self) -> Result<Vec<Track>, Ack> {
+        library::tracks(&self.state)
+            .await
+            .map_err(|e| ack(ACK_ERROR_SYSTEM, e))
+    }
+
+    /// Songs at `uri`, the root is the whole library
+    async fn resolve(&self, uri: &str) -> Result<Vec<Track>, Ack> {
+        let uri = uri.trim_matches('/');
+        if uri.is_empty() {
+            return self.tracks().await;
+        }
+
+        match library::track(&self.state, uri)
+            .await
+            .map_err(|e| ack(ACK_ERROR_SYSTEM, e))?
+        {
+            Some(track) => Ok(vec![track]),
+            None => Err(ack(ACK_ERROR_NO_EXIST, "No such song")),
+        }
+    }
+
+    async fn status(&self) -> Reply {
+        let player = self.player.lock().await;
+        let session = self.session().await;
+        let index = session.current_index as usize;
+
+        let mut out = String::new();
+        line(&mut out, "volume", 100);
+        for mode in ["repeat", "random", "single", "consume"] {
+            line(&mut out, mode, 0);
+        }
+        line(
+            &mut out,
+            "playlist",
+            self.playlist_version.load(Ordering::Relaxed),
+        );
+        line(&mut out, "playlistlength", session.queue.len());
+        line(&mut out, "state", player.state().name());
+
+        if let Some(item) = session.queue.get(index) {
+            line(&mut out, "song", index);
+            line(&mut out, "songid", index);
+            if player.state() != player::State::Stop {
+                let elapsed = player.elapsed();
+                let duration = item.duration.unwrap_or_default();
+                line(&mut out, "time", format!("{}:{duration}", elapsed as u64));
+                line(&mut out, "elapsed", format!("{elapsed:.3}"));
+                line(&mut out, "duration", duration);
+            }
+        }
+        if index + 1 < session.queue.len() {
+            line(&mut out, "nextsong", index + 1);
+            line(&mut out, "nextsongid", index + 1);
+        }
+
+        Ok(out)
+    }
+
+    async fn stats(&self) -> Reply {
+        let tracks = self.tracks().await?;
+        let artists = tracks
+            .iter()
+            .map(|t| t.artist.as_str())
+            .collect::<BTreeSet<&str>>();
+        let albums = tracks
+            .iter()
+            .filter_map(|t| t.album.as_deref())
+            .collect::<BTreeSet<&str>>();
+
+        let mut out = String::new();
+        line(&mut out, "artists", artists.len());
+        line(&mut out, "albums", albums.len());
+        line(&mut out, "songs", tracks.len());
+        line(&mut out, "uptime", self.started.elapsed().as_secs());
+        line(
+            &mut out,
+            "db_playtime",
+            tracks.iter().filter_map(|t| t.duration).sum::<u64>(),
+        );
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(list: &[&str]) -> Vec<String> {
+        list.iter().map(|w| w.to_string()).collect()
+    }
+
+    #[test]
+    fn parse_splits_quoted_words() {
+        let args = parse(r#"find  artist "AC/DC \"live\" \\" album x"#)
+            .ok()
+            .unwrap();
+        assert_eq!(
+            args,
+            words(&["find", "artist", r#"AC/DC "live" \"#, "album", "x"])
+        );
+        assert_eq!(parse("  ").ok().unwrap(), Vec::<String>::new());
+        assert_eq!(parse(r#"add """#).ok().unwrap(), words(&["add", ""]));
+        assert!(parse(r#"add "a.mp3"#).is_err());
+    }
+
+    #[test]
+    fn range_accepts_mpd_forms() {
+        assert_eq!(range("2", 5).ok(), Some(2..3));
+        assert_eq!(range("1:3", 5).ok(), Some(1..3));
+        assert_eq!(range("1:", 5).ok(), Some(1..5));
+        assert_eq!(range("3:10", 5).ok(), Some(3..5));
+        assert_eq!(range("2:2", 5).ok(), Some(2..2));
+    }
+
+    #[test]
+    fn range_rejects_bad_ones() {
+        for arg in ["3:1", "5", "5:", "x", "1:x", ":2", &usize::MAX.to_string()] {
+            let e = range(arg, 5)
+                .err()
+                .unwrap_or_else(|| panic!("{arg} was accepted"));
+            assert_eq!(e.code, ACK_ERROR_ARG);
+        }
+    }
+
+    fn track() -> Track {
+        Track {
+            filename: "AC-DC - Thunderstruck.mp3".to_string(),
+            title: "Thunderstruck".to_string(),
+            artist: "AC/DC".to_string(),
+            album: Some("The Razors Edge".to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn check_filters_wants_known_tag_pairs() {
+        assert!(check_filters(&words(&["artist", "x"])).is_ok());
+        assert!(check_filters(&words(&["Any", "x", "FILE", "y"])).is_ok());
+        assert!(check_filters(&[]).is_err());
+        assert!(check_filters(&words(&["artist"])).is_err());
+        assert!(check_filters(&words(&["composer", "x"])).is_err());
+        assert!(check_filters(&words(&["(artist == 'x')"])).is_err());
+    }
+
+    #[test]
+    fn matches_exact_or_contained() {
+        let track = track();
+        assert!(matches(&track, &words(&["artist", "AC/DC"]), true));
+        assert!(!matches(&track, &words(&["artist", "ac/dc"]), true));
+        assert!(matches(&track, &words(&["artist", "ac/"]), false));
+        assert!(matches(&track, &words(&["any", "razors"]), false));
+        assert!(matches(&track, &words(&["AlbumArtist", "AC/DC"]), true));
+        assert!(!matches(&track, &words(&["genre", "rock"]), false));
+        assert!(!matches(
+            &track,
+            &words(&["artist", "AC/DC", "title", "Hells Bells"]),
+            true
+        ));
+    }
+}
@@ -0,0 +1,728 @@
+//! Vorbis comments for Ogg Vorbis and Opus files, audiotags only understands them inside FLAC.
+
+use std::{
+    fs::File,
+    io::{BufReader, Read, Seek, SeekFrom},
+};
+
+use audiotags::{MimeType, Picture};
+use base64::{engine::general_purpose::STANDARD, Engine};
+
+const CAPTURE_PATTERN: &[u8; 4] = b"OggS";
+const PICTURE_KEY: &str = "METADATA_BLOCK_PICTURE";
+/// Picture type for "Cover (front)" in FLAC picture blocks
+const FRONT_COVER: u32 = 3;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Codec {
+    Vorbis,
+    Opus,
+}
+
+impl Codec {
+    fn from_identification(packet: &[u8]) -> Option<Self> {
+        if packet.starts_with(b"\x01vorbis") {
+            Some(Self::Vorbis)
+        } else if packet.starts_with(b"OpusHead") {
+            Some(Self::Opus)
+        } else {
+            None
+        }
+    }
+
+    /// Vorbis has a setup header after the comments, Opus doesn't
+    fn header_packets(self) -> usize {
+        match self {
+            Self::Vorbis => 3,
+            Self::Opus => 2,
+        }
+    }
+
+    fn comment_magic(self) -> &'static [u8] {
+        match self {
+            Self::Vorbis => b"\x03vorbis",
+            Self::Opus => b"OpusTags",
+        }
+    }
+}
+
+struct Page {
+    header_type: u8,
+    granule: u64,
+    serial: u32,
+    sequence: u32,
+    lacing: Vec<u8>,
+    data: Vec<u8>,
+}
+
+impl Page {
+    fn read(reader: &mut impl Read) -> Result<Option<Self>, String> {
+        let mut header = [0u8; 27];
+        match reader.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.to_string()),
+        }
+
+        if &header[0..4] != CAPTURE_PATTERN {
+            return Err("Not an Ogg page".to_string());
+        }
+
+        let mut lacing = vec![0; header[26] as usize];
+        reader.read_exact(&mut lacing).map_err(|e| e.to_string())?;
+
+        let mut data = vec![0; lacing.iter().map(|l| *l as usize).sum()];
+        reader.read_exact(&mut data).map_err(|e| e.to_string())?;
+
+        Ok(Some(Self {
+            header_type: header[5],
+            granule: u64::from_le_bytes(header[6..14].try_into().unwrap()),
+            serial: u32::from_le_bytes(header[14..18].try_into().unwrap()),
+            sequence: u32::from_le_bytes(header[18..22].try_into().unwrap()),
+            lacing,
+            data,
+        }))
+    }
+
+    fn write(&self, out: &mut Vec<u8>) {
+        let start = out.len();
+
+        out.extend_from_slice(CAPTURE_PATTERN);
+        out.push(0);
+        out.push(self.header_type);
+        out.extend_from_slice(&self.granule.to_le_bytes());
+        out.extend_from_slice(&self.serial.to_le_bytes());
+        out.extend_from_slice(&self.sequence.to_le_bytes());
+        out.extend_from_slice(&[0; 4]);
+        out.push(self.lacing.len() as u8);
+        out.extend_from_slice(&self.lacing);
+        out.extend_from_slice(&self.data);
+
+        let crc = crc32(&out[start..]);
+        out[start + 22..start + 26].copy_from_slice(&crc.to_le_bytes());
+    }
+}
+
+/// Reassemble the first `count` packets.
+/// Returns the packets, how many pages they span and whether the last one ends its page.
+fn header_packets(pages: &[Page], count: usize) -> Option<(Vec<Vec<u8>>, usize, bool)> {
+    let mut packets = Vec::with_capacity(count);
+    let mut current = vec![];
+
+    for (i, page) in pages.iter().enumerate() {
+        let mut offset = 0;
+        for (j, lace) in page.lacing.iter().enumerate() {
+            let lace = *lace as usize;
+            current.extend_from_slice(&page.data[offset..offset + lace]);
+            offset += lace;
+
+            if lace < 255 {
+                packets.push(std::mem::take(&mut current));
+                if packets.len() == count {
+                    return Some((packets, i + 1, j == page.lacing.len() - 1));
+                }
+            }
+        }
+    }
+
+    None
+}
+
+/// Lay packets out on fresh pages starting at `sequence`
+fn paginate(packets: &[Vec<u8>], serial: u32, mut sequence: u32) -> Vec<Page> {
+    let mut pages = vec![];
+    let mut lacing = vec![];
+    let mut data = vec![];
+    let mut continued = false;
+    let mut packet_ended = false;
+
+    for packet in packets {
+        let mut laces = vec![255u8; packet.len() / 255];
+        laces.push((packet.len() % 255) as u8);
+
+        let mut offset = 0;
+        for (i, lace) in laces.into_iter().enumerate() {
+            if lacing.len() == 255 {
+                pages.push(Page {
+                    header_type: continued as u8,
+                    // Pages on which no packet ends carry no granule position
+                    granule: if packet_ended { 0 } else { u64::MAX },
+                    serial,
+                    sequence,
+                    lacing: std::mem::take(&mut lacing),
+                    data: std::mem::take(&mut data),
+                });
+                sequence += 1;
+                continued = i > 0;
+                packet_ended = false;
+            }
+
+            lacing.push(lace);
+            data.extend_from_slice(&packet[offset..offset + lace as usize]);
+            offset += lace as usize;
+        }
+
+        packet_ended = true;
+    }
+
+    if !lacing.is_empty() {
+        pages.push(Page {
+            header_type: continued as u8,
+            granule: 0,
+            serial,
+            sequence,
+            lacing,
+            data,
+        });
+    }
+
+    pages
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    static TABLE: std::sync::OnceLock<[u32; 256]> = std::sync::OnceLock::new();
+    let table = TABLE.get_or_init(|| {
+        let mut table = [0u32; 256];
+        for (i, entry) in table.iter_mut().enumerate() {
+            let mut r = (i as u32) << 24;
+            for _ in 0..8 {
+                r = if r & 0x80000000 != 0 {
+                    (r << 1) ^ 0x04c11db7
+                } else {
+                    r << 1
+                };
+            }
+            *entry = r;
+        }
+        table
+    });
+
+    data.iter().fold(0, |crc, b| {
+        (crc << 8) ^ table[((crc >> 24) as u8 ^ b) as usize]
+    })
+}
+
+struct Cursor<'a>(&'a [u8]);
+
+impl<'a> Cursor<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.0.len() < len {
+            return None;
+        }
+
+        let (head, tail) = self.0.split_at(len);
+        self.0 = tail;
+        Some(head)
+    }
+
+    fn u32_le(&mut self) -> Option<u32> {
+        self.take(4)
+            .map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+    }
+
+    fn u32_be(&mut self) -> Option<u32> {
+        self.take(4)
+            .map(|b| u32::from_be_bytes(b.try_into().unwrap()))
+    }
+}
+
+pub struct OggTag {
+    codec: Codec,
+    vendor: String,
+    comments: Vec<(String, String)>,
+    /// Raw `METADATA_BLOCK_PICTURE` values, written back untouched unless the cover changes
+    pictures: Vec<String>,
+    cover: Option<(MimeType, Vec<u8>)>,
+    duration: Option<f64>,
+}
+
+impl OggTag {
+    pub fn read_from_path(path: &str) -> Result<Self, String> {
+        let mut reader = BufReader::new(File::open(path).map_err(|e| e.to_string())?);
+
+        let mut pages = vec![];
+        let (packets, codec) = loop {
+            let page = Page::read(&mut reader)?.ok_or("Missing Ogg headers")?;
+            pages.push(page);
+
+            let codec = Codec::from_identification(&pages[0].data)
+                .ok_or("Not an Ogg Vorbis or Opus file")?;
+            if let Some((packets, _, _)) = header_packets(&pages, codec.header_packets()) {
+                break (packets, codec);
+            }
+        };
+
+        let mut tag = Self::parse_comments(codec, &packets[1])?;
+        tag.duration = Self::read_duration(codec, &packets[0], reader.get_mut());
+
+        Ok(tag)
+    }
+
+    fn parse_comments(codec: Codec, packet: &[u8]) -> Result<Self, String> {
+        let mut cursor = Cursor(packet);
+        if cursor.take(codec.comment_magic().len()) != Some(codec.comment_magic()) {
+            return Err("Invalid comment header".to_string());
+        }
+
+        let invalid = || "Invalid comment header".to_string();
+
+        let vendor_len = cursor.u32_le().ok_or_else(invalid)? as usize;
+        let vendor = String::from_utf8_lossy(cursor.take(vendor_len).ok_or_else(invalid)?);
+
+        let mut comments = vec![];
+        let mut pictures = vec![];
+        for _ in 0..cursor.u32_le().ok_or_else(invalid)? {
+            let len = cursor.u32_le().ok_or_else(invalid)? as usize;
+            let comment = String::from_utf8_lossy(cursor.take(len).ok_or_else(invalid)?);
+
+            let Some((key, value)) = comment.split_once('=') else {
+                continue;
+            };
+
+            if key.eq_ignore_ascii_case(PICTURE_KEY) {
+                pictures.push(value.to_string());
+            } else {
+                comments.push((key.to_ascii_uppercase(), value.to_string()));
+            }
+        }
+
+        let cover = pictures
+            .iter()
+            .filter_map(|p| decode_picture(p))
+            .max_by_key(|(kind, _, _)| *kind == FRONT_COVER)
+            .map(|(_, mime, data)| (mime, data));
+
+        Ok(Self {
+            codec,
+            vendor: vendor.to_string(),
+            comments,
+            pictures,
+            cover,
+            duration: None,
+        })
+    }
+
+    /// Last granule position divided by the sample rate
+    fn read_duration(codec: Codec, identification: &[u8], file: &mut File) -> Option<f64> {
+        let (rate, pre_skip) = match codec {
+            Codec::Vorbis => (
+                u32::from_le_bytes(identification.get(12..16)?.try_into().ok()?) as f64,
+                0,
+            ),
+            Codec::Opus => (
+                48000.0,
+                u16::from_le_bytes(identification.get(10..12)?.try_into().ok()?) as u64,
+            ),
+        };
+
+        let len = file.seek(SeekFrom::End(0)).ok()?;
+        file.seek(SeekFrom::Start(len.saturating_sub(65536))).ok()?;
+        let mut tail = vec![];
+        file.read_to_end(&mut tail).ok()?;
+
+        let last = tail.windows(4).rposition(|w| w == CAPTURE_PATTERN)?;
+        let granule = u64::from_le_bytes(tail.get(last + 6..last + 14)?.try_into().ok()?);
+
+        Some(granule.saturating_sub(pre_skip) as f64 / rate)
+    }
+
+    fn get(&self, key: &str) -> Option<&str> {
+        self.comments
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    fn set(&mut self, key: &str, value: &str) {
+        self.comments.retain(|(k, _)| k != key);
+        self.comments.push((key.to_string(), value.to_string()));
+    }
+
+    pub fn set_title(&mut self, title: &str) {
+        self.set("TITLE", title);
+    }
+
+    pub fn artist(&self) -> Option<&str> {
+        self.get("ARTIST")
+    }
+
+    pub fn set_artist(&mut self, artist: &str) {
+        self.set("ARTIST", artist);
+    }
+
+    pub fn album_title(&self) -> Option<&str> {
+        self.get("ALBUM")
+    }
+
+    pub fn album_artist(&self) -> Option<&str> {
+        self.get("ALBUMARTIST")
+    }
+
+    /// Also accepts the `3/12` form some taggers write
+    pub fn track_number(&self) -> Option<u16> {
+        self.get("TRACKNUMBER")?
+            .split('/')
+            .next()?
+            .trim()
+            .parse()
+            .ok()
+    }
+
+    pub fn disc_number(&self) -> Option<u16> {
+        self.get("DISCNUMBER")?
+            .split('/')
+            .next()?
+            .trim()
+            .parse()
+            .ok()
+    }
+
+    /// `DATE` is usually a full date, only the year is kept
+    pub fn year(&self) -> Option<i32> {
+        self.get("DATE")?.get(..4)?.parse().ok()
+    }
+
+    pub fn genre(&self) -> Option<&str> {
+        self.get("GENRE")
+    }
+
+    pub fn comment(&self) -> Option<&str> {
+        self.get("COMMENT")
+    }
+
+    pub fn set_comment(&mut self, comment: &str) {
+        self.set("COMMENT", comment);
+    }
+
+    pub fn duration(&self) -> Option<f64> {
+        self.duration
+    }
+
+    pub fn album_cover(&self) -> Option<Picture<'_>> {
+        self.cover
+            .as_ref()
+            .map(|(mime, data)| Picture::new(data, *mime))
+    }
+
+    pub fn set_album_cover(&mut self, cover: Picture) {
+        self.pictures = vec![encode_picture(&cover)];
+        self.cover = Some((cover.mime_type, cover.data.to_vec()));
+    }
+
+    fn comment_packet(&self) -> Vec<u8> {
+        let mut packet = self.codec.comment_magic().to_vec();
+        packet.extend_from_slice(&(self.vendor.len() as u32).to_le_bytes());
+        packet.extend_from_slice(self.vendor.as_bytes());
+
+        let comments = self
+            .comments
+            .iter()
+            .map(|(k, v)| format!("{k}={v}"))
+            .chain(self.pictures.iter().map(|p| format!("{PICTURE_KEY}={p}")))
+            .collect::<Vec<String>>();
+
+        packet.extend_from_slice(&(comments.len() as u32).to_le_bytes());
+        for comment in comments {
+            packet.extend_from_slice(&(comment.len() as u32).to_le_bytes());
+            packet.extend_from_slice(comment.as_bytes());
+        }
+
+        if self.codec == Codec::Vorbis {
+            // Framing bit
+            packet.push(1);
+        }
+
+        packet
+    }
+
+    /// Rewrite the comment header, every following page gets renumbered
+    pub fn write_to_path(&self, path: &str) -> Result<(), String> {
+        let bytes = std::fs::read(path).map_err(|e| e.to_string())?;
+        let mut reader = bytes.as_slice();
+
+        let mut pages = vec![];
+        while let Some(page) = Page::read(&mut reader)? {
+            pages.push(page);
+        }
+
+        let serial = pages.first().ok_or("Empty Ogg file")?.serial;
+        if pages.iter().any(|p| p.serial != serial) {
+            return Err("Multiplexed Ogg streams are not supported".to_string());
+        }
+
+        let (mut packets, header_pages, ends_cleanly) =
+            header_packets(&pages, self.codec.header_packets()).ok_or("Missing Ogg headers")?;
+        if !ends_cleanly || header_pages < 2 {
+            return Err("Audio data shares a page with the headers".to_string());
+        }
+        packets[1] = self.comment_packet();
+
+        let mut out = Vec::with_capacity(bytes.len());
+        pages[0].write(&mut out);
+
+        let headers = paginate(&packets[1..], serial, 1);
+        let mut sequence = headers.len() as u32 + 1;
+        for page in headers {
+            page.write(&mut out);
+        }
+
+        for mut page in pages.into_iter().skip(header_pages) {
+            page.sequence = sequence;
+            sequence += 1;
+            page.write(&mut out);
+        }
+
+        let temp = format!("{path}.tmp");
+        std::fs::write(&temp, out).map_err(|e| e.to_string())?;
+        std::fs::rename(&temp, path).map_err(|e| e.to_string())
+    }
+}
+
+fn decode_picture(value: &str) -> Option<(u32, MimeType, Vec<u8>)> {
+    let block = STANDARD.decode(value).ok()?;
+    let mut cursor = Cursor(&block);
+
+    let kind = cursor.u32_be()?;
+    let mime_len = cursor.u32_be()? as usize;
+    let mime = MimeType::try_from(std::str::from_utf8(cursor.take(mime_len)?).ok()?).ok()?;
+    let description_len = cursor.u32_be()? as usize;
+    cursor.take(description_len)?;
+    // Width, height, color depth and number of colors
+    cursor.take(16)?;
+    let data_len = cursor.u32_be()? as usize;
+
+    Some((kind, mime, cursor.take(data_len)?.to_vec()))
+}
+
+fn encode_picture(picture: &Picture) -> String {
+    let mime = String::from(picture.mime_type);
+
+    let mut block = vec![];
+    block.extend_from_slice(&FRONT_COVER.to_be_bytes());
+    block.extend_from_slice(&(mime.len() as u32).to_be_bytes());
+    block.extend_from_slice(mime.as_bytes());
+    // No description, dimensions are optional
+    block.extend_from_slice(&[0; 20]);
+    block.extend_from_slice(&(picture.data.len() as u32).to_be_bytes());
+    block.extend_from_slice(picture.data);
+
+    STANDARD.encode(block)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SERIAL: u32 = 0x01020304;
+
+    /// Vorbis identification header at 44.1 kHz, the rest of it isn't read
+    fn identification() -> Vec<u8> {
+        let mut packet = b"\x01vorbis".to_vec();
+        packet.extend_from_slice(&0u32.to_le_bytes());
+        packet.push(2);
+        packet.extend_from_slice(&44100u32.to_le_bytes());
+        packet.extend_from_slice(&[0; 13]);
+        packet
+    }
+
+    fn comments(pairs: &[&str]) -> Vec<u8> {
+        let mut packet = b"\x03vorbis".to_vec();
+        packet.extend_from_slice(&4u32.to_le_bytes());
+        packet.extend_from_slice(b"test");
+        packet.extend_from_slice(&(pairs.len() as u32).to_le_bytes());
+        for pair in pairs {
+            packet.extend_from_slice(&(pair.len() as u32).to_le_bytes());
+            packet.extend_from_slice(pair.as_bytes());
+        }
+        packet.push(1);
+        packet
+    }
+
+    /// Setup header with bytes that would show any shift or truncation
+    fn setup(len: usize) -> Vec<u8> {
+        let mut packet = b"\x05vorbis".to_vec();
+        packet.extend((0..len).map(|i| (i * 7 % 251) as u8));
+        packet
+    }
+
+    /// A Vorbis file with `audio` pages of 10 packets, the last one ending at `granule`
+    fn vorbis(comments: Vec<u8>, setup: Vec<u8>, audio: usize, granule: u64) -> Vec<u8> {
+        let mut out = vec![];
+        Page {
+            header_type: 2,
+            granule: 0,
+            serial: SERIAL,
+            sequence: 0,
+            lacing: vec![identification().len() as u8],
+            data: identification(),
+        }
+        .write(&mut out);
+
+        let headers = paginate(&[comments, setup], SERIAL, 1);
+        let mut sequence = headers.len() as u32 + 1;
+        for page in headers {
+            page.write(&mut out);
+        }
+
+        for i in 0..audio {
+            Page {
+                header_type: if i + 1 == audio { 4 } else { 0 },
+                granule: granule * (i as u64 + 1) / audio as u64,
+                serial: SERIAL,
+                sequence,
+                lacing: vec![3; 10],
+                data: [i as u8; 30].to_vec(),
+            }
+            .write(&mut out);
+            sequence += 1;
+        }
+
+        out
+    }
+
+    /// Every page of `bytes`, checking the CRC and numbering of each
+    fn pages(bytes: &[u8]) -> Vec<Page> {
+        let mut reader = bytes;
+        let mut pages = vec![];
+        let mut offset = 0;
+
+        while let Some(page) = Page::read(&mut reader).unwrap() {
+            let len = 27 + page.lacing.len() + page.data.len();
+            let mut raw = bytes[offset..offset + len].to_vec();
+            let stored = u32::from_le_bytes(raw[22..26].try_into().unwrap());
+            raw[22..26].copy_from_slice(&[0; 4]);
+            assert_eq!(crc32(&raw), stored, "CRC of page {}", pages.len());
+            assert_eq!(page.sequence, pages.len() as u32);
+            assert_eq!(page.serial, SERIAL);
+
+            offset += len;
+            pages.push(page);
+        }
+        assert_eq!(offset, bytes.len());
+
+        pages
+    }
+
+    /// Written to a file of its own, removed when dropped
+    struct TempFile(String);
+
+    impl TempFile {
+        fn new(name: &str, bytes: &[u8]) -> Self {
+            let path = std::env::temp_dir().join(format!("ogg-{}-{name}.ogg", std::process::id()));
+            let path = path.to_string_lossy().into_owned();
+            std::fs::write(&path, bytes).unwrap();
+            Self(path)
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    #[test]
+    fn crc_matches_known_values() {
+        // CRC-32 with polynomial 0x04c11db7, no reflection, no final xor
+        assert_eq!(crc32(b"123456789"), 0x89a1897f);
+
+        // Opus identification page, CRC from an independent implementation
+        let mut page = vec![];
+        Page {
+            header_type: 2,
+            granule: 0,
+            serial: SERIAL,
+            sequence: 0,
+            lacing: vec![19],
+            data: b"OpusHead\x01\x02\x38\x01\x80\xbb\x00\x00\x00\x00\x00".to_vec(),
+        }
+        .write(&mut page);
+        assert_eq!(
+            page,
+            b"OggS\x00\x02\x00\x00\x00\x00\x00\x00\x00\x00\x04\x03\x02\x01\x00\x00\x00\x00\
+            \x8f\x9e\x5d\x6e\x01\x13OpusHead\x01\x02\x38\x01\x80\xbb\x00\x00\x00\x00\x00"
+        );
+    }
+
+    #[test]
+    fn paginate_spans_long_packets() {
+        let long = vec![9; 255 * 255 + 1000];
+        let pages = paginate(&[long.clone(), vec![1; 10]], SERIAL, 1);
+
+        assert_eq!(pages.len(), 2);
+        assert_eq!(pages[0].lacing, vec![255; 255]);
+        assert_eq!(pages[0].header_type, 0);
+        // No packet ends on the first page
+        assert_eq!(pages[0].granule, u64::MAX);
+        assert_eq!(pages[1].header_type, 1);
+        assert_eq!(pages[1].sequence, 2);
+
+        let (packets, count, ends_cleanly) = header_packets(&pages, 2).unwrap();
+        assert_eq!((count, ends_cleanly), (2, true));
+        assert_eq!(packets, vec![long, vec![1; 10]]);
+    }
+
+    #[test]
+    fn long_comments_round_trip() {
+        let file = TempFile::new(
+            "long",
+            &vorbis(
+                comments(&["ARTIST=Old", "album=Alb"]),
+                setup(3000),
+                5,
+                441000,
+            ),
+        );
+
+        let mut tag = OggTag::read_from_path(&file.0).unwrap();
+        assert_eq!(tag.artist(), Some("Old"));
+        assert_eq!(tag.duration(), Some(10.0));
+
+        // Base64 makes the comment packet well over 255 * 255 bytes
+        let cover = (0..100_000).map(|i| (i % 256) as u8).collect::<Vec<u8>>();
+        tag.set_artist("New");
+        tag.set_album_cover(Picture::new(&cover, MimeType::Png));
+        tag.write_to_path(&file.0).unwrap();
+
+        let written = std::fs::read(&file.0).unwrap();
+        assert!(pages(&written).len() > 8);
+
+        let tag = OggTag::read_from_path(&file.0).unwrap();
+        assert_eq!(tag.artist(), Some("New"));
+        assert_eq!(tag.album_title(), Some("Alb"));
+        assert_eq!(tag.duration(), Some(10.0));
+        let read = tag.album_cover().unwrap();
+        assert_eq!(read.mime_type, MimeType::Png);
+        assert_eq!(read.data, cover.as_slice());
+    }
+
+    #[test]
+    fn setup_header_and_audio_are_kept() {
+        let setup = setup(70_000);
+        let file = TempFile::new(
+            "setup",
+            &vorbis(comments(&["TITLE=x"]), setup.clone(), 3, 44100),
+        );
+        let before = pages(&std::fs::read(&file.0).unwrap());
+
+        let mut tag = OggTag::read_from_path(&file.0).unwrap();
+        tag.set_title("A much longer title than before");
+        tag.write_to_path(&file.0).unwrap();
+
+        let after = pages(&std::fs::read(&file.0).unwrap());
+        let (packets, header_pages, ends_cleanly) = header_packets(&after, 3).unwrap();
+        assert!(ends_cleanly);
+        assert_eq!(packets[0], identification());
+        assert_eq!(packets[2], setup);
+
+        let audio = |pages: &[Page], skip| {
+            pages
+                .iter()
+                .skip(skip)
+                .map(|p| (p.header_type, p.granule, p.data.clone()))
+                .collect::<Vec<_>>()
+        };
+        let (_, before_headers, _) = header_packets(&before, 3).unwrap();
+        assert_eq!(audio(&after, header_pages), audio(&before, before_headers));
+    }
+}
@@ -0,0 +1,46 @@
+//! Keeps in-memory state such as the playback session in the `app_state` table
+
+use serde::{de::DeserializeOwned, Serialize};
+use sqlx::SqlitePool;
+
+use crate::utils;
+
+pub const RECENTLY_PLAYED: &str = "recently_played";
+pub const AUDIO_FORMAT: &str = "audio_format";
+
+/// `None` when nothing was saved yet or the saved value no longer parses
+pub async fn load<T: DeserializeOwned>(db: &SqlitePool, key: &str) -> Option<T> {
+    let value: Option<String> =
+        match sqlx::query_scalar("SELECT value FROM app_state WHERE key = ?")
+            .bind(key)
+            .fetch_optional(db)
+            .await
+        {
+            Ok(v) => v,
+            Err(e) => {
+                tracing::error!("Cannot load {}: {}", key, e);
+                return None;
+            }
+        };
+
+    serde_json::from_str(&value?)
+        .inspect_err(|e| tracing::warn!("Discarding saved {}: {}", key, e))
+        .ok()
+}
+
+pub async fn save<T: Serialize>(db: &SqlitePool, key: &str, value: &T) -> Result<(), String> {
+    let value = serde_json::to_string(value).map_err(|e| e.to_string())?;
+
+    sqlx::query(
+        "INSERT INTO app_state (key, value, updated_at) VALUES (?, ?, ?)
+        ON CONFLICT (key) DO UPDATE SET value = excluded.value, updated_at = excluded.updated_at",
+    )
+    .bind(key)
+    .bind(value)
+    .bind(utils::unix_now())
+    .execute(db)
+    .await
+    .map_err(|e| e.to_string())?;
+
+    Ok(())
+}
@@ -0,0 +1,150 @@
+//! Audio output on the machine running the server, for [`crate::mpd`].
+//! Each song is played by its own ffplay process. Pausing kills it and resuming starts a new one
+//! where it left off, so no signals are needed.
+
+use std::{process::Stdio, time::Instant};
+
+use tokio::{
+    process::{Child, Command},
+    sync::{mpsc, oneshot},
+};
+
+/// Sent with the generation of the song when ffplay exits on its own, and whether it succeeded
+pub type Finished = (u64, bool);
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum State {
+    Stop,
+    Play,
+    Pause,
+}
+
+impl State {
+    pub fn name(self) -> &'static str {
+        match self {
+            State::Stop => "stop",
+            State::Play => "play",
+            State::Pause => "pause",
+        }
+    }
+}
+
+pub struct Player {
+    state: State,
+    /// Seconds into the song when playback started, or where it is paused
+    offset: f64,
+    started: Instant,
+    /// Dropping it stops ffplay
+    stop: Option<oneshot::Sender<()>>,
+    /// Bumped for every ffplay so a late exit of an old one is told apart
+    generation: u64,
+    finished: mpsc::UnboundedSender<Finished>,
+}
+
+impl Player {
+    pub fn new(finished: mpsc::UnboundedSender<Finished>) -> Self {
+        Self {
+            state: State::Stop,
+            offset: 0.0,
+            started: Instant::now(),
+            stop: None,
+            generation: 0,
+            finished,
+        }
+    }
+
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Seconds into the current song
+    pub fn elapsed(&self) -> f64 {
+        match self.state {
+            State::Play => self.offset + self.started.elapsed().as_secs_f64(),
+            _ => self.offset,
+        }
+    }
+
+    /// Play the file at `path` from `start` seconds in, replacing whatever was playing
+    pub fn play(&mut self, path: &str, start: f64) -> Result<(), String> {
+        self.stop = None;
+
+        let child = Command::new("ffplay")
+            .args(["-nodisp", "-autoexit", "-loglevel", "error", "-ss"])
+            .arg(start.to_string())
+            .arg(path)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|e| format!("Failed to spawn ffplay: {e}"))?;
+
+        let (stop, stopped) = oneshot::channel();
+        self.generation += 1;
+        tokio::spawn(watch(
+            child,
+            stopped,
+            self.generation,
+            self.finished.clone(),
+        ));
+
+        self.stop = Some(stop);
+        self.state = State::Play;
+        self.offset = start;
+        self.started = Instant::now();
+
+        Ok(())
+    }
+
+    pub fn pause(&mut self) {
+        if self.state == State::Play {
+            self.offset = self.elapsed();
+            self.stop = None;
+            self.state = State::Pause;
+        }
+    }
+
+    /// Paused at `offset`, for seeking while paused
+    pub fn pause_at(&mut self, offset: f64) {
+        self.stop = None;
+        self.state = State::Pause;
+        self.offset = offset;
+    }
+
+    pub fn stop(&mut self) {
+        self.stop = None;
+        self.state = State::Stop;
+        self.offset = 0.0;
+    }
+}
+
+async fn watch(
+    child: Child,
+    mut stopped: oneshot::Receiver<()>,
+    generation: u64,
+    finished: mpsc::UnboundedSender<Finished>,
+) {
+    // Dropping the future of `wait_with_output` kills ffplay
+    tokio::select! {
+        output = child.wait_with_output() => {
+            let success = match output {
+                Ok(out) if out.status.success() => true,
+                Ok(out) => {
+                    tracing::error!("ffplay failed: {}", String::from_utf8_lossy(&out.stderr));
+                    false
+                }
+                Err(e) => {
+                    tracing::error!("Cannot wait for ffplay: {}", e);
+                    false
+                }
+            };
+            _ = finished.send((generation, success));
+        }
+        _ = &mut stopped => {}
+    }
+}
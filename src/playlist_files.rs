@@ -0,0 +1,371 @@
+//! Reading and writing M3U/M3U8, XSPF and PLS playlists
+
+use axum::{
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+};
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use serde::Deserialize;
+
+use crate::{utils, QueueItem, Track};
+
+/// Characters left alone in the path of exported URLs
+const PATH: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+#[derive(Clone, Copy)]
+pub enum Format {
+    M3u,
+    Xspf,
+    Pls,
+}
+
+impl Format {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "m3u" | "m3u8" => Some(Self::M3u),
+            "xspf" => Some(Self::Xspf),
+            "pls" => Some(Self::Pls),
+            _ => None,
+        }
+    }
+
+    /// Guess from the content, anything unknown is read as M3U
+    pub fn detect(content: &str) -> Self {
+        let start = content.trim_start_matches('\u{feff}').trim_start();
+        if start.starts_with("<?xml") || start.starts_with("<playlist") {
+            Self::Xspf
+        } else if start.to_ascii_lowercase().starts_with("[playlist]") {
+            Self::Pls
+        } else {
+            Self::M3u
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::M3u => "m3u8",
+            Self::Xspf => "xspf",
+            Self::Pls => "pls",
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Self::M3u => "audio/x-mpegurl",
+            Self::Xspf => "application/xspf+xml",
+            Self::Pls => "audio/x-scpls",
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ExportQuery {
+    format: Option<String>,
+}
+
+/// Serve `items` as a downloadable playlist file, M3U8 unless asked otherwise
+pub fn export(
+    query: ExportQuery,
+    headers: &HeaderMap,
+    name: &str,
+    items: &[QueueItem],
+) -> Response {
+    let format = match query.format.as_deref().map(Format::from_name) {
+        Some(Some(f)) => f,
+        Some(None) => return (StatusCode::BAD_REQUEST, "Unknown format").into_response(),
+        None => Format::M3u,
+    };
+
+    // Players opening the file need absolute URLs back to this server
+    let host = headers
+        .get(header::HOST)
+        .and_then(|h| h.to_str().ok())
+        .unwrap_or("localhost:1809");
+    let body = render(format, name, items, &format!("http://{host}"));
+
+    let disposition = format!(
+        "attachment; filename=\"{}.{}\"",
+        name.replace(['"', '/', '\\'], "_"),
+        format.extension()
+    );
+
+    (
+        StatusCode::OK,
+        [
+            (header::CONTENT_TYPE, format.content_type().to_string()),
+            (header::CONTENT_DISPOSITION, disposition),
+        ],
+        body,
+    )
+        .into_response()
+}
+
+/// Local files are linked through `/m/` on `base`, anything else is a YouTube id
+fn location(item: &QueueItem, base: &str) -> String {
+    if item.url.starts_with("/m/") {
+        format!("{base}/m/{}", utf8_percent_encode(&item.filename, PATH))
+    } else {
+        utils::youtube_url(&item.filename)
+    }
+}
+
+pub fn render(format: Format, name: &str, items: &[QueueItem], base: &str) -> String {
+    match format {
+        Format::M3u => {
+            let mut out = format!("#EXTM3U\n#PLAYLIST:{name}\n");
+            for item in items {
+                out.push_str(&format!(
+                    "#EXTINF:{},{} - {}\n{}\n",
+                    item.duration.map(|d| d as i64).unwrap_or(-1),
+                    item.artist,
+                    item.title,
+                    location(item, base)
+                ));
+            }
+            out
+        }
+        Format::Xspf => {
+            let mut out = format!(
+                "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+                <playlist version=\"1\" xmlns=\"http://xspf.org/ns/0/\">\n  \
+                <title>{}</title>\n  <trackList>\n",
+                html_escape::encode_text(name)
+            );
+            for item in items {
+                out.push_str("    <track>\n");
+                out.push_str(&format!(
+                    "      <location>{}</location>\n      <title>{}</title>\n      <creator>{}</creator>\n",
+                    html_escape::encode_text(&location(item, base)),
+                    html_escape::encode_text(&item.title),
+                    html_escape::encode_text(&item.artist)
+                ));
+                if let Some(d) = item.duration {
+                    out.push_str(&format!("      <duration>{}</duration>\n", d * 1000));
+                }
+                if let Some(t) = &item.thumbnail {
+                    let image = if t.starts_with('/') {
+                        format!("{base}{t}")
+                    } else {
+                        t.clone()
+                    };
+                    out.push_str(&format!(
+                        "      <image>{}</image>\n",
+                        html_escape::encode_text(&image)
+                    ));
+                }
+                out.push_str("    </track>\n");
+            }
+            out.push_str("  </trackList>\n</playlist>\n");
+            out
+        }
+        Format::Pls => {
+            let mut out = "[playlist]\n".to_string();
+            for (i, item) in items.iter().enumerate() {
+                let n = i + 1;
+                out.push_str(&format!(
+                    "File{n}={}\nTitle{n}={} - {}\nLength{n}={}\n",
+                    location(item, base),
+                    item.artist,
+                    item.title,
+                    item.duration.map(|d| d as i64).unwrap_or(-1)
+                ));
+            }
+            out.push_str(&format!("NumberOfEntries={}\nVersion=2\n", items.len()));
+            out
+        }
+    }
+}
+
+/// One track as written in an imported playlist
+pub struct Entry {
+    pub location: String,
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    /// Reported back as-is when the entry can't be matched
+    pub line: String,
+}
+
+impl Entry {
+    /// Last path segment of the location, percent-decoded
+    pub fn file_name(&self) -> String {
+        // An encoded `?` or `#` belongs to the name
+        let path = self.location.split(['?', '#']).next().unwrap_or_default();
+        let name = path.rsplit(['/', '\\']).next().unwrap_or_default();
+
+        percent_decode_str(name).decode_utf8_lossy().to_string()
+    }
+}
+
+/// What an imported entry points to: a YouTube video, or a library track.
+/// Tries the file name first, then the title (and artist when known) from the playlist.
+pub fn resolve(entry: &Entry, library: &[Track]) -> Option<QueueItem> {
+    if let Some(id) = utils::youtube_id(&entry.location) {
+        return Some(QueueItem {
+            url: format!("/temp-stream/{id}"),
+            title: entry.title.clone().unwrap_or_else(|| id.clone()),
+            artist: entry.artist.clone().unwrap_or_default(),
+            filename: id,
+            artists: None,
+            thumbnail: None,
+            duration: None,
+            artist_thumbnail: None,
+        });
+    }
+
+    library_track(entry, library).map(|t| QueueItem::from(t.clone()))
+}
+
+fn library_track<'a>(entry: &Entry, library: &'a [Track]) -> Option<&'a Track> {
+    let file_name = entry.file_name();
+    if let Some(track) = library.iter().find(|t| t.filename == file_name) {
+        return Some(track);
+    }
+
+    // Same song in another format, or a file that was converted since
+    let stem = utils::without_extension(&file_name).to_lowercase();
+    if !stem.is_empty() {
+        if let Some(track) = library.iter().find(|t| t.title.to_lowercase() == stem) {
+            return Some(track);
+        }
+    }
+
+    let title = entry.title.as_ref()?.to_lowercase();
+    let artist = entry.artist.as_ref().map(|a| a.to_lowercase());
+
+    library.iter().find(|t| {
+        t.title.to_lowercase() == title
+            && artist
+                .as_ref()
+                .is_none_or(|a| t.artist.to_lowercase().contains(a.as_str()))
+    })
+}
+
+/// `Artist - Title` as used by `#EXTINF` and PLS titles
+fn split_display_title(display: &str) -> (Option<String>, Option<String>) {
+    match display.split_once(" - ") {
+        Some((artist, title)) => (
+            Some(title.trim().to_string()),
+            Some(artist.trim().to_string()),
+        ),
+        None if !display.trim().is_empty() => (Some(display.trim().to_string()), None),
+        None => (None, None),
+    }
+}
+
+/// Returns the playlist title if the file has one
+pub fn parse(format: Format, content: &str) -> (Option<String>, Vec<Entry>) {
+    let content = content.trim_start_matches('\u{feff}');
+
+    match format {
+        Format::M3u => parse_m3u(content),
+        Format::Xspf => parse_xspf(content),
+        Format::Pls => (None, parse_pls(content)),
+    }
+}
+
+fn parse_m3u(content: &str) -> (Option<String>, Vec<Entry>) {
+    let mut name = None;
+    let mut entries = vec![];
+    let mut info: Option<&str> = None;
+
+    for line in content.lines().map(|l| l.trim()).filter(|l| !l.is_empty()) {
+        if let Some(extinf) = line.strip_prefix("#EXTINF:") {
+            info = Some(extinf);
+            continue;
+        }
+        if let Some(playlist) = line.strip_prefix("#PLAYLIST:") {
+            name = Some(playlist.trim().to_string());
+            continue;
+        }
+        if line.starts_with('#') {
+            continue;
+        }
+
+        let (title, artist) = info
+            .and_then(|i| i.split_once(','))
+            .map(|(_, display)| split_display_title(display))
+            .unwrap_or((None, None));
+
+        entries.push(Entry {
+            location: line.to_string(),
+            title,
+            artist,
+            line: line.to_string(),
+        });
+        info = None;
+    }
+
+    (name, entries)
+}
+
+/// Content of the first `<tag>` in `xml`, entities decoded
+fn xml_text(xml: &str, tag: &str) -> Option<String> {
+    let start = xml.find(&format!("<{tag}>"))? + tag.len() + 2;
+    let end = start + xml[start..].find(&format!("</{tag}>"))?;
+
+    Some(html_escape::decode_html_entities(xml[start..end].trim()).to_string())
+}
+
+fn parse_xspf(content: &str) -> (Option<String>, Vec<Entry>) {
+    let (head, track_list) = content.split_once("<trackList>").unwrap_or((content, ""));
+    let name = xml_text(head, "title");
+
+    let entries = track_list
+        .split("<track>")
+        .skip(1)
+        .filter_map(|track| {
+            let track = track.split("</track>").next()?;
+            let title = xml_text(track, "title");
+
+            Some(Entry {
+                location: xml_text(track, "location").unwrap_or_default(),
+                line: title.clone().unwrap_or_else(|| track.trim().to_string()),
+                title,
+                artist: xml_text(track, "creator"),
+            })
+        })
+        .collect();
+
+    (name, entries)
+}
+
+fn parse_pls(content: &str) -> Vec<Entry> {
+    let mut files: Vec<(usize, String)> = vec![];
+    let mut titles: Vec<(usize, String)> = vec![];
+
+    for line in content.lines().map(|l| l.trim()) {
+        let Some((key, value)) = line.split_once('=') else {
+            continue;
+        };
+        let key = key.to_ascii_lowercase();
+
+        if let Some(n) = key.strip_prefix("file").and_then(|n| n.parse().ok()) {
+            files.push((n, value.to_string()));
+        } else if let Some(n) = key.strip_prefix("title").and_then(|n| n.parse().ok()) {
+            titles.push((n, value.to_string()));
+        }
+    }
+
+    files.sort_by_key(|(n, _)| *n);
+    files
+        .into_iter()
+        .map(|(n, location)| {
+            let (title, artist) = titles
+                .iter()
+                .find(|(i, _)| *i == n)
+                .map(|(_, t)| split_display_title(t))
+                .unwrap_or((None, None));
+
+            Entry {
+                line: format!("File{n}={location}"),
+                location,
+                title,
+                artist,
+            }
+        })
+        .collect()
+}
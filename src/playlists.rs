@@ -0,0 +1,528 @@
+use std::collections::HashSet;
+
+use axum::{
+    extract::{Path, Query, State},
+    http::{HeaderMap, StatusCode},
+    response::Response,
+    routing::{delete, get, post},
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+use sqlx::{types::Json as SqlJson, FromRow};
+
+use crate::{
+    library,
+    playlist_files::{self, ExportQuery, Format},
+    utils, AppState, QueueItem,
+};
+
+type ApiResult<T> = Result<T, (StatusCode, String)>;
+
+fn internal(e: sqlx::Error) -> (StatusCode, String) {
+    tracing::error!("Playlist query failed: {}", e);
+    (StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+}
+
+fn not_found() -> (StatusCode, String) {
+    (StatusCode::NOT_FOUND, "Playlist not found".to_string())
+}
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/", get(list_playlists).post(create_playlist))
+        .route(
+            "/:id",
+            get(get_playlist)
+                .put(rename_playlist)
+                .delete(delete_playlist),
+        )
+        .route("/:id/items", post(add_items))
+        .route("/:id/items/:entry", delete(remove_item))
+        .route("/:id/move", post(move_item))
+        .route("/:id/export", get(export_playlist))
+        .route("/import", post(import_playlist))
+}
+
+#[derive(Serialize, FromRow)]
+pub struct PlaylistSummary {
+    pub id: i64,
+    pub name: String,
+    pub count: i64,
+    pub thumbnail: Option<String>,
+    pub created_at: i64,
+    pub updated_at: i64,
+}
+
+#[derive(Serialize)]
+pub struct Playlist {
+    pub id: i64,
+    pub name: String,
+    pub created_at: i64,
+    pub updated_at: i64,
+    pub items: Vec<PlaylistEntry>,
+}
+
+#[derive(Serialize)]
+pub struct PlaylistEntry {
+    pub id: i64,
+
+    #[serde(flatten)]
+    pub item: QueueItem,
+}
+
+#[derive(FromRow)]
+struct EntryRow {
+    id: i64,
+    filename: String,
+    title: String,
+    artist: String,
+    artists: Option<SqlJson<Vec<String>>>,
+    thumbnail: Option<String>,
+    duration: Option<i64>,
+    artist_thumbnail: Option<String>,
+    url: String,
+}
+
+impl From<EntryRow> for PlaylistEntry {
+    fn from(row: EntryRow) -> Self {
+        Self {
+            id: row.id,
+            item: QueueItem {
+                filename: row.filename,
+                title: row.title,
+                artist: row.artist,
+                artists: row.artists.map(|a| a.0),
+                thumbnail: row.thumbnail,
+                duration: row.duration.map(|d| d as u64),
+                artist_thumbnail: row.artist_thumbnail,
+                url: row.url,
+            },
+        }
+    }
+}
+
+async fn list_playlists(State(state): State<AppState>) -> ApiResult<Json<Vec<PlaylistSummary>>> {
+    Ok(Json(list(&state).await?))
+}
+
+pub async fn list(state: &AppState) -> ApiResult<Vec<PlaylistSummary>> {
+    sqlx::query_as(
+        "SELECT p.id, p.name, p.created_at, p.updated_at,
+            (SELECT COUNT(*) FROM playlist_entries e WHERE e.playlist_id = p.id) AS count,
+            (SELECT e.thumbnail FROM playlist_entries e
+                WHERE e.playlist_id = p.id AND e.thumbnail IS NOT NULL
+                ORDER BY e.position LIMIT 1) AS thumbnail
+        FROM playlists p
+        ORDER BY p.name COLLATE NOCASE",
+    )
+    .fetch_all(&state.db)
+    .await
+    .map_err(internal)
+}
+
+#[derive(Deserialize)]
+struct CreatePlaylistRequest {
+    name: String,
+    #[serde(default)]
+    items: Vec<QueueItem>,
+}
+
+async fn create_playlist(
+    State(state): State<AppState>,
+    Json(body): Json<CreatePlaylistRequest>,
+) -> ApiResult<(StatusCode, Json<Playlist>)> {
+    let name = body.name.trim();
+    if name.is_empty() {
+        return Err((StatusCode::BAD_REQUEST, "Name is empty".to_string()));
+    }
+
+    let id = create(&state, name).await?;
+    insert_items(&state, id, &body.items).await?;
+
+    Ok((StatusCode::CREATED, Json(load(&state, id).await?)))
+}
+
+async fn get_playlist(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> ApiResult<Json<Playlist>> {
+    Ok(Json(load(&state, id).await?))
+}
+
+#[derive(Deserialize)]
+struct RenamePlaylistRequest {
+    name: String,
+}
+
+async fn rename_playlist(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+    Json(body): Json<RenamePlaylistRequest>,
+) -> ApiResult<Json<Playlist>> {
+    let name = body.name.trim();
+    if name.is_empty() {
+        return Err((StatusCode::BAD_REQUEST, "Name is empty".to_string()));
+    }
+
+    let result = sqlx::query("UPDATE playlists SET name = ?, updated_at = ? WHERE id = ?")
+        .bind(name)
+        .bind(utils::unix_now())
+        .bind(id)
+        .execute(&state.db)
+        .await
+        .map_err(internal)?;
+    if result.rows_affected() == 0 {
+        return Err(not_found());
+    }
+
+    Ok(Json(load(&state, id).await?))
+}
+
+async fn delete_playlist(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> ApiResult<(StatusCode, &'static str)> {
+    let result = sqlx::query("DELETE FROM playlists WHERE id = ?")
+        .bind(id)
+        .execute(&state.db)
+        .await
+        .map_err(internal)?;
+    if result.rows_affected() == 0 {
+        return Err(not_found());
+    }
+
+    Ok((StatusCode::OK, "OK"))
+}
+
+/// Appends to the end of the playlist
+async fn add_items(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+    Json(items): Json<Vec<QueueItem>>,
+) -> ApiResult<Json<Playlist>> {
+    exists(&state, id).await?;
+    insert_items(&state, id, &items).await?;
+
+    Ok(Json(load(&state, id).await?))
+}
+
+async fn remove_item(
+    State(state): State<AppState>,
+    Path((id, entry)): Path<(i64, i64)>,
+) -> ApiResult<Json<Playlist>> {
+    let result = sqlx::query("DELETE FROM playlist_entries WHERE id = ? AND playlist_id = ?")
+        .bind(entry)
+        .bind(id)
+        .execute(&state.db)
+        .await
+        .map_err(internal)?;
+    if result.rows_affected() == 0 {
+        return Err((StatusCode::NOT_FOUND, "Entry not found".to_string()));
+    }
+
+    // Close the gap left by the removed entry
+    write_order(&state, id, entry_ids(&state, id).await?).await?;
+
+    Ok(Json(load(&state, id).await?))
+}
+
+#[derive(Deserialize)]
+struct MoveRequest {
+    from: usize,
+    to: usize,
+}
+
+async fn move_item(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+    Json(body): Json<MoveRequest>,
+) -> ApiResult<Json<Playlist>> {
+    exists(&state, id).await?;
+
+    let mut entries = entry_ids(&state, id).await?;
+    if body.from >= entries.len() || body.to >= entries.len() {
+        return Err((StatusCode::BAD_REQUEST, "Position out of range".to_string()));
+    }
+
+    let entry = entries.remove(body.from);
+    entries.insert(body.to, entry);
+    write_order(&state, id, entries).await?;
+
+    Ok(Json(load(&state, id).await?))
+}
+
+async fn export_playlist(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+    Query(query): Query<ExportQuery>,
+    headers: HeaderMap,
+) -> ApiResult<Response> {
+    let playlist = load(&state, id).await?;
+    let items = playlist
+        .items
+        .into_iter()
+        .map(|e| e.item)
+        .collect::<Vec<QueueItem>>();
+
+    Ok(playlist_files::export(
+        query,
+        &headers,
+        &playlist.name,
+        &items,
+    ))
+}
+
+#[derive(Deserialize)]
+struct ImportQuery {
+    format: Option<String>,
+    name: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ImportResponse {
+    playlist: Playlist,
+    /// Entries that point neither to a file in the library nor to a YouTube video
+    unmatched: Vec<String>,
+}
+
+/// Create a playlist from an M3U/M3U8, XSPF or PLS file sent as the body.
+/// The format is detected from the content when not given.
+async fn import_playlist(
+    State(state): State<AppState>,
+    Query(query): Query<ImportQuery>,
+    body: String,
+) -> ApiResult<(StatusCode, Json<ImportResponse>)> {
+    let format = match query.format {
+        Some(f) => Format::from_name(&f)
+            .ok_or_else(|| (StatusCode::BAD_REQUEST, "Unknown format".to_string()))?,
+        None => Format::detect(&body),
+    };
+
+    let (title, entries) = playlist_files::parse(format, &body);
+    if entries.is_empty() {
+        return Err((StatusCode::BAD_REQUEST, "No entries found".to_string()));
+    }
+
+    let library = library::tracks(&state)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))?;
+
+    let mut items = vec![];
+    let mut unmatched = vec![];
+    for entry in entries {
+        match playlist_files::resolve(&entry, &library) {
+            Some(item) => items.push(item),
+            None => unmatched.push(entry.line),
+        }
+    }
+
+    let name = query
+        .name
+        .or(title)
+        .map(|n| n.trim().to_string())
+        .filter(|n| !n.is_empty())
+        .unwrap_or_else(|| "Imported playlist".to_string());
+
+    let id = create(&state, &name).await?;
+    insert_items(&state, id, &items).await?;
+
+    if !unmatched.is_empty() {
+        tracing::warn!(
+            "{} entries of {} not found in library",
+            unmatched.len(),
+            name
+        );
+    }
+
+    Ok((
+        StatusCode::CREATED,
+        Json(ImportResponse {
+            playlist: load(&state, id).await?,
+            unmatched,
+        }),
+    ))
+}
+
+pub async fn create(state: &AppState, name: &str) -> ApiResult<i64> {
+    let now = utils::unix_now();
+    sqlx::query_scalar(
+        "INSERT INTO playlists (name, created_at, updated_at) VALUES (?, ?, ?) RETURNING id",
+    )
+    .bind(name)
+    .bind(now)
+    .bind(now)
+    .fetch_one(&state.db)
+    .await
+    .map_err(internal)
+}
+
+async fn exists(state: &AppState, id: i64) -> ApiResult<()> {
+    let found: Option<i64> = sqlx::query_scalar("SELECT id FROM playlists WHERE id = ?")
+        .bind(id)
+        .fetch_optional(&state.db)
+        .await
+        .map_err(internal)?;
+
+    found.map(|_| ()).ok_or_else(not_found)
+}
+
+async fn touch(tx: &mut sqlx::SqliteConnection, id: i64) -> ApiResult<()> {
+    sqlx::query("UPDATE playlists SET updated_at = ? WHERE id = ?")
+        .bind(utils::unix_now())
+        .bind(id)
+        .execute(tx)
+        .await
+        .map_err(internal)?;
+
+    Ok(())
+}
+
+pub async fn insert_items(state: &AppState, id: i64, items: &[QueueItem]) -> ApiResult<()> {
+    let mut tx = state.db.begin().await.map_err(internal)?;
+
+    let next: i64 = sqlx::query_scalar(
+        "SELECT COALESCE(MAX(position) + 1, 0) FROM playlist_entries WHERE playlist_id = ?",
+    )
+    .bind(id)
+    .fetch_one(&mut *tx)
+    .await
+    .map_err(internal)?;
+
+    for (i, item) in items.iter().enumerate() {
+        sqlx::query(
+            "INSERT INTO playlist_entries (
+                playlist_id, position, filename, title, artist, artists,
+                thumbnail, duration, artist_thumbnail, url
+            )
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+        )
+        .bind(id)
+        .bind(next + i as i64)
+        .bind(&item.filename)
+        .bind(&item.title)
+        .bind(&item.artist)
+        .bind(item.artists.as_ref().map(SqlJson))
+        .bind(&item.thumbnail)
+        .bind(item.duration.map(|d| d as i64))
+        .bind(&item.artist_thumbnail)
+        .bind(&item.url)
+        .execute(&mut *tx)
+        .await
+        .map_err(internal)?;
+    }
+
+    touch(&mut tx, id).await?;
+    tx.commit().await.map_err(internal)?;
+
+    Ok(())
+}
+
+/// Point every entry of local file `filename` at `item` instead
+pub async fn replace_file(state: &AppState, filename: &str, item: &QueueItem) -> ApiResult<()> {
+    let mut tx = state.db.begin().await.map_err(internal)?;
+
+    sqlx::query(
+        "UPDATE playlists SET updated_at = ?
+        WHERE id IN (
+            SELECT playlist_id FROM playlist_entries WHERE filename = ? AND url LIKE '/m/%'
+        )",
+    )
+    .bind(utils::unix_now())
+    .bind(filename)
+    .execute(&mut *tx)
+    .await
+    .map_err(internal)?;
+
+    sqlx::query(
+        "UPDATE playlist_entries SET
+            filename = ?, title = ?, artist = ?, artists = ?,
+            thumbnail = ?, duration = ?, artist_thumbnail = ?, url = ?
+        WHERE filename = ? AND url LIKE '/m/%'",
+    )
+    .bind(&item.filename)
+    .bind(&item.title)
+    .bind(&item.artist)
+    .bind(item.artists.as_ref().map(SqlJson))
+    .bind(&item.thumbnail)
+    .bind(item.duration.map(|d| d as i64))
+    .bind(&item.artist_thumbnail)
+    .bind(&item.url)
+    .bind(filename)
+    .execute(&mut *tx)
+    .await
+    .map_err(internal)?;
+
+    tx.commit().await.map_err(internal)
+}
+
+/// Drop every entry of local file `filename`, it was deleted
+pub async fn remove_file(state: &AppState, filename: &str) -> ApiResult<()> {
+    let playlists: Vec<i64> = sqlx::query_scalar(
+        "DELETE FROM playlist_entries WHERE filename = ? AND url LIKE '/m/%'
+        RETURNING playlist_id",
+    )
+    .bind(filename)
+    .fetch_all(&state.db)
+    .await
+    .map_err(internal)?;
+
+    let playlists = playlists.into_iter().collect::<HashSet<i64>>();
+    for id in playlists {
+        // Close the gaps left by the removed entries
+        write_order(state, id, entry_ids(state, id).await?).await?;
+    }
+
+    Ok(())
+}
+
+async fn entry_ids(state: &AppState, id: i64) -> ApiResult<Vec<i64>> {
+    sqlx::query_scalar("SELECT id FROM playlist_entries WHERE playlist_id = ? ORDER BY position")
+        .bind(id)
+        .fetch_all(&state.db)
+        .await
+        .map_err(internal)
+}
+
+/// Rewrite positions so they follow the order of `entries`
+async fn write_order(state: &AppState, id: i64, entries: Vec<i64>) -> ApiResult<()> {
+    let mut tx = state.db.begin().await.map_err(internal)?;
+    for (position, entry) in entries.into_iter().enumerate() {
+        sqlx::query("UPDATE playlist_entries SET position = ? WHERE id = ?")
+            .bind(position as i64)
+            .bind(entry)
+            .execute(&mut *tx)
+            .await
+            .map_err(internal)?;
+    }
+    touch(&mut tx, id).await?;
+    tx.commit().await.map_err(internal)
+}
+
+pub async fn load(state: &AppState, id: i64) -> ApiResult<Playlist> {
+    let (name, created_at, updated_at): (String, i64, i64) =
+        sqlx::query_as("SELECT name, created_at, updated_at FROM playlists WHERE id = ?")
+            .bind(id)
+            .fetch_optional(&state.db)
+            .await
+            .map_err(internal)?
+            .ok_or_else(not_found)?;
+
+    let entries: Vec<EntryRow> = sqlx::query_as(
+        "SELECT id, filename, title, artist, artists, thumbnail, duration, artist_thumbnail, url
+        FROM playlist_entries
+        WHERE playlist_id = ?
+        ORDER BY position",
+    )
+    .bind(id)
+    .fetch_all(&state.db)
+    .await
+    .map_err(internal)?;
+
+    Ok(Playlist {
+        id,
+        name,
+        created_at,
+        updated_at,
+        items: entries.into_iter().map(PlaylistEntry::from).collect(),
+    })
+}
@@ -0,0 +1,90 @@
+use std::process::Stdio;
+
+use serde::Deserialize;
+use tokio::process::Command;
+
+/// Stream properties reported by ffprobe
+#[derive(Default)]
+pub struct AudioInfo {
+    pub duration: Option<f64>,
+    pub bitrate: Option<u32>,
+    pub sample_rate: Option<u32>,
+    pub channels: Option<u32>,
+    pub codec: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct FfprobeOutput {
+    #[serde(default)]
+    streams: Vec<FfprobeStream>,
+    format: Option<FfprobeFormat>,
+}
+
+// ffprobe prints most numbers as strings
+#[derive(Deserialize)]
+struct FfprobeStream {
+    codec_name: Option<String>,
+    sample_rate: Option<String>,
+    channels: Option<u32>,
+    bit_rate: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct FfprobeFormat {
+    duration: Option<String>,
+    bit_rate: Option<String>,
+}
+
+/// Probe the first audio stream of `path` with ffprobe, which yt-dlp already needs
+pub async fn probe(path: &str) -> Result<AudioInfo, String> {
+    let proc = Command::new("ffprobe")
+        .args([
+            "-v",
+            "error",
+            "-select_streams",
+            "a:0",
+            "-show_entries",
+            "stream=codec_name,sample_rate,channels,bit_rate:format=duration,bit_rate",
+            "-of",
+            "json",
+            "--",
+            path,
+        ])
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .output()
+        .await
+        .map_err(|e| format!("Failed to spawn ffprobe: {e}"))?;
+
+    if !proc.status.success() {
+        return Err(format!(
+            "ffprobe error: {}",
+            String::from_utf8_lossy(&proc.stderr)
+        ));
+    }
+
+    let output: FfprobeOutput =
+        serde_json::from_slice(&proc.stdout).map_err(|e| format!("Failed to parse JSON: {e}"))?;
+    let stream = output.streams.into_iter().next();
+    let format = output.format;
+
+    Ok(AudioInfo {
+        duration: format
+            .as_ref()
+            .and_then(|f| f.duration.as_deref())
+            .and_then(|d| d.parse().ok()),
+        // VBR streams only have an overall bitrate
+        bitrate: stream
+            .as_ref()
+            .and_then(|s| s.bit_rate.as_deref())
+            .or(format.as_ref().and_then(|f| f.bit_rate.as_deref()))
+            .and_then(|b| b.parse().ok()),
+        sample_rate: stream
+            .as_ref()
+            .and_then(|s| s.sample_rate.as_deref())
+            .and_then(|r| r.parse().ok()),
+        channels: stream.as_ref().and_then(|s| s.channels),
+        codec: stream.and_then(|s| s.codec_name),
+    })
+}
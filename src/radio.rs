@@ -0,0 +1,634 @@
+//! Stations: a saved playlist, or the whole library shuffled with `/radio/shuffle`, played as one
+//! endless stream like Icecast does, so VLC or a smart speaker can tune in.
+//!
+//! Every listener of a station hears the same point of it. A station is encoded by one ffmpeg per
+//! track, paced in real time, from the first listener until the last one leaves.
+//! Clients sending `Icy-MetaData: 1` get `StreamTitle` updates interleaved with the audio.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    hash::{BuildHasher, RandomState},
+    process::Stdio,
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    body::Bytes,
+    extract::{Path, Query, State},
+    http::{HeaderMap, HeaderValue, StatusCode},
+    response::Response,
+};
+use serde::Deserialize;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt},
+    process::Command,
+    sync::{broadcast, mpsc},
+};
+
+use crate::{
+    audio_format::{self, AudioFormat},
+    library, playlists, temp_stream, AppState, Track, MUSIC_DIR,
+};
+
+type ApiResult<T> = Result<T, (StatusCode, String)>;
+
+/// Station playing the whole library in random order
+const SHUFFLE: &str = "shuffle";
+/// kbps, constant so players can size their buffers
+const DEFAULT_BITRATE: u32 = 128;
+/// Bytes of audio between two ICY metadata blocks
+const ICY_METAINT: usize = 16 * 1024;
+/// Recent audio sent to new listeners at once, so playback starts without waiting for the buffer
+const BURST_BYTES: usize = 64 * 1024;
+const CHUNK_SIZE: usize = 4 * 1024;
+/// Chunks a listener can fall behind before skipping ahead
+const BACKLOG: usize = 256;
+
+#[derive(Clone)]
+enum Frame {
+    Title(Arc<str>),
+    Audio(Bytes),
+}
+
+/// What a new listener needs to start in the middle of a track
+#[derive(Default)]
+struct Current {
+    title: Arc<str>,
+    /// Ogg header pages of the track, without them the rest can't be decoded
+    headers: Vec<Bytes>,
+    burst: VecDeque<Bytes>,
+    burst_len: usize,
+}
+
+struct Station {
+    frames: broadcast::Sender<Frame>,
+    current: Mutex<Current>,
+}
+
+impl Station {
+    /// Send `audio` to every listener, `false` once there are none left
+    fn publish(&self, audio: Bytes, header: bool) -> bool {
+        let mut current = self.current.lock().expect("radio station lock poisoned");
+        if header {
+            current.headers.push(audio.clone());
+        } else {
+            current.burst_len += audio.len();
+            current.burst.push_back(audio.clone());
+            while current.burst_len > BURST_BYTES {
+                let dropped = current.burst.pop_front().expect("burst is not empty");
+                current.burst_len -= dropped.len();
+            }
+        }
+
+        self.frames.send(Frame::Audio(audio)).is_ok()
+    }
+
+    fn start_track(&self, title: Arc<str>) -> bool {
+        let mut current = self.current.lock().expect("radio station lock poisoned");
+        *current = Current {
+            title: title.clone(),
+            ..Default::default()
+        };
+
+        self.frames.send(Frame::Title(title)).is_ok()
+    }
+
+    fn listen(&self) -> (broadcast::Receiver<Frame>, Arc<str>, Vec<Bytes>) {
+        let current = self.current.lock().expect("radio station lock poisoned");
+        let initial = current
+            .headers
+            .iter()
+            .chain(&current.burst)
+            .cloned()
+            .collect();
+
+        (self.frames.subscribe(), current.title.clone(), initial)
+    }
+}
+
+/// Running stations, keyed by name, format and mp3 bitrate
+#[derive(Default)]
+pub struct Stations(tokio::sync::Mutex<HashMap<String, Arc<Station>>>);
+
+impl Stations {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[derive(Deserialize)]
+pub struct RadioQuery {
+    /// `mp3` or `opus`
+    format: Option<String>,
+    /// kbps, mp3 only
+    bitrate: Option<u32>,
+}
+
+/// Tracks of station `name`, in the order they are played
+async fn playlist(state: &AppState, name: &str) -> ApiResult<Vec<Track>> {
+    let internal = |e: String| (StatusCode::INTERNAL_SERVER_ERROR, e);
+
+    if name == SHUFFLE {
+        let mut tracks = library::tracks(state).await.map_err(internal)?;
+        // A new RandomState is seeded differently, so every round has another order
+        let random = RandomState::new();
+        tracks.sort_by_cached_key(|t| random.hash_one(&t.filename));
+        return Ok(tracks);
+    }
+
+    let summary = playlists::list(state)
+        .await?
+        .into_iter()
+        .find(|p| p.name == name)
+        .ok_or((StatusCode::NOT_FOUND, format!("No station named {name}")))?;
+
+    // Only local songs can be played, YouTube ones are skipped
+    let mut tracks = vec![];
+    for entry in playlists::load(state, summary.id).await?.items {
+        if !entry.item.url.starts_with("/m/") {
+            continue;
+        }
+        if let Some(track) = library::track(state, &entry.item.filename)
+            .await
+            .map_err(internal)?
+        {
+            tracks.push(track);
+        }
+    }
+
+    Ok(tracks)
+}
+
+pub async fn radio(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+    Query(query): Query<RadioQuery>,
+    headers: HeaderMap,
+) -> ApiResult<Response> {
+    let bad_request = |e: &str| (StatusCode::BAD_REQUEST, e.to_string());
+
+    let format = match query.format.as_deref() {
+        None => AudioFormat::Mp3 { bitrate: None },
+        Some(f) => AudioFormat::from_name(f, None).ok_or(bad_request("Unknown format"))?,
+    };
+    if !matches!(format, AudioFormat::Mp3 { .. } | AudioFormat::Opus) {
+        return Err(bad_request("Stations are streamed as mp3 or opus"));
+    }
+    // Listeners asking for another bitrate get a station of their own
+    let (format, key) = match format {
+        AudioFormat::Mp3 { .. } => {
+            let bitrate = query.bitrate.unwrap_or(DEFAULT_BITRATE);
+            audio_format::check_bitrate(bitrate).map_err(|e| bad_request(&e))?;
+            let format = AudioFormat::Mp3 {
+                bitrate: Some(bitrate),
+            };
+            (format, format!("{name}.{bitrate}.{}", format.extension()))
+        }
+        _ if query.bitrate.is_some() => {
+            return Err(bad_request("Bitrate only applies to mp3 stations"));
+        }
+        format => (format, format!("{name}.{}", format.extension())),
+    };
+
+    let (station, (frames, title, initial)) = {
+        let mut stations = state.radio.0.lock().await;
+        match stations.get(&key) {
+            Some(station) => (station.clone(), station.listen()),
+            None => {
+                let tracks = playlist(&state, &name).await?;
+                if tracks.is_empty() {
+                    return Err((StatusCode::NOT_FOUND, format!("{name} has no local songs")));
+                }
+
+                let station = Arc::new(Station {
+                    frames: broadcast::channel(BACKLOG).0,
+                    current: Mutex::new(Current::default()),
+                });
+                let listening = station.listen();
+                stations.insert(key.clone(), station.clone());
+
+                tracing::info!("Starting station {}", key);
+                tokio::spawn(on_air(
+                    state.clone(),
+                    name.clone(),
+                    key,
+                    format,
+                    tracks,
+                    station.clone(),
+                ));
+                (station, listening)
+            }
+        }
+    };
+
+    let metaint = headers
+        .get("icy-metadata")
+        .is_some_and(|v| v.as_bytes() == b"1")
+        .then_some(ICY_METAINT);
+
+    let (tx, rx) = mpsc::channel(16);
+    tokio::spawn(listen(station, frames, title, initial, metaint, tx));
+
+    let mut response = temp_stream::respond(rx, format.content_type()).await?;
+    let headers = response.headers_mut();
+    if let Ok(name) = HeaderValue::from_str(&name) {
+        headers.insert("icy-name", name);
+    }
+    if let Some(metaint) = metaint {
+        headers.insert("icy-metaint", HeaderValue::from(metaint));
+    }
+
+    Ok(response)
+}
+
+enum Aired {
+    Played,
+    /// Nothing could be encoded
+    Skipped,
+    /// There are no listeners left
+    Abandoned,
+}
+
+/// Play `tracks` over and over on `station` until nobody listens anymore.
+/// Dropping the station once it is off air disconnects whoever is left.
+async fn on_air(
+    state: AppState,
+    name: String,
+    key: String,
+    format: AudioFormat,
+    mut tracks: Vec<Track>,
+    station: Arc<Station>,
+) {
+    loop {
+        let mut played = false;
+        for track in &tracks {
+            match play(&station, format, track).await {
+                Aired::Played => played = true,
+                Aired::Skipped => {}
+                Aired::Abandoned => {
+                    // A listener may have joined since the last chunk was sent
+                    let mut stations = state.radio.0.lock().await;
+                    if station.frames.receiver_count() == 0 {
+                        stations.remove(&key);
+                        tracing::info!("Stopped station {}, nobody is listening", key);
+                        return;
+                    }
+                }
+            }
+        }
+
+        // Picks up changes to the playlist, and a new order for the shuffle
+        tracks = match playlist(&state, &name).await {
+            Ok(t) if played && !t.is_empty() => t,
+            _ => {
+                tracing::warn!("Stopped station {}, it has nothing to play", key);
+                state.radio.0.lock().await.remove(&key);
+                return;
+            }
+        };
+    }
+}
+
+/// Encode `track` onto `station` in real time
+async fn play(station: &Station, format: AudioFormat, track: &Track) -> Aired {
+    let title: Arc<str> = format!("{} - {}", track.artist, track.title).into();
+    tracing::debug!("On air: {}", title);
+    if !station.start_track(title) {
+        return Aired::Abandoned;
+    }
+
+    // Sample rate and channels are fixed so decoders don't have to switch between tracks.
+    // MP3 gets no ID3 header or Xing frame, they'd end up in the middle of the stream.
+    let (rate, container_args) = match format {
+        AudioFormat::Opus => ("48000", vec![]),
+        _ => ("44100", vec!["-id3v2_version", "0", "-write_xing", "0"]),
+    };
+    let path = format!("{MUSIC_DIR}/{}", track.filename);
+    let mut ffmpeg = match Command::new("ffmpeg")
+        .args([
+            "-v",
+            "error",
+            "-re",
+            "-i",
+            &path,
+            "-vn",
+            "-map_metadata",
+            "-1",
+        ])
+        .args(["-metadata", &format!("title={}", track.title)])
+        .args(["-metadata", &format!("artist={}", track.artist)])
+        .args(["-ar", rate, "-ac", "2"])
+        .args(format.ffmpeg_args(None))
+        .args(container_args)
+        .arg("pipe:1")
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .kill_on_drop(true)
+        .spawn()
+    {
+        Ok(f) => f,
+        Err(e) => {
+            tracing::error!("Failed to spawn ffmpeg: {}", e);
+            return Aired::Skipped;
+        }
+    };
+    let mut output = ffmpeg.stdout.take().expect("stdout is piped");
+
+    let mut buffer = vec![0; CHUNK_SIZE];
+    let mut aired = Aired::Skipped;
+    loop {
+        // Whole Ogg pages, so listeners can join and skip ahead between them
+        let read = match format {
+            AudioFormat::Opus => ogg_page(&mut output).await,
+            _ => match output.read(&mut buffer).await {
+                Ok(0) => Ok(None),
+                Ok(n) => Ok(Some((Bytes::copy_from_slice(&buffer[..n]), false))),
+                Err(e) => Err(e.to_string()),
+            },
+        };
+
+        match read {
+            Ok(Some((audio, header))) => {
+                if !station.publish(audio, header) {
+                    return Aired::Abandoned;
+                }
+                aired = Aired::Played;
+            }
+            Ok(None) => break,
+            Err(e) => {
+                tracing::error!("Cannot read ffmpeg output for {}: {}", track.filename, e);
+                break;
+            }
+        }
+    }
+
+    if !ffmpeg.wait().await.is_ok_and(|s| s.success()) {
+        tracing::warn!("ffmpeg failed on {}, skipping it", track.filename);
+    }
+    aired
+}
+
+/// The next page and whether it is a header page, which all have a granule position of 0
+async fn ogg_page(reader: &mut (impl AsyncRead + Unpin)) -> Result<Option<(Bytes, bool)>, String> {
+    let mut header = [0u8; 27];
+    match reader.read_exact(&mut header).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.to_string()),
+    }
+    if &header[..4] != b"OggS" {
+        return Err("Lost Ogg page sync".to_string());
+    }
+
+    let mut lacing = vec![0u8; header[26] as usize];
+    reader
+        .read_exact(&mut lacing)
+        .await
+        .map_err(|e| e.to_string())?;
+    let mut data = vec![0u8; lacing.iter().map(|&l| l as usize).sum()];
+    reader
+        .read_exact(&mut data)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    let granule = u64::from_le_bytes(header[6..14].try_into().expect("8 bytes"));
+    let page = [&header[..], &lacing, &data].concat();
+
+    Ok(Some((Bytes::from(page), granule == 0)))
+}
+
+/// Forward what the station plays to one listener
+async fn listen(
+    station: Arc<Station>,
+    mut frames: broadcast::Receiver<Frame>,
+    title: Arc<str>,
+    initial: Vec<Bytes>,
+    metaint: Option<usize>,
+    tx: mpsc::Sender<Result<Bytes, String>>,
+) {
+    let mut icy = metaint.map(|interval| Icy {
+        interval,
+        until_metadata: interval,
+        title,
+        sent: None,
+    });
+
+    let interleave = |icy: &mut Option<Icy>, audio: Bytes| match icy.as_mut() {
+        Some(icy) => icy.interleave(&audio),
+        None => audio,
+    };
+    for audio in initial {
+        if tx.send(Ok(interleave(&mut icy, audio))).await.is_err() {
+            return;
+        }
+    }
+
+    loop {
+        match frames.recv().await {
+            Ok(Frame::Audio(audio)) => {
+                if tx.send(Ok(interleave(&mut icy, audio))).await.is_err() {
+                    return;
+                }
+            }
+            Ok(Frame::Title(title)) => {
+                if let Some(icy) = icy.as_mut() {
+                    icy.title = title;
+                }
+            }
+            Err(broadcast::error::RecvError::Lagged(n)) => {
+                tracing::debug!("Radio listener skipped {} chunks", n);
+
+                // The skipped chunks may have held the Ogg headers of a new track,
+                // empty for mp3
+                let headers = station
+                    .current
+                    .lock()
+                    .expect("radio station lock poisoned")
+                    .headers
+                    .clone();
+                for audio in headers {
+                    if tx.send(Ok(interleave(&mut icy, audio))).await.is_err() {
+                        return;
+                    }
+                }
+            }
+            Err(broadcast::error::RecvError::Closed) => return,
+        }
+    }
+}
+
+/// Shoutcast metadata: every `interval` bytes of audio a length byte, in 16 byte blocks,
+/// followed by the metadata. Empty when the title didn't change.
+struct Icy {
+    interval: usize,
+    until_metadata: usize,
+    title: Arc<str>,
+    sent: Option<Arc<str>>,
+}
+
+impl Icy {
+    fn interleave(&mut self, mut audio: &[u8]) -> Bytes {
+        let mut out = Vec::with_capacity(audio.len() + 1);
+        while !audio.is_empty() {
+            let n = self.until_metadata.min(audio.len());
+            out.extend_from_slice(&audio[..n]);
+            audio = &audio[n..];
+            self.until_metadata -= n;
+
+            if self.until_metadata == 0 {
+                self.metadata(&mut out);
+                self.until_metadata = self.interval;
+            }
+        }
+
+        Bytes::from(out)
+    }
+
+    fn metadata(&mut self, out: &mut Vec<u8>) {
+        if self.sent.as_ref() == Some(&self.title) {
+            out.push(0);
+            return;
+        }
+
+        // The length byte can't count more than 255 blocks
+        let mut title = self.title.to_string();
+        while title.len() > 255 * 16 - "StreamTitle='';".len() {
+            title.pop();
+        }
+        let metadata = format!("StreamTitle='{title}';");
+        let blocks = metadata.len().div_ceil(16);
+
+        out.push(blocks as u8);
+        out.extend_from_slice(metadata.as_bytes());
+        out.resize(out.len() + blocks * 16 - metadata.len(), 0);
+        self.sent = Some(self.title.clone());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn icy(interval: usize, title: &str) -> Icy {
+        Icy {
+            interval,
+            until_metadata: interval,
+            title: title.into(),
+            sent: None,
+        }
+    }
+
+    /// Audio and metadata blocks of an interleaved stream
+    fn split(mut stream: &[u8], interval: usize) -> (Vec<u8>, Vec<Vec<u8>>) {
+        let mut audio = vec![];
+        let mut metadata = vec![];
+        while !stream.is_empty() {
+            let n = interval.min(stream.len());
+            audio.extend_from_slice(&stream[..n]);
+            stream = &stream[n..];
+            if n < interval {
+                break;
+            }
+
+            let len = stream[0] as usize * 16;
+            metadata.push(stream[1..1 + len].to_vec());
+            stream = &stream[1 + len..];
+        }
+
+        (audio, metadata)
+    }
+
+    fn title_block(title: &str) -> Vec<u8> {
+        let mut block = format!("StreamTitle='{title}';").into_bytes();
+        block.resize(block.len().div_ceil(16) * 16, 0);
+        block
+    }
+
+    #[test]
+    fn interleave_across_chunks() {
+        let audio: Vec<u8> = (0..100).collect();
+        let mut chunked = icy(16, "A");
+        let mut out = vec![];
+        for chunk in [&audio[..10], &audio[10..20], &audio[20..52], &audio[52..]] {
+            out.extend_from_slice(&chunked.interleave(chunk));
+        }
+
+        assert_eq!(out, icy(16, "A").interleave(&audio).to_vec());
+        let (played, metadata) = split(&out, 16);
+        assert_eq!(played, audio);
+        assert_eq!(metadata.len(), 6);
+        assert_eq!(metadata[0], title_block("A"));
+        assert!(metadata[1..].iter().all(|m| m.is_empty()));
+    }
+
+    #[test]
+    fn unchanged_title_is_a_zero_byte() {
+        let mut icy = icy(4, "A");
+        assert_eq!(
+            icy.interleave(&[1; 4]).to_vec(),
+            [&[1; 4][..], &[1], &title_block("A")].concat()
+        );
+        assert_eq!(icy.interleave(&[1; 4]).to_vec(), [1, 1, 1, 1, 0]);
+
+        icy.title = "B".into();
+        assert_eq!(
+            icy.interleave(&[1; 4]).to_vec(),
+            [&[1; 4][..], &[1], &title_block("B")].concat()
+        );
+    }
+
+    #[test]
+    fn long_titles_are_cut_to_255_blocks() {
+        // Two bytes per character, cut between them
+        let mut icy = icy(1, &"é".repeat(3000));
+        let out = icy.interleave(&[0]);
+
+        assert_eq!(out[1], 255);
+        assert_eq!(out.len(), 2 + 255 * 16);
+        let metadata = std::str::from_utf8(&out[2..])
+            .unwrap()
+            .trim_end_matches('\0');
+        assert_eq!(metadata, format!("StreamTitle='{}';", "é".repeat(2032)));
+    }
+
+    fn page(granule: u64, data: &[u8]) -> Vec<u8> {
+        let mut lacing = vec![255; data.len() / 255];
+        lacing.push((data.len() % 255) as u8);
+
+        let mut page = b"OggS\0\0".to_vec();
+        page.extend_from_slice(&granule.to_le_bytes());
+        page.extend_from_slice(&[0; 12]);
+        page.push(lacing.len() as u8);
+        page.extend_from_slice(&lacing);
+        page.extend_from_slice(data);
+        page
+    }
+
+    #[tokio::test]
+    async fn ogg_page_tells_header_pages() {
+        let head = page(0, b"OpusHead\x01\x02\x38\x01\x80\xbb\0\0\0\0\0");
+        let audio = page(960, &[7; 600]);
+        let mut stream = &[head.clone(), audio.clone()].concat()[..];
+
+        let read = ogg_page(&mut stream).await.unwrap().unwrap();
+        assert_eq!((read.0.to_vec(), read.1), (head, true));
+        let read = ogg_page(&mut stream).await.unwrap().unwrap();
+        assert_eq!((read.0.to_vec(), read.1), (audio, false));
+        assert!(ogg_page(&mut stream).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn ogg_page_on_truncated_input() {
+        let audio = page(960, &[7; 600]);
+
+        // Ended between pages, or in the middle of one
+        assert!(ogg_page(&mut &audio[..20]).await.unwrap().is_none());
+        assert!(ogg_page(&mut &audio[..28]).await.is_err());
+        assert!(ogg_page(&mut &audio[..audio.len() - 1]).await.is_err());
+
+        let mut garbage = audio.clone();
+        garbage[0] = b'X';
+        assert!(ogg_page(&mut &garbage[..]).await.is_err());
+    }
+}
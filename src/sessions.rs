@@ -0,0 +1,256 @@
+//! Playback sessions, one per device.
+//! Devices are told apart by the `X-Device-Id` header, or a `device_id` cookie handed out on first visit.
+
+use std::{
+    collections::HashMap,
+    hash::{BuildHasher, RandomState},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    extract::{Path, Request, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    middleware::Next,
+    response::Response,
+    routing::{get, post},
+    Extension, Json, Router,
+};
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+
+use crate::{utils, AppState, PlaylistSession, QueueItem};
+
+const DEVICE_HEADER: &str = "x-device-id";
+const DEVICE_COOKIE: &str = "device_id";
+
+type ApiResult<T> = Result<T, (StatusCode, String)>;
+
+#[derive(Clone)]
+pub struct Device(pub String);
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/", get(list_sessions))
+        .route("/transfer", post(transfer_session))
+        .route("/:device", get(get_session).delete(delete_session))
+}
+
+/// Make the caller's [`Device`] available to handlers, setting the cookie if it has none yet
+pub async fn assign_device(mut req: Request, next: Next) -> Response {
+    if let Some(device) = device_from(req.headers()) {
+        req.extensions_mut().insert(Device(device));
+        return next.run(req).await;
+    }
+
+    let device = new_device_id();
+    req.extensions_mut().insert(Device(device.clone()));
+
+    let mut res = next.run(req).await;
+    let cookie = format!("{DEVICE_COOKIE}={device}; Path=/; Max-Age=31536000; SameSite=Lax");
+    if let Ok(v) = HeaderValue::from_str(&cookie) {
+        res.headers_mut().append(header::SET_COOKIE, v);
+    }
+
+    res
+}
+
+fn device_from(headers: &HeaderMap) -> Option<String> {
+    let from_header = headers
+        .get(DEVICE_HEADER)
+        .and_then(|h| h.to_str().ok())
+        .map(|h| h.trim().to_string());
+
+    let from_cookie = || {
+        headers
+            .get_all(header::COOKIE)
+            .iter()
+            .filter_map(|h| h.to_str().ok())
+            .flat_map(|h| h.split(';'))
+            .filter_map(|c| c.trim().split_once('='))
+            .find(|(name, _)| *name == DEVICE_COOKIE)
+            .map(|(_, value)| value.to_string())
+    };
+
+    from_header
+        .or_else(from_cookie)
+        .filter(|d| is_valid_device_id(d))
+}
+
+fn is_valid_device_id(device: &str) -> bool {
+    !device.is_empty()
+        && device.len() <= 64
+        && device
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+fn new_device_id() -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or_default();
+
+    // Every RandomState is seeded differently, good enough to tell devices apart
+    format!(
+        "{:016x}{:016x}",
+        RandomState::new().hash_one(nanos),
+        RandomState::new().hash_one(nanos)
+    )
+}
+
+pub async fn load_all(db: &SqlitePool) -> HashMap<String, PlaylistSession> {
+    let rows: Vec<(String, String, i64)> =
+        match sqlx::query_as("SELECT device, session, updated_at FROM sessions")
+            .fetch_all(db)
+            .await
+        {
+            Ok(r) => r,
+            Err(e) => {
+                tracing::error!("Cannot load sessions: {}", e);
+                return HashMap::new();
+            }
+        };
+
+    rows.into_iter()
+        .filter_map(|(device, session, updated_at)| {
+            match serde_json::from_str::<PlaylistSession>(&session) {
+                Ok(s) => Some((device, PlaylistSession { updated_at, ..s })),
+                Err(e) => {
+                    tracing::warn!("Discarding saved session of {}: {}", device, e);
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+pub async fn save(db: &SqlitePool, device: &str, session: &PlaylistSession) -> Result<(), String> {
+    let value = serde_json::to_string(session).map_err(|e| e.to_string())?;
+
+    sqlx::query(
+        "INSERT INTO sessions (device, session, updated_at) VALUES (?, ?, ?)
+        ON CONFLICT (device) DO UPDATE SET
+            session = excluded.session,
+            updated_at = excluded.updated_at",
+    )
+    .bind(device)
+    .bind(value)
+    .bind(session.updated_at)
+    .execute(db)
+    .await
+    .map_err(|e| e.to_string())?;
+
+    Ok(())
+}
+
+pub async fn remove(db: &SqlitePool, device: &str) -> Result<(), String> {
+    sqlx::query("DELETE FROM sessions WHERE device = ?")
+        .bind(device)
+        .execute(db)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct SessionSummary {
+    device: String,
+    /// Whether this is the session of the device asking
+    current: bool,
+    updated_at: i64,
+    current_index: u32,
+    current_time: f32,
+    queue_length: usize,
+    now_playing: Option<QueueItem>,
+}
+
+async fn list_sessions(
+    State(state): State<AppState>,
+    Extension(Device(device)): Extension<Device>,
+) -> Json<Vec<SessionSummary>> {
+    let sessions = state.sessions.lock().await;
+
+    let mut summaries = sessions
+        .iter()
+        .map(|(d, s)| SessionSummary {
+            current: *d == device,
+            device: d.clone(),
+            updated_at: s.updated_at,
+            current_index: s.current_index,
+            current_time: s.current_time,
+            queue_length: s.queue.len(),
+            now_playing: s.queue.get(s.current_index as usize).cloned(),
+        })
+        .collect::<Vec<SessionSummary>>();
+    summaries.sort_by_key(|s| std::cmp::Reverse(s.updated_at));
+
+    Json(summaries)
+}
+
+async fn get_session(
+    State(state): State<AppState>,
+    Path(device): Path<String>,
+) -> ApiResult<Json<PlaylistSession>> {
+    state
+        .sessions
+        .lock()
+        .await
+        .get(&device)
+        .cloned()
+        .map(Json)
+        .ok_or((StatusCode::NOT_FOUND, "Session not found".to_string()))
+}
+
+async fn delete_session(
+    State(state): State<AppState>,
+    Path(device): Path<String>,
+) -> ApiResult<(StatusCode, &'static str)> {
+    let mut sessions = state.sessions.lock().await;
+    if sessions.remove(&device).is_none() {
+        return Err((StatusCode::NOT_FOUND, "Session not found".to_string()));
+    }
+
+    remove(&state.db, &device)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))?;
+
+    Ok((StatusCode::OK, "OK"))
+}
+
+#[derive(Deserialize)]
+struct TransferRequest {
+    from: String,
+    /// Defaults to the device asking
+    to: Option<String>,
+}
+
+/// Copy the queue and position of one device to another, replacing what it had
+async fn transfer_session(
+    State(state): State<AppState>,
+    Extension(Device(device)): Extension<Device>,
+    Json(body): Json<TransferRequest>,
+) -> ApiResult<Json<PlaylistSession>> {
+    let to = body.to.unwrap_or(device);
+    if !is_valid_device_id(&to) {
+        return Err((StatusCode::BAD_REQUEST, "Invalid device id".to_string()));
+    }
+
+    let mut sessions = state.sessions.lock().await;
+    let Some(session) = sessions.get(&body.from) else {
+        return Err((StatusCode::NOT_FOUND, "Session not found".to_string()));
+    };
+
+    let session = PlaylistSession {
+        updated_at: utils::unix_now(),
+        ..session.clone()
+    };
+    save(&state.db, &to, &session)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))?;
+
+    tracing::info!("Transferred session from {} to {}", body.from, to);
+    sessions.insert(to, session.clone());
+
+    Ok(Json(session))
+}
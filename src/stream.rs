@@ -0,0 +1,118 @@
+//! Library tracks transcoded on the fly for clients on a slow connection, `/m` serves the originals.
+//! Whole transcodes go into the temp cache, where least recently used eviction keeps the popular ones.
+
+use std::{
+    fs::Metadata,
+    hash::{DefaultHasher, Hash, Hasher},
+    process::Stdio,
+};
+
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Redirect, Response},
+};
+use serde::Deserialize;
+use tokio::{process::Command, sync::mpsc};
+
+use crate::{
+    audio_format::{self, AudioFormat},
+    library, temp_stream, AppState, MUSIC_DIR,
+};
+
+type ApiResult<T> = Result<T, (StatusCode, String)>;
+
+/// Small and supported by every modern browser
+const DEFAULT_FORMAT: AudioFormat = AudioFormat::Opus;
+
+#[derive(Deserialize)]
+pub struct StreamQuery {
+    format: Option<String>,
+    /// kbps
+    bitrate: Option<u32>,
+    /// Seconds into the track to start from
+    start: Option<f64>,
+}
+
+/// Seeds names of cached transcodes. Changing the file changes the names,
+/// stale transcodes then age out of the cache.
+pub fn file_hasher(filename: &str, meta: &Metadata) -> DefaultHasher {
+    let mut hasher = DefaultHasher::new();
+    (filename, meta.len(), meta.modified().ok()).hash(&mut hasher);
+    hasher
+}
+
+pub async fn stream(
+    State(state): State<AppState>,
+    Path(filename): Path<String>,
+    Query(query): Query<StreamQuery>,
+) -> ApiResult<Response> {
+    let bad_request = |e: &str| (StatusCode::BAD_REQUEST, e.to_string());
+
+    let format = match query.format.as_deref() {
+        None => DEFAULT_FORMAT,
+        Some(f) => AudioFormat::from_name(f, None).ok_or(bad_request("Unknown format"))?,
+    };
+    if let Some(bitrate) = query.bitrate {
+        audio_format::check_bitrate(bitrate).map_err(|e| bad_request(&e))?;
+    }
+    let start = query.start.unwrap_or(0.0);
+    if !start.is_finite() || start < 0.0 {
+        return Err(bad_request("Invalid start"));
+    }
+
+    transcode(state, filename, format, query.bitrate, start).await
+}
+
+/// Stream `filename` as `format`, from `start` seconds in
+pub async fn transcode(
+    state: AppState,
+    filename: String,
+    format: AudioFormat,
+    bitrate: Option<u32>,
+    start: f64,
+) -> ApiResult<Response> {
+    if library::track(&state, &filename)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))?
+        .is_none()
+    {
+        return Err((StatusCode::NOT_FOUND, format!("{filename} not found")));
+    }
+    let path = format!("{MUSIC_DIR}/{filename}");
+    let meta = std::fs::metadata(&path).map_err(|e| (StatusCode::NOT_FOUND, e.to_string()))?;
+
+    let mut hasher = file_hasher(&filename, &meta);
+    (format.extension(), bitrate).hash(&mut hasher);
+    let name = format!("transcode-{:016x}.{}", hasher.finish(), format.extension());
+
+    // `/td` handles ranges, so clients can seek within a cached transcode on their own
+    let whole = start == 0.0;
+    if whole && state.temp_cache.lookup(&name) {
+        return Ok(Redirect::temporary(&format!("/td/{name}")).into_response());
+    }
+
+    tracing::info!("Transcoding {} to {}", filename, format.extension());
+    let mut ffmpeg = Command::new("ffmpeg")
+        .args(["-v", "error", "-ss", &start.to_string(), "-i", &path, "-vn"])
+        .args(format.ffmpeg_args(bitrate))
+        .arg("pipe:1")
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| {
+            let message = format!("Failed to spawn ffmpeg: {e}");
+            tracing::error!("{}", message);
+            (StatusCode::INTERNAL_SERVER_ERROR, message)
+        })?;
+    let output = ffmpeg.stdout.take().expect("stdout is piped");
+
+    let (tx, rx) = mpsc::channel(16);
+    tokio::spawn(temp_stream::tee(
+        state, name, whole, None, ffmpeg, output, tx,
+    ));
+
+    temp_stream::respond(rx, format.content_type()).await
+}
@@ -0,0 +1,785 @@
+//! Subsonic REST API under `/rest`, so mobile clients like DSub, Symfonium or Feishin can be used.
+//! Credentials are not checked, like everywhere else on this server.
+//!
+//! Albums are grouped by album artist and album tags, tracks without an album tag end up in
+//! an "Unknown Album" of their artist. Ids are hex so any name survives a round trip.
+
+use std::{collections::BTreeMap, fmt::Write, future::Future};
+
+use axum::{
+    body::Body,
+    extract::{Query, Request, State},
+    http::header,
+    response::{IntoResponse, Response},
+    routing::{get, MethodRouter},
+    Json, Router,
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tower::ServiceExt;
+use tower_http::services::ServeFile;
+
+use crate::{
+    audio_format::AudioFormat, history, library, playlists, stream, utils, AppState, Track,
+    MUSIC_DIR,
+};
+
+const API_VERSION: &str = "1.16.1";
+/// What Subsonic itself transcodes to when the client doesn't say
+const TRANSCODE_FORMAT: AudioFormat = AudioFormat::Mp3 { bitrate: None };
+const UNKNOWN_ALBUM: &str = "Unknown Album";
+/// Pixels, larger `size`s for cover art are capped to it
+const MAX_COVER_SIZE: u32 = 2048;
+
+// Error codes defined by the API
+const ERROR_GENERIC: u32 = 0;
+const ERROR_MISSING_PARAMETER: u32 = 10;
+const ERROR_NOT_FOUND: u32 = 70;
+
+type Reply = Result<Value, (u32, String)>;
+
+pub fn router() -> Router<AppState> {
+    let endpoints: [(&str, MethodRouter<AppState>); 14] = [
+        ("ping", endpoint(ping)),
+        ("getLicense", endpoint(get_license)),
+        ("getMusicFolders", endpoint(get_music_folders)),
+        ("getIndexes", endpoint(get_indexes)),
+        ("getArtists", endpoint(get_artists)),
+        ("getArtist", endpoint(get_artist)),
+        ("getAlbum", endpoint(get_album)),
+        ("search3", endpoint(search3)),
+        ("stream", get(stream_song).post(stream_song)),
+        ("getCoverArt", get(get_cover_art).post(get_cover_art)),
+        ("getPlaylists", endpoint(get_playlists)),
+        ("getPlaylist", endpoint(get_playlist)),
+        ("scrobble", endpoint(scrobble)),
+        ("getUser", endpoint(get_user)),
+    ];
+
+    // Older clients add `.view` to every endpoint
+    endpoints
+        .into_iter()
+        .fold(Router::new(), |router, (name, endpoint)| {
+            router
+                .route(&format!("/{name}"), endpoint.clone())
+                .route(&format!("/{name}.view"), endpoint)
+        })
+        .fallback(not_implemented)
+}
+
+/// Query parameters, some like `id` may be repeated
+#[derive(Deserialize)]
+#[serde(transparent)]
+struct Params(Vec<(String, String)>);
+
+impl Params {
+    fn get(&self, name: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> {
+        self.0
+            .iter()
+            .filter(move |(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn number<T: std::str::FromStr>(&self, name: &str) -> Option<T> {
+        self.get(name).and_then(|v| v.parse().ok())
+    }
+
+    fn required(&self, name: &str) -> Result<&str, (u32, String)> {
+        self.get(name).ok_or((
+            ERROR_MISSING_PARAMETER,
+            format!("Required parameter is missing: {name}"),
+        ))
+    }
+
+    /// XML unless asked otherwise
+    fn json(&self) -> bool {
+        self.get("f") == Some("json")
+    }
+}
+
+fn generic(e: String) -> (u32, String) {
+    (ERROR_GENERIC, e)
+}
+
+fn not_found(what: &str) -> (u32, String) {
+    (ERROR_NOT_FOUND, format!("{what} not found"))
+}
+
+/// Wrap a handler producing the payload into one rendering the whole response
+fn endpoint<F, Fut>(handler: F) -> MethodRouter<AppState>
+where
+    F: Fn(AppState, Params) -> Fut + Clone + Send + Sync + 'static,
+    Fut: Future<Output = Reply> + Send + 'static,
+{
+    let handler = move |State(state): State<AppState>, Query(params): Query<Params>| async move {
+        let json = params.json();
+        render(json, handler(state, params).await)
+    };
+
+    get(handler.clone()).post(handler)
+}
+
+fn render(json: bool, reply: Reply) -> Response {
+    let mut response = json!({
+        "status": "ok",
+        "version": API_VERSION,
+        "type": env!("CARGO_PKG_NAME"),
+        "serverVersion": env!("CARGO_PKG_VERSION"),
+        "openSubsonic": true,
+    });
+    match reply {
+        Ok(Value::Object(payload)) => {
+            if let Value::Object(response) = &mut response {
+                response.extend(payload);
+            }
+        }
+        Ok(_) => {}
+        Err((code, message)) => {
+            response["status"] = json!("failed");
+            response["error"] = json!({ "code": code, "message": message });
+        }
+    }
+    strip_nulls(&mut response);
+
+    if json {
+        return Json(json!({ "subsonic-response": response })).into_response();
+    }
+
+    let mut xml = String::from(r#"<?xml version="1.0" encoding="UTF-8"?>"#);
+    write_xml(
+        &mut xml,
+        "subsonic-response",
+        &response,
+        Some("http://subsonic.org/restapi"),
+    );
+    ([(header::CONTENT_TYPE, "text/xml; charset=utf-8")], xml).into_response()
+}
+
+fn strip_nulls(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            map.retain(|_, v| !v.is_null());
+            map.values_mut().for_each(strip_nulls);
+        }
+        Value::Array(items) => items.iter_mut().for_each(strip_nulls),
+        _ => {}
+    }
+}
+
+/// Scalars become attributes, objects and arrays child elements, scalars in arrays with text content.
+/// The API's JSON is derived from its XML the other way around.
+fn write_xml(out: &mut String, name: &str, value: &Value, namespace: Option<&str>) {
+    let Value::Object(map) = value else {
+        return;
+    };
+
+    _ = write!(out, "<{name}");
+    if let Some(namespace) = namespace {
+        _ = write!(out, r#" xmlns="{namespace}""#);
+    }
+    for (key, value) in map {
+        let text = match value {
+            Value::String(s) => s.clone(),
+            Value::Number(n) => n.to_string(),
+            Value::Bool(b) => b.to_string(),
+            _ => continue,
+        };
+        _ = write!(
+            out,
+            r#" {key}="{}""#,
+            html_escape::encode_double_quoted_attribute(&text)
+        );
+    }
+
+    let children = map
+        .iter()
+        .filter(|(_, v)| v.is_object() || v.is_array())
+        .collect::<Vec<(&String, &Value)>>();
+    if children.is_empty() {
+        out.push_str("/>");
+        return;
+    }
+
+    out.push('>');
+    for (key, value) in children {
+        match value {
+            Value::Array(items) => {
+                for item in items {
+                    match item {
+                        Value::Object(_) => write_xml(out, key, item, None),
+                        // Like `<folder>1</folder>`
+                        Value::String(s) => {
+                            _ = write!(out, "<{key}>{}</{key}>", html_escape::encode_text(s))
+                        }
+                        Value::Number(_) | Value::Bool(_) => {
+                            _ = write!(out, "<{key}>{item}</{key}>")
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            _ => write_xml(out, key, value, None),
+        }
+    }
+    _ = write!(out, "</{name}>");
+}
+
+fn encode_id(kind: &str, name: &str) -> String {
+    let mut id = format!("{kind}-");
+    for byte in name.bytes() {
+        _ = write!(id, "{byte:02x}");
+    }
+    id
+}
+
+fn decode_id(id: &str, kind: &str) -> Option<String> {
+    let hex = id.strip_prefix(kind)?.strip_prefix('-')?;
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+
+    let bytes = (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+    String::from_utf8(bytes).ok()
+}
+
+fn album_artist(track: &Track) -> &str {
+    track.album_artist.as_deref().unwrap_or(&track.artist)
+}
+
+fn album_name(track: &Track) -> &str {
+    track.album.as_deref().unwrap_or(UNKNOWN_ALBUM)
+}
+
+fn album_id(artist: &str, album: &str) -> String {
+    encode_id("al", &format!("{artist}\u{1f}{album}"))
+}
+
+/// Album artist encoded in album id `id`
+fn artist_of_album(id: &str) -> Option<String> {
+    decode_id(id, "al")?
+        .split_once('\u{1f}')
+        .map(|(artist, _)| artist.to_string())
+}
+
+fn artist_id(artist: &str) -> String {
+    encode_id("ar", artist)
+}
+
+fn song_id(track: &Track) -> String {
+    encode_id("tr", &track.filename)
+}
+
+fn content_type(filename: &str) -> &'static str {
+    match utils::extension(filename).to_ascii_lowercase().as_str() {
+        "mp3" => "audio/mpeg",
+        "mp4" | "m4a" => "audio/mp4",
+        "flac" => "audio/flac",
+        "ogg" | "oga" | "opus" => "audio/ogg",
+        "wav" => "audio/wav",
+        _ => "application/octet-stream",
+    }
+}
+
+struct Album {
+    id: String,
+    name: String,
+    artist: String,
+    /// Unix timestamp of the newest file
+    created: i64,
+    songs: Vec<Track>,
+}
+
+/// Albums of `artist`, or of the whole library, sorted by artist then name.
+/// Lookups of a single album or artist ask for theirs so they don't load everything.
+async fn albums(state: &AppState, artist: Option<&str>) -> Result<Vec<Album>, (u32, String)> {
+    let mtimes: Vec<(String, i64)> = sqlx::query_as(
+        "SELECT filename, mtime FROM library WHERE ?1 IS NULL OR COALESCE(album_artist, artist) = ?1",
+    )
+    .bind(artist)
+    .fetch_all(&state.db)
+    .await
+    .map_err(|e| generic(e.to_string()))?;
+    let mtimes = mtimes.into_iter().collect::<BTreeMap<String, i64>>();
+
+    let tracks = match artist {
+        Some(artist) => library::tracks_by_album_artist(state, artist).await,
+        None => library::tracks(state).await,
+    };
+
+    let mut albums: BTreeMap<(String, String), Album> = BTreeMap::new();
+    for track in tracks.map_err(generic)? {
+        let (artist, name) = (album_artist(&track), album_name(&track));
+        let album = albums
+            .entry((artist.to_string(), name.to_string()))
+            .or_insert_with(|| Album {
+                id: album_id(artist, name),
+                name: name.to_string(),
+                artist: artist.to_string(),
+                created: 0,
+                songs: vec![],
+            });
+
+        album.created = album
+            .created
+            .max(mtimes.get(&track.filename).copied().unwrap_or(0));
+        album.songs.push(track);
+    }
+
+    let mut albums = albums.into_values().collect::<Vec<Album>>();
+    for album in &mut albums {
+        album.songs.sort_by_cached_key(|t| {
+            (
+                t.disc_number.unwrap_or(1),
+                t.track_number.unwrap_or(u32::MAX),
+                t.title.to_lowercase(),
+            )
+        });
+    }
+    albums.sort_by_cached_key(|a| (a.artist.to_lowercase(), a.name.to_lowercase()));
+
+    Ok(albums)
+}
+
+fn by_artist(albums: &[Album]) -> Vec<(&str, Vec<&Album>)> {
+    let mut artists: Vec<(&str, Vec<&Album>)> = vec![];
+    // Albums are sorted by artist already
+    for album in albums {
+        match artists.last_mut() {
+            Some((artist, albums)) if *artist == album.artist => albums.push(album),
+            _ => artists.push((&album.artist, vec![album])),
+        }
+    }
+    artists
+}
+
+fn song_json(track: &Track) -> Value {
+    let id = song_id(track);
+    let (artist, album) = (album_artist(track), album_name(track));
+
+    json!({
+        "id": id,
+        "parent": album_id(artist, album),
+        "isDir": false,
+        "title": track.title,
+        "album": album,
+        "artist": track.artist,
+        "track": track.track_number,
+        "discNumber": track.disc_number,
+        "year": track.year,
+        "genre": track.genre,
+        "coverArt": track.thumbnail.as_ref().map(|_| &id),
+        "contentType": content_type(&track.filename),
+        "suffix": utils::extension(&track.filename),
+        "duration": track.duration,
+        // kbps
+        "bitRate": track.bitrate.map(|b| b / 1000),
+        "path": track.filename,
+        "type": "music",
+        "isVideo": false,
+        "albumId": album_id(artist, album),
+        "artistId": artist_id(artist),
+        "playCount": track.play_count,
+    })
+}
+
+fn album_json(album: &Album) -> Value {
+    let has_cover = album.songs.iter().any(|s| s.thumbnail.is_some());
+
+    json!({
+        "id": album.id,
+        "name": album.name,
+        "artist": album.artist,
+        "artistId": artist_id(&album.artist),
+        "coverArt": has_cover.then_some(&album.id),
+        "songCount": album.songs.len(),
+        "duration": album.songs.iter().filter_map(|s| s.duration).sum::<u64>(),
+        "playCount": album.songs.iter().filter_map(|s| s.play_count).sum::<u32>(),
+        "created": utils::iso8601(album.created),
+        "year": album.songs.iter().find_map(|s| s.year),
+        "genre": album.songs.iter().find_map(|s| s.genre.as_deref()),
+    })
+}
+
+fn artist_json(name: &str, albums: &[&Album]) -> Value {
+    let id = artist_id(name);
+    let has_cover = albums
+        .iter()
+        .any(|a| a.songs.iter().any(|s| s.thumbnail.is_some()));
+
+    json!({
+        "id": id,
+        "name": name,
+        "albumCount": albums.len(),
+        "coverArt": has_cover.then_some(&id),
+    })
+}
+
+/// Artists grouped by their first letter
+fn index(albums: &[Album]) -> Vec<Value> {
+    let mut index: BTreeMap<String, Vec<Value>> = BTreeMap::new();
+    for (artist, albums) in by_artist(albums) {
+        let letter = artist
+            .chars()
+            .next()
+            .filter(|c| c.is_alphabetic())
+            .map(|c| c.to_uppercase().to_string())
+            .unwrap_or_else(|| "#".to_string());
+        index
+            .entry(letter)
+            .or_default()
+            .push(artist_json(artist, &albums));
+    }
+
+    index
+        .into_iter()
+        .map(|(name, artists)| json!({ "name": name, "artist": artists }))
+        .collect()
+}
+
+async fn ping(_: AppState, _: Params) -> Reply {
+    Ok(json!({}))
+}
+
+async fn get_license(_: AppState, _: Params) -> Reply {
+    Ok(json!({ "license": { "valid": true } }))
+}
+
+async fn get_user(_: AppState, params: Params) -> Reply {
+    Ok(json!({
+        "user": {
+            "username": params.get("u").unwrap_or("admin"),
+            "scrobblingEnabled": true,
+            "adminRole": true,
+            "settingsRole": true,
+            "downloadRole": true,
+            "uploadRole": false,
+            "playlistRole": true,
+            "coverArtRole": true,
+            "commentRole": false,
+            "podcastRole": false,
+            "streamRole": true,
+            "jukeboxRole": false,
+            "shareRole": false,
+            "folder": [1],
+        }
+    }))
+}
+
+async fn get_music_folders(_: AppState, _: Params) -> Reply {
+    Ok(json!({
+        "musicFolders": { "musicFolder": [{ "id": 1, "name": "Music" }] }
+    }))
+}
+
+async fn get_indexes(state: AppState, _: Params) -> Reply {
+    let albums = albums(&state, None).await?;
+    let last_modified = albums.iter().map(|a| a.created).max().unwrap_or(0);
+
+    Ok(json!({
+        "indexes": {
+            "lastModified": last_modified * 1000,
+            "ignoredArticles": "",
+            "index": index(&albums),
+        }
+    }))
+}
+
+async fn get_artists(state: AppState, _: Params) -> Reply {
+    let albums = albums(&state, None).await?;
+
+    Ok(json!({
+        "artists": { "ignoredArticles": "", "index": index(&albums) }
+    }))
+}
+
+async fn get_artist(state: AppState, params: Params) -> Reply {
+    let name = decode_id(params.required("id")?, "ar").ok_or_else(|| not_found("Artist"))?;
+    let albums = albums(&state, Some(&name)).await?;
+    let albums = albums.iter().collect::<Vec<&Album>>();
+    if albums.is_empty() {
+        return Err(not_found("Artist"));
+    }
+
+    let mut artist = artist_json(&name, &albums);
+    artist["album"] = albums.iter().map(|a| album_json(a)).collect();
+
+    Ok(json!({ "artist": artist }))
+}
+
+async fn get_album(state: AppState, params: Params) -> Reply {
+    let id = params.required("id")?;
+    let artist = artist_of_album(id).ok_or_else(|| not_found("Album"))?;
+    let albums = albums(&state, Some(&artist)).await?;
+    let album = albums
+        .iter()
+        .find(|a| a.id == id)
+        .ok_or_else(|| not_found("Album"))?;
+
+    let mut json = album_json(album);
+    json["song"] = album.songs.iter().map(song_json).collect();
+
+    Ok(json!({ "album": json }))
+}
+
+async fn search3(state: AppState, params: Params) -> Reply {
+    // Some clients search for `""` to list everything
+    let query = params
+        .get("query")
+        .unwrap_or_default()
+        .trim_matches('"')
+        .to_lowercase();
+    let matches = |s: &str| s.to_lowercase().contains(&query);
+    let page = |kind: &str| {
+        (
+            params.number(&format!("{kind}Offset")).unwrap_or(0),
+            params.number(&format!("{kind}Count")).unwrap_or(20),
+        )
+    };
+
+    let albums = albums(&state, None).await?;
+
+    let (offset, count) = page("artist");
+    let artists = by_artist(&albums)
+        .into_iter()
+        .filter(|(name, _)| matches(name))
+        .skip(offset)
+        .take(count)
+        .map(|(name, albums)| artist_json(name, &albums))
+        .collect::<Vec<Value>>();
+
+    let (offset, count) = page("album");
+    let found = albums
+        .iter()
+        .filter(|a| matches(&a.name) || matches(&a.artist))
+        .skip(offset)
+        .take(count)
+        .map(album_json)
+        .collect::<Vec<Value>>();
+
+    let (offset, count) = page("song");
+    let songs = albums
+        .iter()
+        .flat_map(|a| &a.songs)
+        .filter(|s| matches(&s.title) || matches(&s.artist) || matches(album_name(s)))
+        .skip(offset)
+        .take(count)
+        .map(song_json)
+        .collect::<Vec<Value>>();
+
+    Ok(json!({
+        "searchResult3": { "artist": artists, "album": found, "song": songs }
+    }))
+}
+
+async fn song(state: &AppState, id: &str) -> Result<Track, (u32, String)> {
+    let filename = decode_id(id, "tr").ok_or_else(|| not_found("Song"))?;
+    library::track(state, &filename)
+        .await
+        .map_err(generic)?
+        .ok_or_else(|| not_found("Song"))
+}
+
+/// The original file unless the client asks for another format, a lower bitrate or an offset
+async fn stream_song(
+    State(state): State<AppState>,
+    Query(params): Query<Params>,
+    request: Request,
+) -> Response {
+    let track = match params.required("id") {
+        Ok(id) => song(&state, id).await,
+        Err(e) => Err(e),
+    };
+    let track = match track {
+        Ok(t) => t,
+        Err(e) => return render(params.json(), Err(e)),
+    };
+
+    let max_bitrate = params.number::<u32>("maxBitRate").filter(|&b| b > 0);
+    let start = params.number::<f64>("timeOffset").filter(|&s| s > 0.0);
+    let format = params
+        .get("format")
+        .and_then(|f| AudioFormat::from_name(f, None));
+    let too_big = match (max_bitrate, track.bitrate) {
+        (Some(max), Some(bitrate)) => bitrate / 1000 > max,
+        (Some(_), None) => true,
+        (None, _) => false,
+    };
+
+    if params.get("format") == Some("raw") || (format.is_none() && start.is_none() && !too_big) {
+        // Handles ranges
+        let path = format!("{MUSIC_DIR}/{}", track.filename);
+        return match ServeFile::new(path).oneshot(request).await {
+            Ok(response) => response.map(Body::new),
+            Err(e) => match e {},
+        };
+    }
+
+    let format = format.unwrap_or(TRANSCODE_FORMAT);
+    let bitrate = max_bitrate.map(|b| b.clamp(32, 320));
+    match stream::transcode(state, track.filename, format, bitrate, start.unwrap_or(0.0)).await {
+        Ok(response) => response,
+        Err((_, e)) => render(params.json(), Err(generic(e))),
+    }
+}
+
+/// Path of the cover for a song, album or artist id
+async fn cover_path(state: &AppState, id: &str) -> Result<String, (u32, String)> {
+    let artist = artist_of_album(id).or_else(|| decode_id(id, "ar"));
+    let cover = match artist {
+        Some(artist) => albums(state, Some(&artist))
+            .await?
+            .into_iter()
+            .filter(|a| a.id == id || artist_id(&a.artist) == id)
+            .flat_map(|a| a.songs)
+            .find_map(|s| s.thumbnail),
+        None => song(state, id).await?.thumbnail,
+    };
+
+    // Covers are extracted to `/img/...`
+    cover
+        .map(|c| c.trim_start_matches('/').to_string())
+        .ok_or_else(|| not_found("Cover art"))
+}
+
+/// Covers are only ever shrunk, asking for a bigger one gets the original
+fn resize(data: Vec<u8>, size: u32) -> Result<Vec<u8>, String> {
+    let img = image::load_from_memory(&data).map_err(|e| e.to_string())?;
+    if size >= img.width().max(img.height()) {
+        return Ok(data);
+    }
+
+    let mut buffer = Vec::new();
+    img.thumbnail(size, size)
+        .into_rgb8()
+        .write_to(
+            &mut std::io::Cursor::new(&mut buffer),
+            image::ImageFormat::Jpeg,
+        )
+        .map_err(|e| e.to_string())?;
+
+    Ok(buffer)
+}
+
+async fn get_cover_art(State(state): State<AppState>, Query(params): Query<Params>) -> Response {
+    let path = match params.required("id") {
+        Ok(id) => cover_path(&state, id).await,
+        Err(e) => Err(e),
+    };
+    let data = match path {
+        Ok(path) => tokio::fs::read(&path)
+            .await
+            .map_err(|_| not_found("Cover art")),
+        Err(e) => Err(e),
+    };
+    let data = match (data, params.number::<u32>("size")) {
+        (Ok(data), Some(size)) => {
+            let size = size.clamp(1, MAX_COVER_SIZE);
+            tokio::task::spawn_blocking(move || resize(data, size))
+                .await
+                .map_err(|e| e.to_string())
+                .and_then(|resized| resized)
+                .map_err(generic)
+        }
+        (data, _) => data,
+    };
+
+    match data {
+        Ok(data) => ([(header::CONTENT_TYPE, "image/jpeg")], data).into_response(),
+        Err(e) => render(params.json(), Err(e)),
+    }
+}
+
+/// Local songs of playlist `id`, YouTube entries can't be played through the API
+async fn playlist(
+    state: &AppState,
+    id: i64,
+) -> Result<(playlists::Playlist, Vec<Track>), (u32, String)> {
+    let playlist = playlists::load(state, id)
+        .await
+        .map_err(|_| not_found("Playlist"))?;
+
+    let mut songs = vec![];
+    for entry in playlist
+        .items
+        .iter()
+        .filter(|e| e.item.url.starts_with("/m/"))
+    {
+        if let Some(track) = library::track(state, &entry.item.filename)
+            .await
+            .map_err(generic)?
+        {
+            songs.push(track);
+        }
+    }
+
+    Ok((playlist, songs))
+}
+
+fn playlist_json(playlist: &playlists::Playlist, songs: &[Track], owner: &str) -> Value {
+    json!({
+        "id": playlist.id.to_string(),
+        "name": playlist.name,
+        "owner": owner,
+        "public": false,
+        "songCount": songs.len(),
+        "duration": songs.iter().filter_map(|s| s.duration).sum::<u64>(),
+        "created": utils::iso8601(playlist.created_at),
+        "changed": utils::iso8601(playlist.updated_at),
+        "coverArt": songs.iter().find(|s| s.thumbnail.is_some()).map(song_id),
+    })
+}
+
+async fn get_playlists(state: AppState, params: Params) -> Reply {
+    let owner = params.get("u").unwrap_or("admin");
+
+    let mut list = vec![];
+    for summary in playlists::list(&state).await.map_err(|(_, e)| generic(e))? {
+        let (playlist, songs) = playlist(&state, summary.id).await?;
+        list.push(playlist_json(&playlist, &songs, owner));
+    }
+
+    Ok(json!({ "playlists": { "playlist": list } }))
+}
+
+async fn get_playlist(state: AppState, params: Params) -> Reply {
+    let id = params
+        .required("id")?
+        .parse()
+        .map_err(|_| not_found("Playlist"))?;
+    let (playlist, songs) = playlist(&state, id).await?;
+
+    let mut json = playlist_json(&playlist, &songs, params.get("u").unwrap_or("admin"));
+    json["entry"] = songs.iter().map(song_json).collect();
+
+    Ok(json!({ "playlist": json }))
+}
+
+async fn scrobble(state: AppState, params: Params) -> Reply {
+    // Only "now playing", nothing to record
+    if params.get("submission") == Some("false") {
+        return Ok(json!({}));
+    }
+
+    for id in params.all("id") {
+        let track = song(&state, id).await?;
+        history::record(&state, &track, None)
+            .await
+            .map_err(generic)?;
+    }
+
+    Ok(json!({}))
+}
+
+async fn not_implemented(Query(params): Query<Params>) -> Response {
+    render(params.json(), Err(generic("Not implemented".to_string())))
+}
@@ -0,0 +1,165 @@
+use audiotags::{AudioTag, Picture};
+
+use crate::{ogg::OggTag, utils, AppState};
+
+/// Either an audiotags tag or Vorbis comments from an Ogg container
+pub enum Tag {
+    Audiotags(Box<dyn AudioTag + Send + Sync>),
+    Ogg(OggTag),
+}
+
+pub fn is_supported(filename: &str) -> bool {
+    matches!(
+        utils::extension(filename).to_ascii_lowercase().as_str(),
+        "mp3" | "wav" | "mp4" | "m4a" | "flac" | "ogg" | "oga" | "opus"
+    )
+}
+
+pub fn read(state: &AppState, path: &str) -> Result<Tag, String> {
+    let reader = match utils::extension(path).to_ascii_lowercase().as_str() {
+        // id3 finds the ID3 chunk inside RIFF on its own
+        "mp3" | "wav" => state.mp3_reader.clone(),
+        "mp4" | "m4a" => state.mp4_reader.clone(),
+        "flac" => state.flac_reader.clone(),
+        "ogg" | "oga" | "opus" => return OggTag::read_from_path(path).map(Tag::Ogg),
+        _ => return Err("Unrecognize format".to_string()),
+    };
+
+    reader
+        .read_from_path(path)
+        .map(Tag::Audiotags)
+        .map_err(|e| e.to_string())
+}
+
+/// Like [`read`], but gives mp3 and WAV files without an ID3 tag an empty one first,
+/// as yt-dlp only writes one when asked to embed metadata
+pub fn read_or_create(state: &AppState, path: &str) -> Result<Tag, String> {
+    // id3 tells WAV apart by its header and uses the ID3 chunk inside RIFF
+    let ext = utils::extension(path).to_ascii_lowercase();
+    if matches!(ext.as_str(), "mp3" | "wav")
+        && matches!(id3::Tag::read_from_path(path), Err(e) if matches!(e.kind, id3::ErrorKind::NoTag))
+    {
+        id3::Tag::new()
+            .write_to_path(path, id3::Version::Id3v24)
+            .map_err(|e| e.to_string())?;
+    }
+
+    read(state, path)
+}
+
+impl Tag {
+    pub fn set_title(&mut self, title: &str) {
+        match self {
+            Self::Audiotags(t) => t.set_title(title),
+            Self::Ogg(t) => t.set_title(title),
+        }
+    }
+
+    pub fn artist(&self) -> Option<&str> {
+        match self {
+            Self::Audiotags(t) => t.artist(),
+            Self::Ogg(t) => t.artist(),
+        }
+    }
+
+    pub fn set_artist(&mut self, artist: &str) {
+        match self {
+            Self::Audiotags(t) => t.set_artist(artist),
+            Self::Ogg(t) => t.set_artist(artist),
+        }
+    }
+
+    pub fn album_title(&self) -> Option<&str> {
+        match self {
+            Self::Audiotags(t) => t.album_title(),
+            Self::Ogg(t) => t.album_title(),
+        }
+    }
+
+    pub fn album_artist(&self) -> Option<&str> {
+        match self {
+            Self::Audiotags(t) => t.album_artist(),
+            Self::Ogg(t) => t.album_artist(),
+        }
+    }
+
+    pub fn track_number(&self) -> Option<u16> {
+        match self {
+            Self::Audiotags(t) => t.track_number(),
+            Self::Ogg(t) => t.track_number(),
+        }
+    }
+
+    pub fn disc_number(&self) -> Option<u16> {
+        match self {
+            Self::Audiotags(t) => t.disc_number(),
+            Self::Ogg(t) => t.disc_number(),
+        }
+    }
+
+    pub fn year(&self) -> Option<i32> {
+        match self {
+            Self::Audiotags(t) => t.year(),
+            Self::Ogg(t) => t.year(),
+        }
+    }
+
+    pub fn genre(&self) -> Option<&str> {
+        match self {
+            Self::Audiotags(t) => t.genre(),
+            Self::Ogg(t) => t.genre(),
+        }
+    }
+
+    pub fn comment(&self) -> Option<&str> {
+        match self {
+            Self::Audiotags(t) => t.comment(),
+            Self::Ogg(t) => t.comment(),
+        }
+    }
+
+    pub fn set_comment(&mut self, comment: &str) {
+        match self {
+            Self::Audiotags(t) => t.set_comment(comment.to_string()),
+            Self::Ogg(t) => t.set_comment(comment),
+        }
+    }
+
+    /// YouTube id of the video the file was downloaded from.
+    /// yt-dlp stores the video URL as the comment, so that's where it's kept.
+    pub fn source_id(&self) -> Option<String> {
+        self.comment().and_then(utils::youtube_id)
+    }
+
+    pub fn set_source_id(&mut self, id: &str) {
+        self.set_comment(&utils::youtube_url(id));
+    }
+
+    pub fn duration(&self) -> Option<f64> {
+        match self {
+            Self::Audiotags(t) => t.duration(),
+            Self::Ogg(t) => t.duration(),
+        }
+    }
+
+    pub fn album_cover(&self) -> Option<Picture<'_>> {
+        match self {
+            Self::Audiotags(t) => t.album_cover(),
+            Self::Ogg(t) => t.album_cover(),
+        }
+    }
+
+    pub fn set_album_cover(&mut self, cover: Picture) {
+        match self {
+            Self::Audiotags(t) => t.set_album_cover(cover),
+            Self::Ogg(t) => t.set_album_cover(cover),
+        }
+    }
+
+    pub fn write_to_path(&mut self, path: &str) -> Result<(), String> {
+        match self {
+            Self::Audiotags(t) => t.write_to_path(path).map_err(|e| e.to_string()),
+            Self::Ogg(t) => t.write_to_path(path),
+        }
+    }
+}
@@ -0,0 +1,281 @@
+//! `TEMP_DIR` as a cache: files are evicted once unused for [`MAX_AGE`] or, least recently
+//! used first, when the directory outgrows [`MAX_BYTES`]. Files being served or still being
+//! written are never evicted.
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, UNIX_EPOCH},
+};
+
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    middleware::Next,
+    response::Response,
+    Json,
+};
+use serde::Serialize;
+use tokio_stream::StreamExt;
+
+use crate::{utils, AppState, TEMP_DIR};
+
+/// 2 GiB
+pub const MAX_BYTES: u64 = 2 * 1024 * 1024 * 1024;
+/// Seconds since last access, a day
+pub const MAX_AGE: i64 = 24 * 3600;
+
+const SWEEP_INTERVAL: Duration = Duration::from_secs(300);
+
+struct Entry {
+    size: u64,
+    /// Unix timestamp
+    last_access: i64,
+    /// Responses currently streaming this file
+    readers: usize,
+}
+
+pub struct TempCache {
+    /// Keyed by file name inside `TEMP_DIR`
+    entries: Mutex<HashMap<String, Entry>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+}
+
+/// Keeps a file from being evicted until dropped
+pub struct Serving {
+    cache: Arc<TempCache>,
+    /// None if the file wasn't there to begin with
+    name: Option<String>,
+}
+
+impl Drop for Serving {
+    fn drop(&mut self) {
+        let Some(name) = &self.name else {
+            return;
+        };
+
+        let mut entries = self.cache.entries.lock().unwrap();
+        if let Some(entry) = entries.get_mut(name) {
+            entry.readers = entry.readers.saturating_sub(1);
+            entry.last_access = utils::unix_now();
+        }
+    }
+}
+
+impl TempCache {
+    pub fn new() -> Self {
+        Self {
+            entries: Mutex::new(HashMap::new()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+        }
+    }
+
+    /// Whether `name` is already cached, counted as a hit or a miss
+    pub fn lookup(&self, name: &str) -> bool {
+        let cached = std::path::Path::new(&format!("{TEMP_DIR}/{name}")).exists();
+        if cached {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            self.touch(name);
+        } else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+        }
+
+        cached
+    }
+
+    /// Mark `name` as just used, picking up its size if it was just written
+    pub fn touch(&self, name: &str) {
+        let Ok(meta) = std::fs::metadata(format!("{TEMP_DIR}/{name}")) else {
+            return;
+        };
+
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.entry(name.to_string()).or_insert(Entry {
+            size: 0,
+            last_access: 0,
+            readers: 0,
+        });
+        entry.size = meta.len();
+        entry.last_access = utils::unix_now();
+    }
+
+    pub fn serve(self: &Arc<Self>, name: &str) -> Serving {
+        self.touch(name);
+
+        let mut entries = self.entries.lock().unwrap();
+        let name = entries.get_mut(name).map(|entry| {
+            entry.readers += 1;
+            name.to_string()
+        });
+
+        Serving {
+            cache: self.clone(),
+            name,
+        }
+    }
+
+    /// Sync with what is on disk, then evict expired files and the least recently used
+    /// ones until the cache fits in [`MAX_BYTES`]. Blocks on the file system.
+    pub fn sweep(&self) {
+        let dir = match std::fs::read_dir(TEMP_DIR) {
+            Ok(dir) => dir,
+            Err(e) => {
+                tracing::warn!("Cannot read {}: {}", TEMP_DIR, e);
+                return;
+            }
+        };
+
+        let mut on_disk = HashMap::new();
+        for file in dir.flatten() {
+            let Ok(meta) = file.metadata() else {
+                continue;
+            };
+            let Ok(name) = file.file_name().into_string() else {
+                continue;
+            };
+            if !meta.is_file() {
+                continue;
+            }
+
+            let mtime = meta
+                .modified()
+                .ok()
+                .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+                .map(|d| d.as_secs() as i64)
+                .unwrap_or(0);
+            // Still being written, unless left behind long ago by something that died
+            if in_progress(&name) && utils::unix_now() - mtime <= MAX_AGE {
+                continue;
+            }
+            on_disk.insert(name, (meta.len(), mtime));
+        }
+
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|name, _| on_disk.contains_key(name));
+        for (name, (size, mtime)) in on_disk {
+            // Files written by someone else, or before a restart, were last used when written
+            let entry = entries.entry(name).or_insert(Entry {
+                size,
+                last_access: mtime,
+                readers: 0,
+            });
+            entry.size = size;
+            entry.last_access = entry.last_access.max(mtime);
+        }
+
+        let now = utils::unix_now();
+        let mut idle = entries
+            .iter()
+            .filter(|(_, e)| e.readers == 0)
+            .map(|(name, e)| (e.last_access, e.size, name.clone()))
+            .collect::<Vec<(i64, u64, String)>>();
+        idle.sort();
+
+        let mut total: u64 = entries.values().map(|e| e.size).sum();
+        let mut evict = Vec::new();
+        for (last_access, size, name) in idle {
+            if now - last_access <= MAX_AGE && total <= MAX_BYTES {
+                break;
+            }
+            total -= size;
+            evict.push(name);
+        }
+        drop(entries);
+
+        for name in evict {
+            // Forgotten first, without holding the lock while removing
+            {
+                let mut entries = self.entries.lock().unwrap();
+                if !matches!(entries.get(&name), Some(e) if e.readers == 0) {
+                    continue;
+                }
+                entries.remove(&name);
+            }
+
+            match std::fs::remove_file(format!("{TEMP_DIR}/{name}")) {
+                Ok(_) => {
+                    tracing::debug!("Evicted {} from temp cache", name);
+                    self.evictions.fetch_add(1, Ordering::Relaxed);
+                }
+                // Picked up again by the next sweep if it is still there
+                Err(e) => tracing::warn!("Cannot evict {}: {}", name, e),
+            }
+        }
+    }
+}
+
+/// Partial downloads and transcodes, ours end in `.part` and so do yt-dlp's
+/// along with its fragments and `.ytdl` state
+fn in_progress(name: &str) -> bool {
+    name.ends_with(".part") || name.contains(".part-Frag") || name.ends_with(".ytdl")
+}
+
+pub async fn run(cache: Arc<TempCache>) {
+    let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        let cache = cache.clone();
+        if let Err(e) = tokio::task::spawn_blocking(move || cache.sweep()).await {
+            tracing::error!("Temp cache sweep failed: {}", e);
+        }
+    }
+}
+
+/// Middleware for `/td`, holds on to the file until its response body is done
+pub async fn track_serving(
+    State(cache): State<Arc<TempCache>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let name = percent_encoding::percent_decode_str(request.uri().path().trim_start_matches('/'))
+        .decode_utf8_lossy()
+        .into_owned();
+    let serving = cache.serve(&name);
+
+    let (parts, body) = next.run(request).await.into_parts();
+    let body = body.into_data_stream().map(move |chunk| {
+        let _ = &serving;
+        chunk
+    });
+
+    Response::from_parts(parts, Body::from_stream(body))
+}
+
+#[derive(Serialize)]
+pub struct CacheStats {
+    files: usize,
+    bytes: u64,
+    max_bytes: u64,
+    /// Seconds
+    max_age: i64,
+    /// Files with a response in progress
+    serving: usize,
+    hits: u64,
+    misses: u64,
+    evictions: u64,
+}
+
+pub async fn stats(State(state): State<AppState>) -> Json<CacheStats> {
+    let cache = &state.temp_cache;
+    let entries = cache.entries.lock().unwrap();
+
+    Json(CacheStats {
+        files: entries.len(),
+        bytes: entries.values().map(|e| e.size).sum(),
+        max_bytes: MAX_BYTES,
+        max_age: MAX_AGE,
+        serving: entries.values().filter(|e| e.readers > 0).count(),
+        hits: cache.hits.load(Ordering::Relaxed),
+        misses: cache.misses.load(Ordering::Relaxed),
+        evictions: cache.evictions.load(Ordering::Relaxed),
+    })
+}
@@ -0,0 +1,281 @@
+//! Previews played while yt-dlp is still downloading, instead of after `/temp-download` is done.
+//! The audio is written to the temp cache on the way, so the next play is served from `/td`.
+
+use std::{
+    process::Stdio,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use axum::{
+    body::{Body, Bytes},
+    extract::{Path, Query, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Redirect, Response},
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    process::{Child, ChildStderr, ChildStdout, Command},
+    sync::mpsc,
+};
+use tokio_stream::{wrappers::ReceiverStream, StreamExt};
+
+use crate::{
+    audio_format::{self, FormatQuery},
+    AppState, TEMP_DIR,
+};
+
+type ApiResult<T> = Result<T, (StatusCode, String)>;
+
+const CHUNK_SIZE: usize = 64 * 1024;
+const CODEC_PREFIX: &str = "[acodec]";
+
+pub async fn temp_stream(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Query(query): Query<FormatQuery>,
+) -> ApiResult<Response> {
+    let format = audio_format::requested(&state, &query).await?;
+    let name = format!("{id}.{}", format.extension());
+    if state.temp_cache.lookup(&name) {
+        return Ok(Redirect::temporary(&format!("/td/{name}")).into_response());
+    }
+
+    tracing::info!("Streaming to temp: {}", id);
+    let internal = |e: String| {
+        tracing::error!("{}", e);
+        (StatusCode::INTERNAL_SERVER_ERROR, e)
+    };
+
+    let mut yt_dlp = Command::new("yt-dlp")
+        .args([
+            "-f",
+            format.selector(),
+            "--no-playlist",
+            "--no-warning",
+            "--quiet",
+            "--no-simulate",
+            "--print",
+            &format!("before_dl:{CODEC_PREFIX} %(acodec)s"),
+            "-o",
+            "-",
+            "--",
+            &id,
+        ])
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| internal(format!("Failed to spawn yt-dlp: {e}")))?;
+    let input: Stdio = yt_dlp
+        .stdout
+        .take()
+        .expect("stdout is piped")
+        .try_into()
+        .map_err(|e: std::io::Error| internal(e.to_string()))?;
+
+    // With the audio on stdout, yt-dlp prints on stderr
+    let mut stderr = yt_dlp.stderr.take().expect("stderr is piped");
+    let acodec = picked_codec(&mut stderr).await.map_err(|e| {
+        tracing::error!("Stream of {} failed: {}", id, e);
+        (StatusCode::BAD_GATEWAY, e)
+    })?;
+    // Keep reading the rest so yt-dlp never blocks on a full pipe
+    let video = id.clone();
+    tokio::spawn(async move {
+        let mut lines = BufReader::new(stderr).lines();
+        while let Ok(Some(line)) = lines.next_line().await {
+            tracing::debug!("yt-dlp {}: {}", video, line);
+        }
+    });
+
+    // Cached files are what /temp-download would have saved and /keep moves into the library,
+    // so a stream that is already in the right codec isn't encoded a second time
+    let args = if format.is_codec(&acodec) {
+        format.ffmpeg_copy_args()
+    } else {
+        format.ffmpeg_args(None)
+    };
+
+    let mut ffmpeg = Command::new("ffmpeg")
+        .args(["-v", "error", "-i", "pipe:0", "-vn"])
+        .args(args)
+        .arg("pipe:1")
+        .stdin(input)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| internal(format!("Failed to spawn ffmpeg: {e}")))?;
+    let output = ffmpeg.stdout.take().expect("stdout is piped");
+
+    let (tx, rx) = mpsc::channel(16);
+    tokio::spawn(tee(state, name, true, Some(yt_dlp), ffmpeg, output, tx));
+
+    respond(rx, format.content_type()).await
+}
+
+/// The codec of the format yt-dlp picked, printed before it starts the download.
+/// Read a byte at a time so nothing after it is taken from `stderr`.
+async fn picked_codec(stderr: &mut ChildStderr) -> Result<String, String> {
+    let mut printed = vec![];
+    let mut line = vec![];
+    let mut byte = [0];
+
+    loop {
+        match stderr.read(&mut byte).await {
+            Ok(0) => break,
+            Ok(_) if byte[0] == b'\n' => {
+                let text = String::from_utf8_lossy(&line).into_owned();
+                if let Some(codec) = text.strip_prefix(CODEC_PREFIX) {
+                    return Ok(codec.trim().to_string());
+                }
+                printed.push(text);
+                line.clear();
+            }
+            Ok(_) => line.push(byte[0]),
+            Err(e) => return Err(format!("Cannot read yt-dlp output: {e}")),
+        }
+    }
+
+    printed.push(String::from_utf8_lossy(&line).into_owned());
+    let message = printed.join("\n").trim().to_string();
+    if message.is_empty() {
+        Err("yt-dlp exited before downloading".to_string())
+    } else {
+        Err(message)
+    }
+}
+
+/// Stream what [`tee`] sends, waiting for the first chunk so a failure to start is
+/// reported with an error status rather than an empty 200
+pub async fn respond(
+    mut rx: mpsc::Receiver<Result<Bytes, String>>,
+    content_type: &'static str,
+) -> ApiResult<Response> {
+    let first = match rx.recv().await {
+        Some(Ok(chunk)) => chunk,
+        Some(Err(e)) => return Err((StatusCode::BAD_GATEWAY, e)),
+        None => return Err((StatusCode::BAD_GATEWAY, "No audio".to_string())),
+    };
+    let body = tokio_stream::once(Ok(first)).chain(ReceiverStream::new(rx));
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, content_type),
+            (header::CACHE_CONTROL, "no-store"),
+        ],
+        Body::from_stream(body),
+    )
+        .into_response())
+}
+
+/// Send what ffmpeg outputs to the client and, if `cache`, into the temp cache as `name`.
+/// Once the client is gone the transcode still finishes so the cache gets the whole file.
+/// `input` is whatever feeds ffmpeg, its failure fails the stream too.
+pub async fn tee(
+    state: AppState,
+    name: String,
+    cache: bool,
+    input: Option<Child>,
+    mut ffmpeg: Child,
+    mut output: ChildStdout,
+    tx: mpsc::Sender<Result<Bytes, String>>,
+) {
+    // Unique so two streams of the same video don't write over each other
+    static STREAMS: AtomicU64 = AtomicU64::new(0);
+    let part = format!(
+        "{TEMP_DIR}/{name}.{}.part",
+        STREAMS.fetch_add(1, Ordering::Relaxed)
+    );
+
+    let mut file = if cache {
+        match tokio::fs::File::create(&part).await {
+            Ok(f) => Some(f),
+            Err(e) => {
+                tracing::warn!("Cannot cache {}: {}", name, e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+    let mut listening = true;
+    let mut written = 0;
+    let mut buffer = vec![0; CHUNK_SIZE];
+
+    loop {
+        let n = match output.read(&mut buffer).await {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) => {
+                tracing::error!("Cannot read ffmpeg output for {}: {}", name, e);
+                break;
+            }
+        };
+        let chunk = Bytes::copy_from_slice(&buffer[..n]);
+        written += n;
+
+        if let Some(f) = file.as_mut() {
+            if let Err(e) = f.write_all(&chunk).await {
+                tracing::warn!("Cannot cache {}: {}", name, e);
+                file = None;
+            }
+        }
+
+        if listening && tx.send(Ok(chunk)).await.is_err() {
+            listening = false;
+        }
+        if !listening && file.is_none() {
+            // Dropping the children kills them
+            if cache {
+                _ = tokio::fs::remove_file(&part).await;
+            }
+            return;
+        }
+    }
+
+    let encoded = ffmpeg.wait().await.is_ok_and(|s| s.success());
+    let fed = match input.map(|i| i.wait_with_output()) {
+        None => Ok(()),
+        Some(waiting) => match waiting.await {
+            Ok(out) if out.status.success() => Ok(()),
+            // Empty when the caller reads stderr itself
+            Ok(out) if out.stderr.is_empty() => Err(format!("Input failed: {}", out.status)),
+            Ok(out) => Err(String::from_utf8_lossy(&out.stderr).into_owned()),
+            Err(e) => Err(e.to_string()),
+        },
+    };
+
+    match (fed, encoded) {
+        (Ok(()), true) if written > 0 => {
+            if let Some(mut f) = file {
+                let cached = match f.flush().await {
+                    Ok(()) => tokio::fs::rename(&part, format!("{TEMP_DIR}/{name}")).await,
+                    Err(e) => Err(e),
+                };
+                match cached {
+                    Ok(()) => state.temp_cache.touch(&name),
+                    Err(e) => {
+                        tracing::warn!("Cannot cache {}: {}", name, e);
+                        _ = tokio::fs::remove_file(&part).await;
+                    }
+                }
+            }
+        }
+        (fed, _) => {
+            let message = fed
+                .err()
+                .filter(|e| !e.is_empty())
+                .unwrap_or_else(|| "ffmpeg failed".to_string());
+            tracing::error!("Stream of {} failed: {}", name, message);
+
+            if cache {
+                _ = tokio::fs::remove_file(&part).await;
+            }
+            if listening {
+                _ = tx.send(Err(message)).await;
+            }
+        }
+    }
+}
@@ -1,3 +1,35 @@
+/// Seconds since the unix epoch
+#[inline]
+pub fn unix_now() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+/// Unix timestamp as an ISO 8601 date and time in UTC
+pub fn iso8601(timestamp: i64) -> String {
+    let (days, secs) = (timestamp.div_euclid(86400), timestamp.rem_euclid(86400));
+
+    // Days to civil date, from Howard Hinnant's date algorithms
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z",
+        secs / 3600,
+        secs % 3600 / 60,
+        secs % 60
+    )
+}
+
 #[inline]
 pub fn without_extension(filename: &str) -> &str {
     filename
@@ -6,9 +38,46 @@ pub fn without_extension(filename: &str) -> &str {
         .unwrap_or(filename)
 }
 
-/// Height > Width will break this but there's no way right?  
-/// Width and height divided by 2 then minus each other to find the offset
+/// Files without extension are treated as mp3
 #[inline]
-pub fn find_offset_to_center(width: u32, height: u32) -> u32 {
-    (width / 2) - (height / 2)
-}
\ No newline at end of file
+pub fn extension(filename: &str) -> &str {
+    filename
+        .rfind('.')
+        .map(|i| &filename[i + 1..])
+        .unwrap_or("mp3")
+}
+
+/// Accepts bare video ids and the usual watch, youtu.be, shorts and embed URLs
+pub fn youtube_id(input: &str) -> Option<String> {
+    let is_id = |s: &str| {
+        s.len() == 11
+            && s.chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    };
+
+    let input = input.trim();
+    if is_id(input) {
+        return Some(input.to_string());
+    }
+
+    let rest = input
+        .split_once('?')
+        .and_then(|(_, query)| query.split('&').find_map(|p| p.strip_prefix("v=")))
+        .or_else(|| {
+            ["youtu.be/", "/shorts/", "/embed/", "/live/"]
+                .iter()
+                .find_map(|m| input.split_once(m).map(|(_, rest)| rest))
+        })?;
+
+    let id = rest
+        .chars()
+        .take_while(|c| c.is_ascii_alphanumeric() || *c == '-' || *c == '_')
+        .collect::<String>();
+
+    is_id(&id).then_some(id)
+}
+
+#[inline]
+pub fn youtube_url(id: &str) -> String {
+    format!("https://www.youtube.com/watch?v={id}")
+}
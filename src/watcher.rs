@@ -0,0 +1,76 @@
+use std::{collections::HashSet, path::PathBuf, time::Duration};
+
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use tokio::sync::mpsc;
+
+use crate::{library, tags, AppState, MUSIC_DIR};
+
+/// yt-dlp and tag editors write in bursts, wait until a file has been quiet for this long
+const DEBOUNCE: Duration = Duration::from_secs(2);
+
+/// Watch `MUSIC_DIR` and keep the library index in sync.
+/// The returned watcher stops as soon as it's dropped.
+pub fn spawn(state: AppState) -> notify::Result<RecommendedWatcher> {
+    let (tx, mut rx) = mpsc::unbounded_channel::<PathBuf>();
+
+    let mut watcher =
+        notify::recommended_watcher(move |res: notify::Result<notify::Event>| match res {
+            Ok(event) => {
+                if matches!(event.kind, EventKind::Access(_)) {
+                    return;
+                }
+
+                for path in event.paths {
+                    _ = tx.send(path);
+                }
+            }
+            Err(e) => tracing::error!("Watch error: {}", e),
+        })?;
+    watcher.watch(std::path::Path::new(MUSIC_DIR), RecursiveMode::NonRecursive)?;
+
+    tokio::spawn(async move {
+        let mut pending = HashSet::new();
+
+        while let Some(path) = rx.recv().await {
+            pending.insert(path);
+
+            loop {
+                match tokio::time::timeout(DEBOUNCE, rx.recv()).await {
+                    Ok(Some(path)) => {
+                        pending.insert(path);
+                    }
+                    Ok(None) => return,
+                    Err(_) => break,
+                }
+            }
+
+            for path in pending.drain() {
+                sync(&state, path).await;
+            }
+        }
+    });
+
+    Ok(watcher)
+}
+
+async fn sync(state: &AppState, path: PathBuf) {
+    let Some(filename) = path.file_name().map(|f| f.to_string_lossy().to_string()) else {
+        return;
+    };
+
+    if !tags::is_supported(&filename) {
+        return;
+    }
+
+    if path.exists() {
+        tracing::info!("Change detected: {}", filename);
+        if let Err(e) = library::index_file(state, &filename).await {
+            tracing::error!("{} ({})", e, filename);
+        }
+    } else {
+        tracing::info!("Removal detected: {}", filename);
+        if let Err(e) = library::remove(state, &filename).await {
+            tracing::error!("{} ({})", e, filename);
+        }
+    }
+}